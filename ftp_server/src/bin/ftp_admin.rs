@@ -0,0 +1,40 @@
+use clap::{App, Arg};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::exit;
+
+fn main() {
+    let matches = App::new("FTP Server admin")
+        .version("1.0")
+        .about("Lists and kicks the sessions of a running FTP server through its admin socket.")
+        .arg(
+            Arg::with_name("socket")
+                .help("Admin socket of the server, the admin_socket of its configuration")
+                .short("s")
+                .long("socket")
+                .value_name("SOCKET")
+                .default_value("./var/admin.sock"),
+        )
+        .arg(
+            Arg::with_name("command")
+                .help("list | kick <session> | kick-user <user> | broadcast <message> | maintenance [on [message] | off] | quota-rescan <user or group> | group-add <group> <user> | group-remove <group> <user>")
+                .required(true)
+                .multiple(true),
+        )
+        .get_matches();
+    let socket = matches.value_of("socket").unwrap();
+    let command: Vec<&str> = matches.values_of("command").unwrap().collect();
+    if let Err(err) = send(socket, &command.join(" ")) {
+        eprintln!("Error talking to the server on {}: {}", socket, err);
+        exit(1);
+    }
+}
+
+fn send(socket: &str, command: &str) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    print!("{}", answer);
+    Ok(())
+}
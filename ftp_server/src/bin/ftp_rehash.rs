@@ -0,0 +1,25 @@
+use clap::{App, Arg};
+use std::process::exit;
+
+fn main() {
+    let matches = App::new("FTP Server rehash")
+        .version("1.0")
+        .about("Replaces the plaintext passwords of a users file by their argon2id hashes. Run it while the server is stopped.")
+        .arg(
+            Arg::with_name("file")
+                .help("Users file to rehash")
+                .short("f")
+                .long("file")
+                .value_name("FILE")
+                .default_value("./etc/users.json"),
+        )
+        .get_matches();
+    let file = matches.value_of("file").unwrap();
+    match user_manage::rehash_file(file) {
+        Ok(hashed) => println!("{} passwords of {} hashed", hashed, file),
+        Err(err) => {
+            eprintln!("Error rehashing {}: {}", file, err);
+            exit(1);
+        }
+    }
+}
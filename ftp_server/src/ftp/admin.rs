@@ -0,0 +1,410 @@
+//! Control interface for the operators on a Unix domain socket.
+//!
+//! Every connection sends a single command line and gets a plain text answer,
+//! then the server closes it. The `ftp_admin` binary is a small client for it.
+//!
+//! * `list`: sessions with their user, address, working directory and transfer.
+//! * `kick <session>`: closes a session.
+//! * `kick-user <user>`: closes every session of a user.
+//! * `broadcast <message>`: sends the message to every session with its next reply.
+//! * `maintenance [on [message] | off]`: while it's on new connections are rejected.
+//! * `quota-rescan <user or group>`: counts again the files of a user or a group for its quota.
+//! * `group-add <group> <user>`, `group-remove <group> <user>`: changes the members of a group.
+
+use super::{
+    close_with_reply, ActionList, FileTransferType, HashMutex, RequestContextMutex, RequestType,
+    SharedState, Token,
+};
+use log::{info, warn};
+use mio::{Interest, Waker};
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::Instant,
+};
+
+/// Message of the 421 rejection when maintenance mode is on and the operator didn't give one
+pub const MAINTENANCE_MESSAGE: &str = "Server under maintenance, try again later.";
+
+#[derive(Debug, PartialEq)]
+enum AdminCommand<'a> {
+    List,
+    Kick(usize),
+    KickUser(&'a str),
+    Broadcast(&'a str),
+    Maintenance(Option<Option<&'a str>>),
+    QuotaRescan(&'a str),
+    GroupAdd(&'a str, &'a str),
+    GroupRemove(&'a str, &'a str),
+}
+
+impl<'a> AdminCommand<'a> {
+    fn parse(line: &'a str) -> Result<Self, &'static str> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(' ') {
+            Some((command, argument)) => (command, Some(argument.trim())),
+            None => (line, None),
+        };
+        match (command, argument) {
+            ("list", None) => Ok(AdminCommand::List),
+            ("kick", Some(session)) => session
+                .parse()
+                .map(AdminCommand::Kick)
+                .map_err(|_| "The session must be a number"),
+            ("kick-user", Some(user)) => Ok(AdminCommand::KickUser(user)),
+            ("broadcast", Some(message)) => Ok(AdminCommand::Broadcast(message)),
+            ("maintenance", None) => Ok(AdminCommand::Maintenance(None)),
+            ("maintenance", Some("off")) => Ok(AdminCommand::Maintenance(Some(None))),
+            ("maintenance", Some(on)) if on == "on" || on.starts_with("on ") => {
+                let message = on[2..].trim();
+                Ok(AdminCommand::Maintenance(Some(Some(message))))
+            }
+            ("quota-rescan", Some(name)) => Ok(AdminCommand::QuotaRescan(name)),
+            ("group-add", Some(arguments)) | ("group-remove", Some(arguments)) => {
+                let (group, user) = match arguments.split_once(' ') {
+                    Some((group, user)) => (group, user.trim()),
+                    None => return Err("A group and a user are needed"),
+                };
+                Ok(if command == "group-add" {
+                    AdminCommand::GroupAdd(group, user)
+                } else {
+                    AdminCommand::GroupRemove(group, user)
+                })
+            }
+            _ => Err("Unknown command, use list, kick <session>, kick-user <user>, \
+                      broadcast <message>, maintenance [on [message] | off], \
+                      quota-rescan <user or group>, group-add <group> <user> \
+                      or group-remove <group> <user>"),
+        }
+    }
+}
+
+/// Turns a reply into a multiline one that starts with `notice`
+pub fn add_notice(reply: &[u8], notice: &str) -> Vec<u8> {
+    let code = String::from_utf8_lossy(&reply[..3.min(reply.len())]).to_string();
+    let mut with_notice = Vec::new();
+    for line in notice.lines() {
+        with_notice.extend_from_slice(format!("{}-{}\r\n", code, line).as_bytes());
+    }
+    with_notice.extend_from_slice(reply);
+    with_notice
+}
+
+/// What the admin interface needs from the server to inspect and close sessions
+pub struct Admin {
+    pub connections: HashMutex<Token, RequestContextMutex>,
+
+    pub actions: ActionList,
+
+    pub shared: SharedState,
+
+    pub waker: Arc<Waker>,
+}
+
+impl Admin {
+    fn execute(&self, line: &str) -> String {
+        let command = match AdminCommand::parse(line) {
+            Ok(command) => command,
+            Err(err) => return format!("{}\n", err),
+        };
+        info!("[ADMIN] {:?}", command);
+        match command {
+            AdminCommand::List => self.list(),
+            AdminCommand::Kick(session) => {
+                if self.kick(|token, _| token.0 == session) == 0 {
+                    format!("There is no session {}\n", session)
+                } else {
+                    format!("Session {} kicked\n", session)
+                }
+            }
+            AdminCommand::KickUser(user) => {
+                let kicked = self.kick(|_, ctx| {
+                    ctx.loged && ctx.user_id.as_deref() == Some(user)
+                });
+                format!("{} sessions of {} kicked\n", kicked, user)
+            }
+            AdminCommand::Broadcast(message) => {
+                let mut sessions = 0;
+                for (_, conn) in self.connections() {
+                    if let RequestType::CommandTransfer(_, to_write, _, _) =
+                        &mut conn.lock().unwrap().request_type
+                    {
+                        to_write.notice = Some(message.to_string());
+                        sessions += 1;
+                    }
+                }
+                format!("Message sent to {} sessions\n", sessions)
+            }
+            AdminCommand::Maintenance(change) => {
+                let mut maintenance = self.shared.maintenance.lock().unwrap();
+                if let Some(change) = change {
+                    *maintenance = change.map(|message| match message {
+                        "" => MAINTENANCE_MESSAGE.to_string(),
+                        message => message.to_string(),
+                    });
+                }
+                match maintenance.as_ref() {
+                    Some(message) => format!("Maintenance mode is on: {}\n", message),
+                    None => "Maintenance mode is off\n".to_string(),
+                }
+            }
+            AdminCommand::QuotaRescan(name) => {
+                // The files of a group are counted through the tree of one of its members
+                let user = {
+                    let db = self.shared.users_db.lock().unwrap();
+                    match db.get_group(name) {
+                        Some(group) => group.members.first().and_then(|member| {
+                            db.get_user_clone(member).map(|user| (member.clone(), user))
+                        }),
+                        None => db.get_user_clone(name).map(|user| (name.to_string(), user)),
+                    }
+                };
+                let (user_name, user) = match user {
+                    Some(user) => user,
+                    None => return format!("There is no user or group with members {}\n", name),
+                };
+                let storage = self.shared.storage.as_ref();
+                match self.shared.quotas.rescan(&user_name, &user, storage) {
+                    Ok(report) => format!("Quota of {}: {}\n", user_name, report),
+                    Err(err) => format!("Error counting the files of {}: {}\n", user_name, err),
+                }
+            }
+            AdminCommand::GroupAdd(group, user_name)
+            | AdminCommand::GroupRemove(group, user_name) => {
+                let mut db = self.shared.users_db.lock().unwrap();
+                let result = if let AdminCommand::GroupAdd(..) = command {
+                    db.add_member(group, user_name)
+                } else {
+                    db.remove_member(group, user_name)
+                };
+                match result {
+                    Ok(()) => {
+                        // The shared directory of the group stops, or starts, being left out of
+                        // the usage of the user
+                        self.shared.quotas.forget(user_name);
+                        let members = db.get_group(group).unwrap().members.join(", ");
+                        format!("Members of {}: {}\n", group, members)
+                    }
+                    Err(err) => format!("{}\n", err),
+                }
+            }
+        }
+    }
+
+    /// Clones the connections so we don't hold the map lock while locking each of them
+    fn connections(&self) -> Vec<(Token, RequestContextMutex)> {
+        let mut connections: Vec<(Token, RequestContextMutex)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(token, conn)| (*token, conn.clone()))
+            .collect();
+        connections.sort_by_key(|(token, _)| token.0);
+        connections
+    }
+
+    fn list(&self) -> String {
+        let connections = self.connections();
+        let now = Instant::now();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:<8} {:<16} {:<22} {:<8} {:<20} TRANSFER",
+            "SESSION", "USER", "PEER", "IDLE", "CWD"
+        );
+        for (token, conn) in connections.iter() {
+            let ctx = conn.lock().unwrap();
+            if !matches!(ctx.request_type, RequestType::CommandTransfer(_, _, _, _)) {
+                continue;
+            }
+            let user = match (&ctx.user_id, ctx.loged) {
+                (Some(user), true) => user.clone(),
+                (Some(user), false) => format!("({})", user),
+                (None, _) => "-".to_string(),
+            };
+            let peer = ctx
+                .peer
+                .map(|peer| peer.to_string())
+                .unwrap_or_else(|| "-".to_string());
+            let idle = now.saturating_duration_since(ctx.last_activity).as_secs();
+            let cwd = match (&ctx.user_id, ctx.loged) {
+                (Some(_), true) => Some(ctx.cwd.clone()),
+                _ => None,
+            };
+            drop(ctx);
+            let transfers: Vec<String> = connections
+                .iter()
+                .filter_map(|(_, data)| transfer_progress(data, *token))
+                .collect();
+            let _ = writeln!(
+                out,
+                "{:<8} {:<16} {:<22} {:<8} {:<20} {}",
+                token.0,
+                user,
+                peer,
+                format!("{}s", idle),
+                cwd.unwrap_or_else(|| "-".to_string()),
+                if transfers.is_empty() {
+                    "-".to_string()
+                } else {
+                    transfers.join(", ")
+                }
+            );
+        }
+        out
+    }
+
+    /// Replies 421 to the sessions that match and closes them, returns how many there were
+    fn kick<F>(&self, matches: F) -> usize
+    where
+        F: Fn(Token, &super::RequestContext) -> bool,
+    {
+        let mut kicked = 0;
+        for (token, conn) in self.connections() {
+            let mut ctx = conn.lock().unwrap();
+            if !matches(token, &ctx) {
+                continue;
+            }
+            if let RequestType::CommandTransfer(_, to_write, _, _) = &mut ctx.request_type {
+                info!("[ADMIN] - {} - Kicking session", token.0);
+                close_with_reply(&conn, to_write, "Session closed by the administrator.");
+                drop(ctx);
+                self.actions
+                    .lock()
+                    .unwrap()
+                    .push((token, conn.clone(), Interest::WRITABLE));
+                kicked += 1;
+            }
+        }
+        if kicked > 0 {
+            let _ = self.waker.wake();
+        }
+        kicked
+    }
+}
+
+/// Describes the data connection if it belongs to the session `cmd_token`
+fn transfer_progress(data: &RequestContextMutex, cmd_token: Token) -> Option<String> {
+    let ctx = data.lock().unwrap();
+    match &ctx.request_type {
+        RequestType::FileTransferActive(_, ftt, cmd) | RequestType::FileTransferPassive(_, ftt, cmd)
+            if *cmd == cmd_token =>
+        {
+            Some(match ftt {
+                FileTransferType::FileDownload(download, transfer) => {
+                    match download.size().filter(|size| *size > 0) {
+                        Some(size) => format!(
+                            "RETR {} {}/{} bytes ({}%)",
+                            transfer.path,
+                            transfer.bytes,
+                            size,
+                            transfer.bytes * 100 / size
+                        ),
+                        None => format!("RETR {} {} bytes", transfer.path, transfer.bytes),
+                    }
+                }
+                FileTransferType::FileUpload(_, _, transfer, _) => {
+                    format!("STOR {} {} bytes", transfer.path, transfer.bytes)
+                }
+                FileTransferType::Buffer(buffer) => {
+                    format!("LIST {}/{} bytes", buffer.offset, buffer.buffer.len())
+                }
+            })
+        }
+        RequestType::PassiveModePort(_, cmd) if *cmd == cmd_token => {
+            Some("waiting for the data connection".to_string())
+        }
+        _ => None,
+    }
+}
+
+fn answer(admin: &Admin, stream: UnixStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut stream = reader.into_inner();
+    stream.write_all(admin.execute(&line).as_bytes())?;
+    stream.flush()
+}
+
+/// Creates the socket at `path`, before the sandbox so it can be anywhere.
+/// Only the owner of the server process can connect to it
+pub fn bind(path: &str) -> io::Result<UnixListener> {
+    let path = PathBuf::from(path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // A socket left by a previous run that didn't exit cleanly
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    info!("[ADMIN] Listening on {}", path.display());
+    Ok(listener)
+}
+
+/// Answers the requests of the listener of `bind` from a thread of its own
+pub fn serve(listener: UnixListener, admin: Admin) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| answer(&admin, stream));
+            if let Err(err) = result {
+                warn!("[ADMIN] Error answering a request: {}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add_notice, AdminCommand};
+
+    #[test]
+    fn parses_admin_commands() {
+        assert_eq!(AdminCommand::parse("list\n"), Ok(AdminCommand::List));
+        assert_eq!(AdminCommand::parse("kick 12\n"), Ok(AdminCommand::Kick(12)));
+        assert!(AdminCommand::parse("kick user\n").is_err());
+        assert_eq!(
+            AdminCommand::parse("kick-user n\n"),
+            Ok(AdminCommand::KickUser("n"))
+        );
+        assert_eq!(
+            AdminCommand::parse("maintenance on back at 10\n"),
+            Ok(AdminCommand::Maintenance(Some(Some("back at 10"))))
+        );
+        assert_eq!(
+            AdminCommand::parse("maintenance off\n"),
+            Ok(AdminCommand::Maintenance(Some(None)))
+        );
+        assert!(AdminCommand::parse("maintenance once\n").is_err());
+        assert_eq!(
+            AdminCommand::parse("quota-rescan n\n"),
+            Ok(AdminCommand::QuotaRescan("n"))
+        );
+        assert_eq!(
+            AdminCommand::parse("group-add team n\n"),
+            Ok(AdminCommand::GroupAdd("team", "n"))
+        );
+        assert_eq!(
+            AdminCommand::parse("group-remove team n\n"),
+            Ok(AdminCommand::GroupRemove("team", "n"))
+        );
+        assert!(AdminCommand::parse("group-add team\n").is_err());
+    }
+
+    #[test]
+    fn notice_before_reply() {
+        let reply = add_notice(b"250 Okay.\r\n", "Restarting\nin 5 minutes");
+        assert_eq!(reply, b"250-Restarting\r\n250-in 5 minutes\r\n250 Okay.\r\n");
+    }
+}
@@ -0,0 +1,86 @@
+//! Anonymous FTP.
+//!
+//! With `anonymous.enabled` in the configuration `USER anonymous` or `USER ftp` log in with any
+//! password, which by convention is the email of the client. Every anonymous session is the
+//! `anonymous` user, its root is the `root` directory of the configuration and it can list and
+//! download everything in there but not change it. The `incoming` directory only takes uploads
+//! of new files: they can't be listed, downloaded or replaced by the anonymous sessions, so it
+//! can't be used to share files. The anonymous sessions have their own `max_sessions` and
+//! bandwidth, the defaults of the users don't apply to them.
+
+use super::{config::AnonymousConfig, storage::Storage};
+use log::warn;
+use std::io::ErrorKind;
+use user_manage::{PathPermissions, Permission, SystemUsers, User, ANONYMOUS};
+
+/// If the name that the client sent with USER means the anonymous user
+pub fn is_anonymous_name(user_name: &str) -> bool {
+    user_name.eq_ignore_ascii_case(ANONYMOUS) || user_name.eq_ignore_ascii_case("ftp")
+}
+
+/// Record of the anonymous user for the configuration
+fn user(config: &AnonymousConfig) -> User {
+    let mut permissions = vec![PathPermissions {
+        path: "/".to_string(),
+        allow: vec![Permission::List, Permission::Read],
+    }];
+    if !config.incoming.is_empty() {
+        permissions.push(PathPermissions {
+            path: config.incoming.clone(),
+            allow: vec![Permission::Write],
+        });
+    }
+    User::anonymous(
+        &config.root,
+        permissions,
+        Some(config.max_sessions),
+        Some(config.upload_rate),
+        Some(config.download_rate),
+    )
+}
+
+/// Turns anonymous logins on or off as the configuration says, the incoming directory is
+/// created if it doesn't exist
+pub fn configure(config: &AnonymousConfig, users: &mut SystemUsers, storage: &dyn Storage) {
+    if !config.enabled {
+        users.set_anonymous(None);
+        return;
+    }
+    let user = user(config);
+    if let Err(err) = storage.create_user_root(&user) {
+        warn!("[ANONYMOUS] Error creating the root {}: {}", config.root, err);
+    } else if !config.incoming.is_empty() {
+        let created = storage
+            .user_root(&user)
+            .and_then(|tree| tree.mkdir(&config.incoming));
+        if let Err(err) = created {
+            if err.kind() != ErrorKind::AlreadyExists {
+                warn!(
+                    "[ANONYMOUS] Error creating the incoming directory {}: {}",
+                    config.incoming, err
+                );
+            }
+        }
+    }
+    users.set_anonymous(Some(user));
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_anonymous_name, user};
+    use crate::ftp::config::AnonymousConfig;
+    use user_manage::Permission;
+
+    #[test]
+    fn incoming_only_takes_uploads() {
+        assert!(is_anonymous_name("FTP"));
+        assert!(!is_anonymous_name("admin"));
+        let user = user(&AnonymousConfig::default());
+        assert!(user.is_allowed(Permission::Read, "/pub/release.tar.gz"));
+        assert!(!user.is_allowed(Permission::Write, "/pub/release.tar.gz"));
+        assert!(user.is_allowed(Permission::Write, "/incoming/patch.diff"));
+        assert!(!user.is_allowed(Permission::List, "/incoming"));
+        assert!(!user.is_allowed(Permission::Read, "/incoming/patch.diff"));
+        assert!(!user.is_allowed(Permission::Delete, "/incoming/patch.diff"));
+    }
+}
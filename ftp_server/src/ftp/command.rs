@@ -21,12 +21,18 @@ pub enum Command<'a> {
 
     Password(&'a str),
 
+    /// ACCT, the invite code of a user that is registering
+    Account(&'a str),
+
     // PWD, returns the current directory!
     CurrentDirectory,
 
     /// STOR command that passes a path where the user wants a download
     Store(&'a Path),
 
+    /// APPE, like STOR but adding to the end of the file if it exists
+    Append(&'a Path),
+
     // PASV\r\n
     Passive,
 
@@ -48,26 +54,9 @@ pub enum Command<'a> {
 
     /// Quit the connection
     Quit,
-}
 
-impl<'a> Command<'a> {
-    /// Returns if this command needs authentication in the FTP protocol
-    pub fn is_auth_command(&self) -> bool {
-        match self {
-            &Command::Port(_, _)
-            | &Command::List(_)
-            | &Command::Retr(_)
-            | &Command::Mkdir(_)
-            | &Command::Store(_)
-            | &Command::Delete(_)
-            | &Command::RemoveDirectory(_)
-            | &Command::CurrentDirectory
-            | &Command::ChangeDirectory(_)
-            | &Command::RenameTo(_)
-            | &Command::RenameFrom(_) => true,
-            _ => false,
-        }
-    }
+    /// SITE <name> [argument], everything after the verb
+    Site(&'a str),
 }
 
 fn expects_byte(byte: u8, expected_byte: u8, msg: &'static str) -> Result<(), &'static str> {
@@ -144,6 +133,19 @@ impl<'a> TryFrom<&'a [u8]> for Command<'a> {
         // This is also done in compilers with switch statements, where they create
         // a trie of switches where they check if the word is a keyword.
         match command[0] {
+            b'A' => match command[1] {
+                b'C' => {
+                    if command.len() <= 6 || &command[2..4] != b"CT" {
+                        return Err("Invalid command, maybe you meant: `ACCT`?");
+                    }
+                    expects_byte(command[4], b' ', "Expected a space in between")?;
+                    let account = std::str::from_utf8(&command[5..command.len() - 2])
+                        .map_err(|_| "expected utf8 string")?;
+                    Ok(Command::Account(account))
+                }
+                _ => Ok(Command::Append(parse_path(command, b"PPE", (1, 4))?)),
+            },
+
             b'C' => Ok(Command::ChangeDirectory(parse_path(
                 &command,
                 b"WD",
@@ -199,7 +201,18 @@ impl<'a> TryFrom<&'a [u8]> for Command<'a> {
                 _ => return Err("Unknown command, maybe you meant 'RETR' or 'RMD'?"),
             },
 
-            b'S' => Ok(Command::Store(parse_path(&command, b"TOR", (1, 4))?)),
+            b'S' => match command[1] {
+                b'I' => {
+                    if command.len() <= 6 || &command[2..4] != b"TE" {
+                        return Err("Invalid command, maybe you meant: `SITE`?");
+                    }
+                    expects_byte(command[4], b' ', "Expected a space in between")?;
+                    let argument = std::str::from_utf8(&command[5..command.len() - 2])
+                        .map_err(|_| "expected utf8 string")?;
+                    Ok(Command::Site(argument))
+                }
+                _ => Ok(Command::Store(parse_path(&command, b"TOR", (1, 4))?)),
+            },
 
             b'P' => {
                 match command[1] {
@@ -318,6 +331,11 @@ mod test {
                 Command::Store(Path::new("./test/test/test1.txt")),
                 true,
             ),
+            (
+                "APPE ./test/test/test1.txt\r\n".as_bytes(),
+                Command::Append(Path::new("./test/test/test1.txt")),
+                true,
+            ),
             (
                 "MKD ./test/test/test1.txt\r\n".as_bytes(),
                 Command::Mkdir(Path::new("./test/test/test1.txt")),
@@ -355,6 +373,8 @@ mod test {
                 true,
             ),
             ("PASV\r\n".as_bytes(), Command::Passive, true),
+            ("SITE HELP\r\n".as_bytes(), Command::Site("HELP"), true),
+            ("ACCT 1234\r\n".as_bytes(), Command::Account("1234"), true),
             ("PWD\r\n".as_bytes(), Command::CurrentDirectory, true),
             ("PASS GABI\r\n".as_bytes(), Command::Password("GABI"), true),
             (
@@ -0,0 +1,225 @@
+//! Registry of the commands that the control connections understand.
+//!
+//! The built-in verbs are registered when the server is created, and more can be added
+//! (or built-in ones replaced) with `FTPServer::register_command`. `SITE <name>` commands are
+//! added with `FTPServer::register_site_command`, `SITE HELP` lists them.
+//! The `disabled_commands` of the configuration are answered with 502 whoever handles them.
+//!
+//! ```ignore
+//! struct Hello;
+//!
+//! impl CommandHandler for Hello {
+//!     fn handle(&self, session: &Session, _argument: Option<&str>) -> Reply {
+//!         let message = format!("Hello {}", session.user_name().unwrap_or("stranger"));
+//!         Reply::new(ResponseCode::command_okay(), &message)
+//!     }
+//! }
+//!
+//! server.register_site_command("HELLO", Arc::new(Hello));
+//! ```
+
+use super::{
+    create_response,
+    response::ResponseCode,
+    storage::{self, Storage, StorageBackend},
+    SharedState,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+use user_manage::{SystemUsers, User};
+
+/// Verbs implemented by the server and if they need the user to be logged in
+const BUILTINS: [(&str, bool); 18] = [
+    ("USER", false),
+    ("PASS", false),
+    ("ACCT", false),
+    ("QUIT", false),
+    ("PORT", true),
+    ("PASV", true),
+    ("LIST", true),
+    ("RETR", true),
+    ("STOR", true),
+    ("APPE", true),
+    ("PWD", true),
+    ("CWD", true),
+    ("MKD", true),
+    ("RMD", true),
+    ("DELE", true),
+    ("RNFR", true),
+    ("RNTO", true),
+    // Every SITE command says if it needs it
+    ("SITE", false),
+];
+
+/// Answer of a command
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub code: ResponseCode,
+    pub message: String,
+}
+
+impl Reply {
+    pub fn new(code: ResponseCode, message: &str) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub(super) fn into_bytes(self) -> Vec<u8> {
+        create_response(self.code, &self.message)
+    }
+}
+
+/// What a handler can see of the session that sent the command
+pub struct Session<'a> {
+    pub(super) id: usize,
+
+    pub(super) peer: Option<SocketAddr>,
+
+    /// Only set when the user is logged in
+    pub(super) user: Option<&'a str>,
+
+    /// Working directory of the session
+    pub(super) cwd: &'a str,
+
+    pub(super) shared: &'a SharedState,
+}
+
+impl Session<'_> {
+    /// ID of the control connection, the one that the logs and the admin interface show
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Name of the logged in user
+    pub fn user_name(&self) -> Option<&str> {
+        self.user
+    }
+
+    /// Record of the logged in user
+    pub fn user(&self) -> Option<User> {
+        self.users().lock().unwrap().get_user_clone(self.user?)
+    }
+
+    pub fn users(&self) -> &Arc<Mutex<SystemUsers>> {
+        &self.shared.users_db
+    }
+
+    /// Virtual path of a path sent by the client, relative to the working directory of the session
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> Result<String, &'static str> {
+        self.user.ok_or("Not logged in")?;
+        Ok(storage::resolve(self.cwd, path))
+    }
+
+    /// Files of the logged in user, they are opened with the paths of `resolve`
+    pub fn storage(&self) -> io::Result<Box<dyn StorageBackend>> {
+        let user = self
+            .user()
+            .ok_or_else(|| io::Error::new(ErrorKind::PermissionDenied, "Not logged in"))?;
+        self.shared.storage.user_root(&user)
+    }
+}
+
+/// A command that the server doesn't implement, or a `SITE` command
+pub trait CommandHandler: Send + Sync {
+    /// If the user must be logged in to use it
+    fn needs_login(&self) -> bool {
+        true
+    }
+
+    /// Runs the command, `argument` is everything after the verb (or after the name of a `SITE` command)
+    fn handle(&self, session: &Session, argument: Option<&str>) -> Reply;
+}
+
+#[derive(Clone)]
+pub(super) enum Handler {
+    BuiltIn { needs_login: bool },
+    Custom(Arc<dyn CommandHandler>),
+}
+
+/// Handlers of every verb and `SITE` command, they are looked up by their uppercase name
+#[derive(Clone)]
+pub struct Commands {
+    verbs: HashMap<String, Handler>,
+    site: BTreeMap<String, Arc<dyn CommandHandler>>,
+}
+
+impl Commands {
+    pub fn with_builtins() -> Self {
+        let verbs = BUILTINS
+            .iter()
+            .map(|(verb, needs_login)| {
+                let handler = Handler::BuiltIn {
+                    needs_login: *needs_login,
+                };
+                (verb.to_string(), handler)
+            })
+            .collect();
+        Self {
+            verbs,
+            site: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a verb, if it's a built-in one the new handler replaces it
+    pub fn register(&mut self, verb: &str, handler: Arc<dyn CommandHandler>) {
+        self.verbs
+            .insert(verb.to_uppercase(), Handler::Custom(handler));
+    }
+
+    pub fn register_site(&mut self, name: &str, handler: Arc<dyn CommandHandler>) {
+        self.site.insert(name.to_uppercase(), handler);
+    }
+
+    pub(super) fn get(&self, verb: &str) -> Option<&Handler> {
+        self.verbs.get(verb)
+    }
+
+    pub(super) fn get_site(&self, name: &str) -> Option<&Arc<dyn CommandHandler>> {
+        self.site.get(&name.to_uppercase())
+    }
+
+    pub(super) fn site_names(&self) -> Vec<&str> {
+        self.site.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandHandler, Commands, Handler, Reply, Session};
+    use crate::ftp::response::ResponseCode;
+    use std::sync::Arc;
+
+    struct Hello;
+
+    impl CommandHandler for Hello {
+        fn handle(&self, _session: &Session, _argument: Option<&str>) -> Reply {
+            Reply::new(ResponseCode::command_okay(), "Hello")
+        }
+    }
+
+    #[test]
+    fn registry() {
+        let mut commands = Commands::with_builtins();
+        assert!(matches!(
+            commands.get("RETR"),
+            Some(Handler::BuiltIn { needs_login: true })
+        ));
+        assert!(commands.get("HELLO").is_none());
+        commands.register("retr", Arc::new(Hello));
+        assert!(matches!(commands.get("RETR"), Some(Handler::Custom(_))));
+        commands.register_site("hello", Arc::new(Hello));
+        assert!(commands.get_site("HeLLo").is_some());
+        assert_eq!(commands.site_names(), vec!["HELLO"]);
+    }
+}
@@ -1,46 +1,412 @@
-use std::{fs::File, sync::Mutex};
-
-pub static mut DEBUG: bool = false;
-pub static mut STDOUT_FILE: Option<Mutex<File>> = None;
-
-// Don't call this in multithreaded environments!!
-pub(crate) fn use_stdout(file: File) {
-    // This is totally safe because we won't use this function concurrently
-    unsafe {
-        STDOUT_FILE = Some(Mutex::new(file));
-    }
-}
-
-pub(crate) fn set_debug(debug: bool) {
-    // This is totally safe because we won't use this function concurrently
-    unsafe {
-        DEBUG = debug;
-    }
-}
-
-#[macro_export]
-macro_rules! print_stdout {
-    ($($arg:tt)*) => (
-        {
-            use crate::ftp::config::*;
-            use std::io::prelude::*;
-            unsafe {
-                if DEBUG {
-                    if let Err(e) = write!(&mut ::std::io::stdout(), "{}\n", format_args!($($arg)*)) {
-                        panic!("Failed to write to stdout.\
-                            \nOriginal error output: {}\
-                            \nSecondary error writing to stderr: {}", format!($($arg)*), e);
-                    }
-                }
-                if STDOUT_FILE.is_some() {
-                    let mut m = STDOUT_FILE.as_mut().unwrap().lock().unwrap();
-                    if let Err(e) = write!(m, "{}\n", format_args!($($arg)*)) {
-                        panic!("Failed to write to stdout.\
-                            \nOriginal error output: {}\
-                            \nSecondary error writing to stderr: {}", format!($($arg)*), e);
-                    }
-                }
-            }
+use super::hooks::HookEvent;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, error::Error, fs, net::IpAddr, path::Path, time::Duration};
+
+pub const CONFIG_PATH: &str = "./etc/config.json";
+
+/// Server settings stored in `etc/config.json`.
+/// Everything in here is read again when the server receives a SIGHUP,
+/// missing keys take their default value
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Message that goes with the 220 reply when a client connects
+    pub banner: String,
+
+    /// Maximum concurrent control connections
+    pub max_connections: usize,
+
+    /// Maximum concurrent control connections from the same address, 0 means unlimited
+    pub max_connections_per_ip: usize,
+
+    /// Maximum logged in sessions of the same user, 0 means unlimited.
+    /// Users can override it with `max_sessions` in their record
+    pub max_sessions_per_user: usize,
+
+    /// Seconds a control connection can stay without sending commands, 0 disables it
+    pub control_timeout: u64,
+
+    /// Seconds a data connection can stay without moving data, 0 disables it
+    pub data_timeout: u64,
+
+    /// Bytes per second that all the uploads together can use, 0 means unlimited
+    pub upload_rate: u64,
+
+    /// Bytes per second that all the downloads together can use, 0 means unlimited
+    pub download_rate: u64,
+
+    /// Default bytes per second for the uploads of every user, they can override it in their record
+    pub user_upload_rate: u64,
+
+    /// Default bytes per second for the downloads of every user, they can override it in their record
+    pub user_download_rate: u64,
+
+    /// Bytes per second for the uploads of a single session, 0 means unlimited
+    pub session_upload_rate: u64,
+
+    /// Bytes per second for the downloads of a single session, 0 means unlimited
+    pub session_download_rate: u64,
+
+    /// Serve the downloads with `sendfile` when the system supports it
+    pub sendfile: bool,
+
+    /// Seconds a port opened with PASV waits for the client to connect, 0 disables it
+    pub passive_timeout: u64,
+
+    /// Where and how much the server logs
+    pub log: LogConfig,
+
+    /// File where the events are written as JSON lines (see `ftp::events`), empty disables it
+    pub event_log: String,
+
+    /// File where every RETR and STOR is written in `xferlog` format, empty disables it.
+    /// It's opened again if it's moved away (e.g by logrotate)
+    pub xferlog: String,
+
+    /// Local address where the Prometheus metrics are served on `/metrics`, empty disables it.
+    /// Changing it needs a restart
+    pub metrics_address: String,
+
+    /// Unix socket of the admin interface (see `ftp::admin`), empty disables it.
+    /// Changing it needs a restart
+    pub admin_socket: String,
+
+    /// Programs and URLs that are notified of the events of the sessions (see `ftp::hooks`)
+    pub hooks: Vec<HookConfig>,
+
+    /// Events that can wait for the hooks, newer ones are dropped when it's full.
+    /// Changing it starts a new queue
+    pub hook_queue_size: usize,
+
+    /// Verbs that are answered with 502 even if they are implemented (e.g `["DELE", "RMD"]`)
+    pub disabled_commands: Vec<String>,
+
+    /// Where the files of the users are kept (see `ftp::storage`).
+    /// Changing it needs a restart
+    pub storage: StorageConfig,
+
+    /// Storages that the `mounts` of the users can use by name.
+    /// Changing them needs a restart
+    pub storages: HashMap<String, StorageConfig>,
+
+    /// Restrictions of the process (see `ftp::sandbox`). Changing it needs a restart
+    pub sandbox: SandboxConfig,
+
+    /// Logins as `anonymous` or `ftp` (see `ftp::anonymous`)
+    pub anonymous: AnonymousConfig,
+
+    /// Who can create an account by logging in with a new user name (see `ftp::registration`)
+    pub registration: RegistrationConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            banner: "Service ready for new user.".to_string(),
+            max_connections: 50,
+            max_connections_per_ip: 50,
+            max_sessions_per_user: 10,
+            control_timeout: 300,
+            data_timeout: 300,
+            passive_timeout: 60,
+            sendfile: true,
+            upload_rate: 0,
+            download_rate: 0,
+            user_upload_rate: 0,
+            user_download_rate: 0,
+            session_upload_rate: 0,
+            session_download_rate: 0,
+            log: LogConfig::default(),
+            event_log: String::new(),
+            xferlog: String::new(),
+            metrics_address: String::new(),
+            admin_socket: String::new(),
+            hooks: Vec::new(),
+            hook_queue_size: 100,
+            disabled_commands: Vec::new(),
+            storage: StorageConfig::default(),
+            storages: HashMap::new(),
+            sandbox: SandboxConfig::default(),
+            anonymous: AnonymousConfig::default(),
+            registration: RegistrationConfig::default(),
+        }
+    }
+}
+
+/// Destination of the log messages
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    Stdout,
+    File,
+    Both,
+}
+
+/// Logging settings, the `log` key of the configuration file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Most verbose level that is written: off, error, warn, info, debug or trace
+    pub level: LevelFilter,
+
+    /// Levels for specific modules, the key is a module path like `ftp_server::ftp::handler_read`
+    pub modules: HashMap<String, LevelFilter>,
+
+    pub output: LogOutput,
+
+    /// Log file used when the output is `file` or `both`, it's created if it doesn't exist
+    pub file: String,
+
+    /// Bytes that the log file can reach before it's moved to `<file>.1`, 0 means unlimited
+    pub max_file_size: u64,
+
+    /// Sessions of these users log everything, whatever the levels say
+    pub debug_users: Vec<String>,
+
+    /// Sessions coming from these addresses log everything, whatever the levels say
+    pub debug_addresses: Vec<IpAddr>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            modules: HashMap::new(),
+            output: LogOutput::Stdout,
+            file: "./var/server.log".to_string(),
+            max_file_size: 10 * 1024 * 1024,
+            debug_users: Vec::new(),
+            debug_addresses: Vec::new(),
+        }
+    }
+}
+
+/// Hardening of the process, the `sandbox` key of the configuration.
+/// Everything is applied once the listener is bound, the server doesn't start if it can't be
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Unprivileged user that the server switches to, empty keeps the one that started it
+    pub user: String,
+
+    /// Limits the files that the server can reach with Landlock to the storage of the users,
+    /// `etc`, `var` and the paths below
+    pub landlock: bool,
+
+    /// More files and directories that can be written with Landlock
+    pub write_paths: Vec<String>,
+
+    /// Files and directories that can be read with Landlock, e.g the ones that resolving
+    /// host names or running the commands of the hooks needs. The missing ones are skipped
+    pub read_paths: Vec<String>,
+
+    /// Only lets the server make the system calls that it needs, the rest fail with `EPERM`
+    pub seccomp: bool,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            user: String::new(),
+            landlock: false,
+            write_paths: Vec::new(),
+            read_paths: vec![
+                "/etc/hosts".to_string(),
+                "/etc/resolv.conf".to_string(),
+                "/etc/nsswitch.conf".to_string(),
+                "/etc/ld.so.cache".to_string(),
+                "/bin".to_string(),
+                "/lib".to_string(),
+                "/lib64".to_string(),
+                "/usr".to_string(),
+            ],
+            seccomp: false,
+        }
+    }
+}
+
+/// Anonymous FTP, the `anonymous` key of the configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AnonymousConfig {
+    /// Lets `anonymous` and `ftp` log in with any password (their email)
+    pub enabled: bool,
+
+    /// Directory that the anonymous sessions see as their root, in the storage of the users
+    pub root: String,
+
+    /// Directory of the root where the anonymous sessions can upload new files but not list
+    /// or download them, empty doesn't let them upload
+    pub incoming: String,
+
+    /// Maximum logged in anonymous sessions, 0 means unlimited
+    pub max_sessions: usize,
+
+    /// Bytes per second for the uploads of all the anonymous sessions together, 0 means unlimited
+    pub upload_rate: u64,
+
+    /// Bytes per second for the downloads of all the anonymous sessions together, 0 means unlimited
+    pub download_rate: u64,
+}
+
+impl Default for AnonymousConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            root: "./root/anonymous".to_string(),
+            incoming: "/incoming".to_string(),
+            max_sessions: 50,
+            upload_rate: 0,
+            download_rate: 0,
         }
-    )
+    }
+}
+
+/// Self-registration of new users, the `registration` key of the configuration
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RegistrationConfig {
+    pub policy: RegistrationPolicy,
+
+    /// Addresses that can register with the `allowlist` policy
+    pub allowed_addresses: Vec<IpAddr>,
+
+    /// Codes that are accepted with ACCT by the `invite` policy
+    pub invite_codes: Vec<String>,
+}
+
+/// When a login with an unknown user name creates the user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationPolicy {
+    /// Never, unknown users are answered like a wrong password
+    #[default]
+    Disabled,
+    /// Always
+    Open,
+    /// When the client connects from one of the `allowed_addresses`
+    Allowlist,
+    /// When the client sends one of the `invite_codes` with ACCT after PASS
+    Invite,
+}
+
+/// Backend of the `storage` key of the configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// The `chroot` directory of every user in the disk
+    Local {
+        #[serde(default)]
+        symlinks: SymlinkPolicy,
+    },
+
+    /// Files in memory that are lost when the server stops, sizes in bytes and 0 means unlimited
+    Memory {
+        #[serde(default)]
+        max_file_size: u64,
+
+        /// Bytes of all the files together
+        #[serde(default)]
+        max_size: u64,
+    },
+
+    /// Objects of a bucket of an S3 compatible service, see `storage::S3Storage`
+    S3 {
+        /// URL of the service without the bucket, e.g `http://127.0.0.1:9000`
+        endpoint: String,
+
+        bucket: String,
+
+        #[serde(default = "StorageConfig::default_region")]
+        region: String,
+
+        access_key: String,
+
+        secret_key: String,
+
+        /// Prefix of the bucket where the chroots are, empty for the root of the bucket
+        #[serde(default)]
+        prefix: String,
+
+        /// Seconds a request to the service can take
+        #[serde(default = "StorageConfig::default_timeout")]
+        timeout: u64,
+    },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig::Local {
+            symlinks: SymlinkPolicy::default(),
+        }
+    }
+}
+
+/// What the local disk does with the symbolic links that it finds inside the root of a user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// They can't be opened or traversed, listings show them as files
+    #[default]
+    Deny,
+
+    /// They are followed as long as they point inside the root
+    Follow,
+}
+
+impl StorageConfig {
+    fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+
+    fn default_timeout() -> u64 {
+        30
+    }
+}
+
+/// A hook of the `hooks` key of the configuration, it should have a `command`, a `url` or both
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HookConfig {
+    /// Events that run the hook
+    pub events: Vec<HookEvent>,
+
+    /// Executable that is run with the event in its environment
+    #[serde(default)]
+    pub command: Option<String>,
+
+    /// URL that gets the event as JSON in a POST
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// Seconds the hook can take before it's killed or abandoned
+    #[serde(default = "HookConfig::default_timeout")]
+    pub timeout: u64,
+}
+
+impl HookConfig {
+    fn default_timeout() -> u64 {
+        10
+    }
+}
+
+impl ServerConfig {
+    /// Returns the timeout as a duration, `None` when it's disabled
+    pub fn timeout(seconds: u64) -> Option<Duration> {
+        if seconds == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(seconds))
+        }
+    }
+
+    /// Parses the configuration file, if it doesn't exist it returns the default configuration
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        if !path.as_ref().exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)?;
+        let config = serde_json::from_str(&content)?;
+        Ok(config)
+    }
 }
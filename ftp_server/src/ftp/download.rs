@@ -0,0 +1,117 @@
+use std::{
+    io::{self, ErrorKind, Write},
+    ops::Range,
+};
+
+use super::storage::FileReader;
+use mio::net::TcpStream;
+
+/// Size of the buffer used when we can't use `sendfile`
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// File that is being served to the client.
+/// We keep track of the offset ourselves, so we never need to seek the file back
+/// when the socket would block
+pub struct FileDownload {
+    file: Box<dyn FileReader>,
+
+    /// Bytes of the file that have already been read (sent or waiting in the buffer)
+    offset: u64,
+
+    /// If we should try to use `sendfile`, it's disabled when the file system doesn't support it
+    /// or the file isn't in the local disk
+    use_sendfile: bool,
+
+    /// Reusable buffer for the transfers that can't use `sendfile`
+    buffer: Vec<u8>,
+
+    /// Bytes of the buffer that have been read from the file but not sent yet
+    pending: Range<usize>,
+}
+
+impl FileDownload {
+    pub fn new(file: Box<dyn FileReader>, use_sendfile: bool) -> Self {
+        Self {
+            use_sendfile: use_sendfile && file.as_file().is_some(),
+            file,
+            offset: 0,
+            buffer: Vec::new(),
+            pending: 0..0,
+        }
+    }
+
+    /// Bytes of the file already sent to the client
+    pub fn sent(&self) -> u64 {
+        self.offset - self.pending.len() as u64
+    }
+
+    /// Size of the file that is being sent
+    pub fn size(&self) -> Option<u64> {
+        self.file.size().ok()
+    }
+
+    /// Sends at most `max` bytes of the file to the socket.
+    /// Returns the bytes sent, 0 means that the whole file has been sent
+    pub fn send(&mut self, stream: &mut TcpStream, max: usize) -> io::Result<usize> {
+        if self.use_sendfile && self.pending.is_empty() {
+            match self.sendfile(stream, max) {
+                Err(err)
+                    if err.raw_os_error() == Some(libc::EINVAL)
+                        || err.raw_os_error() == Some(libc::ENOSYS) =>
+                {
+                    self.use_sendfile = false;
+                }
+                result => return result,
+            }
+        }
+        self.send_buffered(stream, max)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sendfile(&mut self, stream: &mut TcpStream, max: usize) -> io::Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        let file = match self.file.as_file() {
+            Some(file) => file,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOSYS)),
+        };
+        let mut offset = self.offset as libc::off_t;
+        // This is safe because both file descriptors are owned by us and alive during the call,
+        // and the kernel only writes into `offset`
+        let sent =
+            unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, max) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.offset = offset as u64;
+        Ok(sent as usize)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sendfile(&mut self, _stream: &mut TcpStream, _max: usize) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+
+    fn send_buffered(&mut self, stream: &mut TcpStream, max: usize) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            if self.buffer.is_empty() {
+                self.buffer = vec![0; BUFFER_SIZE];
+            }
+            let to_read = max.min(self.buffer.len());
+            let read = loop {
+                match self.file.read_at(&mut self.buffer[..to_read], self.offset) {
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    result => break result?,
+                }
+            };
+            if read == 0 {
+                return Ok(0);
+            }
+            self.offset += read as u64;
+            self.pending = 0..read;
+        }
+        let end = self.pending.end.min(self.pending.start + max);
+        let written = stream.write(&self.buffer[self.pending.start..end])?;
+        self.pending.start += written;
+        Ok(written)
+    }
+}
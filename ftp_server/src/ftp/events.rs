@@ -0,0 +1,208 @@
+//! Machine readable log of what happens in the server, one JSON object per line.
+//!
+//! Every line has these fields:
+//!
+//! | field     | type            | description                                              |
+//! |-----------|-----------------|----------------------------------------------------------|
+//! | `time`    | string          | RFC 3339 UTC time with milliseconds                      |
+//! | `event`   | string          | kind of the event, see below                             |
+//! | `session` | number \| null  | ID of the control connection                             |
+//! | `peer`    | string \| null  | address and port of the client                           |
+//! | `user`    | string \| null  | user name sent with USER, even if it isn't logged in yet |
+//!
+//! And depending on `event`:
+//!
+//! * `connect`: a client opened a control connection.
+//! * `login`: `success` (bool) and `reason` (string, only when it fails).
+//! * `command`: `command` (uppercase verb as the client sent it), `argument`
+//!   (string or null, always null for PASS and ACCT) and `code` (number, first reply to the command).
+//! * `transfer_start`: `direction` (`upload` or `download`) and `path` (relative to the chroot).
+//! * `transfer_end`: `direction`, `path`, `bytes` (number), `duration_ms` (number)
+//!   and `success` (bool, false when it was aborted).
+//! * `disconnect`: the control connection was closed.
+//!
+//! New fields may be added, but the existing ones keep their name and type.
+//! The file is opened again every time the configuration is reloaded (SIGHUP),
+//! so it can be rotated by moving it and sending the signal.
+
+use super::{logger, throttle::Direction};
+use chrono::SecondsFormat;
+use log::warn;
+use serde::Serialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+static EVENT_LOG: Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Connect,
+    Login {
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<&'a str>,
+    },
+    Command {
+        command: &'a str,
+        argument: Option<&'a str>,
+        code: u16,
+    },
+    TransferStart {
+        direction: Direction,
+        path: &'a str,
+    },
+    TransferEnd {
+        direction: Direction,
+        path: &'a str,
+        bytes: u64,
+        duration_ms: u64,
+        success: bool,
+    },
+    Disconnect,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+    session: Option<usize>,
+    peer: Option<SocketAddr>,
+    user: Option<&'a str>,
+}
+
+/// Command received on a control connection, kept until its reply is sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandLine {
+    pub command: String,
+    pub argument: Option<String>,
+}
+
+impl CommandLine {
+    /// Splits the raw command into its verb and argument, hiding the password of PASS
+    /// and the invite code of ACCT
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        let line = String::from_utf8_lossy(raw);
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut parts = line.splitn(2, ' ');
+        let command = parts.next()?.trim().to_uppercase();
+        if command.is_empty() {
+            return None;
+        }
+        let argument = match command.as_str() {
+            "PASS" | "ACCT" => None,
+            _ => parts.next().map(|argument| argument.to_string()),
+        };
+        Some(Self { command, argument })
+    }
+
+    /// Writes the `command` event with the code of its reply
+    pub fn emit(&self, code: u16) {
+        emit(&Event::Command {
+            command: &self.command,
+            argument: self.argument.as_deref(),
+            code,
+        });
+    }
+}
+
+/// Code of a reply, 0 if it doesn't start with one
+pub fn reply_code(reply: &[u8]) -> u16 {
+    reply
+        .get(..3)
+        .and_then(|code| std::str::from_utf8(code).ok())
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Opens the event log, an empty path disables it
+pub fn configure(path: &str) -> io::Result<()> {
+    let file = if path.is_empty() {
+        None
+    } else {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Some(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+    *EVENT_LOG.lock().unwrap() = file;
+    Ok(())
+}
+
+/// Writes the event with the fields of the session that the current thread is serving
+pub fn emit(event: &Event) {
+    let mut event_log = EVENT_LOG.lock().unwrap();
+    let file = match event_log.as_mut() {
+        Some(file) => file,
+        None => return,
+    };
+    let session = logger::current_session();
+    let line = Line {
+        time: chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        event,
+        session: session.as_ref().map(|session| session.id),
+        peer: session.as_ref().and_then(|session| session.peer),
+        user: session
+            .as_ref()
+            .and_then(|session| session.user.as_deref()),
+    };
+    let mut json = match serde_json::to_vec(&line) {
+        Ok(json) => json,
+        Err(err) => {
+            warn!("[EVENTS] Error serializing {:?}: {}", event, err);
+            return;
+        }
+    };
+    json.push(b'\n');
+    if let Err(err) = file.write_all(&json) {
+        warn!("[EVENTS] Error writing the event log: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandLine, Event, Line};
+    use crate::ftp::throttle::Direction;
+
+    #[test]
+    fn parses_commands() {
+        let retr = CommandLine::parse(b"retr dir/some file.txt\r\n").unwrap();
+        assert_eq!(retr.command, "RETR");
+        assert_eq!(retr.argument.as_deref(), Some("dir/some file.txt"));
+        let pass = CommandLine::parse(b"PASS secret\r\n").unwrap();
+        assert_eq!(pass.argument, None);
+        let acct = CommandLine::parse(b"ACCT invite\r\n").unwrap();
+        assert_eq!(acct.argument, None);
+        assert_eq!(CommandLine::parse(b"\r\n"), None);
+    }
+
+    #[test]
+    fn event_schema() {
+        let event = Event::TransferEnd {
+            direction: Direction::Download,
+            path: "/file.txt",
+            bytes: 10,
+            duration_ms: 3,
+            success: true,
+        };
+        let line = Line {
+            time: "2021-01-01T00:00:00.000Z".to_string(),
+            event: &event,
+            session: Some(1),
+            peer: Some("127.0.0.1:2000".parse().unwrap()),
+            user: Some("user"),
+        };
+        assert_eq!(
+            serde_json::to_string(&line).unwrap(),
+            "{\"time\":\"2021-01-01T00:00:00.000Z\",\"event\":\"transfer_end\",\
+             \"direction\":\"download\",\"path\":\"/file.txt\",\"bytes\":10,\"duration_ms\":3,\
+             \"success\":true,\"session\":1,\"peer\":\"127.0.0.1:2000\",\"user\":\"user\"}"
+        );
+    }
+}
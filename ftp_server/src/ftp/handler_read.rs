@@ -1,28 +1,39 @@
 use super::{command::Command, response::ResponseCode, FileTransferType};
+use super::anonymous;
+use super::config::RegistrationPolicy;
+use super::commands::{Handler, Reply, Session};
+use super::download::FileDownload;
+use super::storage::{self, DirEntry, FileReader, FileWriter, Storage, StorageBackend};
+use super::events::{self, CommandLine, Event};
+use super::hooks::{self, HookEvent};
+use super::logger;
+use super::metrics;
+use super::quota::{self, Charge, Usage};
+use super::registration::{self, Registration};
+use super::transfer::Transfer;
+use super::throttle::{Direction, RateLimiter};
 use super::{
     create_response, Action, ActionList, BufferToWrite, HashMutex, RequestContext,
-    RequestContextMutex, RequestType, Token,
+    RequestContextMutex, RequestType, SharedState, Token,
 };
-use crate::system;
 use crate::port::{get_ftp_port_pair, get_random_port};
+use log::{debug, info, warn};
 use mio::{net::TcpListener, net::TcpStream, Interest, Waker};
-use std::fs;
 use std::{
     convert::TryFrom,
-    path::Path,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Instant,
 };
-use std::{
-    fs::File,
-    io::{ErrorKind, Read},
-};
+use std::io::{ErrorKind, Read};
 use std::{
     io::{Error, Write},
     net::Shutdown,
 };
 // #[macro_use]
 // use super::config::;
-use user_manage::{SystemUsers, User};
+use user_manage::{password, AuthEvent, Permission, SystemUsers, User, ANONYMOUS};
 
 pub struct HandlerRead {
     /// The request context token
@@ -40,51 +51,113 @@ pub struct HandlerRead {
     /// ** Warning Internals: This mutex should never adquired inside `handle_read`, only be used for cloning the Arc
     connection: RequestContextMutex,
 
-    users_db: Arc<Mutex<SystemUsers>>,
+    /// Users database, configuration...
+    shared: SharedState,
+
+    /// Address of the client
+    peer: Option<SocketAddr>,
 
     user_id: Option<String>,
 
     loged: bool,
-}
 
-#[derive(Debug, Clone, Copy)]
-pub enum ErrorTypeUser {
-    PathNotFound,
-    UserNotFound
+    /// Password sent by a user that is registering with an invite code
+    pending_password: Option<String>,
+
+    /// Working directory of the session, it's stored back in the request context after
+    /// every command
+    cwd: String,
+
+    /// Bandwidth limits of the connection that we are reading from
+    rate_limiter: RateLimiter,
 }
 
+
 impl HandlerRead {
+    /// `ctx` is the locked request context of `connection`, its session fields are copied
     pub fn new(
         connection_token: Token,
         connection_db: HashMutex<Token, RequestContextMutex>,
         connection: RequestContextMutex,
-        users_db: Arc<Mutex<SystemUsers>>,
-        user_id: Option<String>,
-        loged: bool,
+        shared: SharedState,
+        ctx: &RequestContext,
     ) -> Self {
         Self {
             connection_token,
             connection_db,
             actions: Vec::new(),
             connection,
-            users_db,
-            user_id,
-            loged,
+            shared,
+            peer: ctx.peer,
+            user_id: ctx.user_id.clone(),
+            loged: ctx.loged,
+            pending_password: ctx.pending_password.clone(),
+            cwd: ctx.cwd.clone(),
+            rate_limiter: ctx.rate_limiter.clone(),
+        }
+    }
+
+    /// Builds the bandwidth limits of a new transfer of the logged user
+    fn transfer_rate_limiter(&self, direction: Direction) -> RateLimiter {
+        let user_name = self.user_id.as_ref().unwrap();
+        let user = self.shared.users_db.lock().unwrap().get_user_clone(user_name);
+        let config = self.shared.config.lock().unwrap();
+        let (global_rate, default_user_rate, session_rate, user_rate) = match direction {
+            Direction::Upload => (
+                config.upload_rate,
+                config.user_upload_rate,
+                config.session_upload_rate,
+                user.and_then(|user| user.get_upload_rate()),
+            ),
+            Direction::Download => (
+                config.download_rate,
+                config.user_download_rate,
+                config.session_download_rate,
+                user.and_then(|user| user.get_download_rate()),
+            ),
+        };
+        self.shared.throttle.limiter(
+            user_name,
+            direction,
+            global_rate,
+            user_rate.unwrap_or(default_user_rate),
+            session_rate,
+        )
+    }
+
+    /// Registers the session of the user that is logging in, returns false if the user
+    /// already has too many sessions
+    fn add_user_session(&self, user: &User) -> bool {
+        // The session was already counted on the first PASS
+        if self.loged {
+            return true;
         }
+        let limit = match user.get_max_sessions() {
+            Some(limit) => limit,
+            None => self.shared.config.lock().unwrap().max_sessions_per_user,
+        };
+        self.shared.user_sessions
+            .try_add(self.user_id.as_ref().unwrap(), limit)
     }
 
     fn handle_file_transfer_download(
         &mut self,
         ctx: &mut RequestContext,
-        file: File,
+        file: Box<dyn FileReader>,
+        path: String,
     ) -> Result<(), Error> {
         match &mut ctx.request_type {
-            RequestType::CommandTransfer(_, _, _, _) | RequestType::Closed(_) => {
+            RequestType::CommandTransfer(_, _, _, _) | RequestType::Closed(_, _) => {
                 Err(Error::from(ErrorKind::NotFound))
             }
             RequestType::FileTransferPassive(_stream, ftt, _)
             | RequestType::FileTransferActive(_stream, ftt, _) => {
-                *ftt = FileTransferType::FileDownload(file);
+                let use_sendfile = self.shared.config.lock().unwrap().sendfile;
+                *ftt = FileTransferType::FileDownload(
+                    FileDownload::new(file, use_sendfile),
+                    Transfer::start(Direction::Download, path),
+                );
+                ctx.rate_limiter = self.transfer_rate_limiter(Direction::Download);
                 Ok(())
             }
             RequestType::PassiveModePort(_, _) => Err(Error::from(ErrorKind::NotFound)),
@@ -94,59 +167,92 @@ impl HandlerRead {
     fn handle_file_transfer_upload(
         &mut self,
         ctx: &mut RequestContext,
-        file: File,
+        file: Box<dyn FileWriter>,
+        path: String,
+        charge: Charge,
     ) -> Result<(), Error> {
         match &mut ctx.request_type {
-            RequestType::CommandTransfer(_, _, _, _) | RequestType::Closed(_) => {
+            RequestType::CommandTransfer(_, _, _, _) | RequestType::Closed(_, _) => {
                 Err(Error::from(ErrorKind::NotFound))
             }
             RequestType::FileTransferPassive(_stream, ftt, _)
             | RequestType::FileTransferActive(_stream, ftt, _) => {
-                *ftt = FileTransferType::FileUpload(file, None);
+                *ftt = FileTransferType::FileUpload(
+                    file,
+                    None,
+                    Transfer::start(Direction::Upload, path),
+                    charge,
+                );
+                ctx.rate_limiter = self.transfer_rate_limiter(Direction::Upload);
                 Ok(())
             }
             RequestType::PassiveModePort(_, _) => Err(Error::from(ErrorKind::NotFound)),
         }
     }
 
-    /// Gets the chroot of the user *non canon
-    pub fn get_user_path(&self) -> Option<String> {
-        let user_id = self.user_id.as_ref().unwrap();
-        let db = self.users_db.lock().unwrap();
-        let user = db.get_user(user_id)?;        
-        Some(user.get_chroot().to_string())
-    } 
+    /// Working directory of the session, as a virtual path
+    fn working_dir(&self) -> String {
+        self.cwd.clone()
+    }
+
+    fn set_working_dir(&mut self, cwd: &str) {
+        self.cwd = cwd.to_string();
+    }
+
+    /// Virtual path of a path sent by the client
+    fn resolve<P: AsRef<Path>>(&self, path: P) -> String {
+        storage::resolve(&self.working_dir(), path)
+    }
+
+    /// Files of the user
+    fn storage(&self) -> Result<Box<dyn StorageBackend>, Error> {
+        let user = self
+            .user_id
+            .as_ref()
+            .and_then(|user_id| self.shared.users_db.lock().unwrap().get_user_clone(user_id))
+            .ok_or_else(|| Error::from(ErrorKind::NotFound))?;
+        self.shared.storage.user_root(&user)
+    }
 
-    /// Returns the current user total path (non canon)
-    pub fn get_user_path_non_canon(&self) -> String {
-        let user_id = self.user_id.as_ref().unwrap();
-        let db = self.users_db.lock().unwrap();
-        let user = db.get_user(user_id).unwrap();        
-        user.total_path_and_decano().to_string()
-    } 
+    /// Name and record of the user
+    fn logged_user(&self) -> Option<(String, User)> {
+        let user_id = self.user_id.as_ref()?;
+        let user = self.shared.users_db.lock().unwrap().get_user_clone(user_id)?;
+        Some((user_id.clone(), user))
+    }
 
-    /// Returns the new path of the user with the specified path
-    pub fn handle_user_path<P: AsRef<Path>>(&self, path: P) -> Result<String, ErrorTypeUser> {
-        let user_id = self.user_id.as_ref().unwrap();
-        let db = self.users_db.lock().unwrap();
-        let user = db.get_user(user_id);
-        if let None = user {
-            return Err(ErrorTypeUser::UserNotFound);
+    /// Takes `freed` out of the quota usage that `path` counts in
+    fn release(&self, path: &str, freed: Usage) {
+        if let Some((user_id, user)) = self.logged_user() {
+            self.shared.quotas.release(&user_id, &user, path, freed);
         }
-        let user = user.unwrap();       
-        User::new_dir(&&user.get_chroot(), &&user.get_actual_dir(), path.as_ref()).map_err(|_| ErrorTypeUser::PathNotFound)        
     }
 
     /// Handles when the user is actually on a bad directory
-    pub fn safe_change_dir_for_user(&mut self) {
-        let user_id = self.user_id.as_ref().unwrap();
-        let mut db = self.users_db.lock().unwrap();
-        let user = db.get_user_mut(user_id);
-        if let None = user {
-            return;
+    fn safe_change_dir_for_user(&mut self, storage: &dyn StorageBackend) {
+        let mut cwd = self.working_dir();
+        while cwd != "/" && !storage.stat(&cwd).map(|metadata| metadata.is_dir).unwrap_or(false) {
+            cwd = storage::parent(&cwd).to_string();
+        }
+        self.set_working_dir(&cwd);
+    }
+
+    /// Lines of LIST, the paths are relative to `ROOT` as they were when it read the disk
+    fn list_lines(&self, dir: &str, entries: Vec<DirEntry>) -> Vec<u8> {
+        let chroot = self
+            .user_id
+            .as_ref()
+            .and_then(|user_id| self.shared.users_db.lock().unwrap().get_user_clone(user_id))
+            .map(|user| user.get_chroot().clone())
+            .unwrap_or_default();
+        let prefix: PathBuf = Path::new(&chroot).components().skip(2).collect();
+        let mut lines = Vec::new();
+        for entry in entries {
+            let path = storage::join(dir, &entry.name);
+            let line = prefix.join(path.trim_start_matches('/'));
+            lines.extend_from_slice(format!("{}\r\n", line.to_string_lossy()).as_bytes());
         }
-        let user = user.unwrap();   
-        user.change_dir_to_recursive_if_doesnt_exist();
+        lines
     }
 
     /// This function handles the read of the `request_type`,
@@ -181,13 +287,13 @@ impl HandlerRead {
                     return Ok(None);
                 }
 
-                print_stdout!(
+                debug!(
                     "[HANDLE_READ] {} - {} bytes read",
                     self.connection_token.0, read
                 );
 
                 if read >= buff.len() {
-                    print_stdout!(
+                    debug!(
                         "[HANDLE_READ] {} - command is too big, returning bad sequence of commands",
                         self.connection_token.0
                     );
@@ -200,673 +306,33 @@ impl HandlerRead {
                     return Ok(None);
                 }
 
-                // Translate to Command enum
-                let possible_command = Command::try_from(&buff[..read]);
-
-                // Check if it's a valid command
-                if let Err(message) = possible_command {
-                    print_stdout!(
-                        "[HANDLE_READ] {} - User sent a bad command {}",
-                        self.connection_token.0, message
-                    );
-                    to_write.reset(create_response(
-                        ResponseCode::bad_sequence_of_commands(),
-                        message,
-                    ));
-                    self.actions.push((
-                        self.connection_token,
-                        self.connection.clone(),
-                        Interest::WRITABLE,
-                    ));
-                    return Ok(None);
-                }
-
-                // Get the command
-                let command =
-                    possible_command.expect("command parse is not an error, this is safe");
+                to_write.command = CommandLine::parse(&buff[..read]);
 
-                if command.is_auth_command() && (self.user_id.is_none() || !self.loged) {
-                    self.actions.push((
-                        self.connection_token,
-                        self.connection.clone(),
-                        Interest::WRITABLE,
-                    ));
-                    to_write.reset(create_response(ResponseCode::unauthorized(), "Unauthorized."));                
-                    return Ok(None);
-                }
-
-                match command {
-                    Command::RenameFrom(from) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        if let Ok(path) = self.handle_user_path(from) {
-                            *path_from = Some(path);
-                            to_write.reset(create_response(
-                                ResponseCode::file_action_pending(), "Requested file action pending further information."));
-                            return Ok(None);
-                        }
-                        to_write.reset(create_response(
-                            ResponseCode::file_unavailable(), "File unavailable, file not found."));
-                    } 
-
-                    Command::RenameTo(to) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        if let Some(from) = path_from.take() {
-                            let mut to_no_child = Path::new(to.clone()).to_path_buf();
-                            to_no_child.pop();
-                            let to_path = self.handle_user_path(to_no_child);                            
-                            let from = Path::new(&from);
-                            let to_child = to.file_name();
-                            if !(to_path.is_err() || to_child.is_none()) {
-                                let to_path = to_path.expect("safe");
-                                let to = format!("{}/{}", to_path, to_child.unwrap().to_str().unwrap());              
-                                let rename_result = system::rename(from.to_str().unwrap(), to.as_str());
-                                if rename_result.is_ok() {
-                                    to_write.reset(create_response(
-                                        ResponseCode::file_action_okay(),
-                                        "Requested file action okay, completed."
-                                    ));
-                                    return Ok(None);                                
-                                }                                               
-                            }
-                        } 
-                        to_write.reset(
-                            create_response(ResponseCode::file_action_not_taken(), "Requested action not taken. File name not allowed.")
-                        );
-                       
-                    }
-
-                    Command::CurrentDirectory => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        let users_db = self.users_db.lock().unwrap();
-                        let user = users_db.get_user_clone(self.user_id.as_ref().unwrap()).unwrap();
-                        drop(users_db);
-                        to_write.reset(
-                            create_response(ResponseCode::directory_action_okay(), &user.total_path_and_decano())
-                        );
-                    }
-
-                    Command::ChangeDirectory(dir) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        let mut users_db = self.users_db.lock().unwrap();
-                        let user = users_db.get_user_mut(self.user_id.as_ref().unwrap()).unwrap();
-                        let result = user.change_dir(dir);
-                        drop(user);
-                        drop(users_db);
-                        if result.is_err() {
-                            to_write.reset(create_response(
-                                ResponseCode::file_unavailable(),
-                                "Requested action not taken. File unavailable, file not found.",
-                            ));
-                            return Ok(None);
-                        } 
-                        to_write.reset(create_response(
-                            ResponseCode::file_action_okay(),
-                            "Requested file action okay, completed.",
-                        ));
-                        return Ok(None);
-                    }
-
-                    Command::Passive => {
-                        // We know that we will write to the command socket anyways, add an action interest
-                        // An array of actions is a list of a token of a socket, the socket, and the interest,
-                        // Then in the ftp module it will take the mutex of the action list that the TCP implementation is using
-                        // and add it
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));                        
-                        let random_port = get_random_port();
-                        if let Some(port) = random_port {
-                            // Create tcp listener and add it to the connections database
-                            let tcp_listener =
-                                TcpListener::bind(format!("0.0.0.0:{}", port).parse().unwrap())
-                                    .expect("port to be init");
-                            let mut db = self.connection_db.lock().unwrap();
-                            // Smart multithread safe pointer where we got a mutex of a socket
-                            let arc = Arc::new(Mutex::new(RequestContext::new(
-                                RequestType::PassiveModePort(tcp_listener, self.connection_token),
-                            )));                            
-                            db.insert(Token(next_id), arc.clone());
-                            // Mark the listener as readable so we can read new connections
-                            self.actions.push((Token(next_id), arc, Interest::READABLE));
-                            let (first_part, second_part) = get_ftp_port_pair(port);
-                            to_write.reset(
-                                create_response(
-                                    ResponseCode::passive_ok(),
-                                    format!(
-                                        "Entering Passive Mode (0,0,0,0,{},{})",
-                                        first_part, second_part
-                                    )
-                                    .as_str()
-                                )
-                            );                            
-                            return Ok(None);
-                        }
-                        to_write.reset(create_response(ResponseCode::all_ports_taken(), "All ports taken."));                        
-                        return Ok(None);
-                    }
-
-                    Command::Quit => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        to_write.reset(
-                            create_response(
-                                ResponseCode::closing_control_connection_success(),
-                                "Service closing control connection.",                                
-                            ));
-                        let conn = self.connection.clone();
-                        to_write.callback_after_sending = Some(Box::new(move || {
-                            let connection = conn.lock().unwrap();
-                            if let RequestType::CommandTransfer(stream, _, _, _) =
-                                &connection.request_type
-                            {
-                                let _ = stream.shutdown(Shutdown::Both);
-                            }
-                        }));
-                        return Ok(None);
-                    }
-
-                    Command::Password(pwd) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        let mut db = self.users_db.lock().unwrap();                        
-                        if let Some(user_id) = &self.user_id {
-                            if !db.user_exists(&user_id) {
-                                let user = db.create_user(&user_id, pwd);
-                                if user.is_err() {
-                                    to_write.reset(create_response(ResponseCode::unauthorized(), "Not logged in."));
-                                    return Ok(None);
-                                }                                
-                                to_write.reset(create_response(
-                                    ResponseCode::login_success(),
-                                    "User logged in, proceed.",
-                                ));
-                                return Ok(Some(Box::new(|ctx| {
-                                    ctx.loged = true;
-                                })));
-                            }
-                            if db.has_passwd(user_id, pwd) {
-                                to_write.reset(create_response(
-                                    ResponseCode::login_success(),
-                                    "User logged in, proceed.",
-                                ));
-                                return Ok(Some(Box::new(move |ctx| {                                
-                                    ctx.loged = true;
-                                })));
-                            }
-                            to_write.reset(create_response(ResponseCode::unauthorized(), "Not logged in."));
-                            return Ok(None);
-                        }
-                        to_write.reset(create_response(ResponseCode::unauthorized(), "Not logged in."));
-                        return Ok(None);
-                    }
-
-                    Command::User(username) => {                        
-                        print_stdout!(
-                            "[HANDLE_READ] {} - New user {}",
-                            self.connection_token.0, username
-                        );
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        to_write.reset(create_response(
-                            ResponseCode::username_okay(),
-                            "User name okay, need password.",
-                        ));
-                        let username = username.to_string();
-                        return Ok(Some(Box::new(move |ctx| {
-                            ctx.user_id = Some(username);
-                            ctx.loged = false;
-                        })));
-                    }
-
-                    Command::Delete(path) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        if let Ok(path) = self.handle_user_path(path) {
-                            let result = fs::remove_file(path);
-                            if let Err(_err) = result {
-                                to_write.reset(create_response(
-                                    ResponseCode::file_unavailable(),
-                                    "Requested action not taken. File unavailable, file not found.",
-                                ));
-                                return Ok(None);
-                            } 
-                            to_write.reset(create_response(
-                                ResponseCode::file_action_okay(),
-                                "Requested file action okay, completed.",
-                            ));
-                            return Ok(None);
-                        }  else { 
-                            to_write.reset(create_response(
-                                ResponseCode::file_unavailable(),
-                                "Requested action not taken. File unavailable, file not found.",
-                            ));
-                            return Ok(None);
-                        }
-                    }
-
-                    Command::RemoveDirectory(directory) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        if let Ok(path) = self.handle_user_path(directory) {
-                            let result = fs::remove_dir_all(path);
-                            if let Err(_err) = result {
-                                to_write.reset(create_response(
-                                    ResponseCode::file_unavailable(),
-                                    "Requested action not taken. File unavailable, file not found.",
-                                ));
-                                return Ok(None);
-                            } 
-
-                            // Check if the client is a bit dumbass and deleted its own directory
-                            self.safe_change_dir_for_user();
-
-                            to_write.reset(create_response(
-                                ResponseCode::file_action_okay(),
-                                "Requested file action okay, completed.",
-                            ));
-                            return Ok(None);
-                        }  else { 
-                            to_write.reset(create_response(
-                                ResponseCode::file_unavailable(),
-                                "Requested action not taken. File unavailable, file not found.",
-                            ));
-                            return Ok(None);
-                        }
-                    }
-
-                    Command::Retr(path) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-
-                        if let None = data_connection {
-                            to_write.reset(create_response(
-                                ResponseCode::bad_sequence_of_commands(),
-                                "Bad sequence of commands.",
-                            ));
-                            return Ok(None);
-                        }                    
-                        if let Ok(path) = self.handle_user_path(path) {                        
-                            let file = File::open(path);
-                            if let Err(_) = file {
-                                to_write.reset(create_response(
-                                    ResponseCode::file_unavailable(),
-                                    "Requested action not taken. File unavailable, file not found.",
-                                ));
-                                return Ok(None);
-                            }
-                            let file = file.unwrap();
-                            let mut connection_db = self.connection_db.lock().unwrap();
-                            let token_data_conn = data_connection.take().unwrap();
-                            let data_transfer_conn = connection_db.get_mut(&token_data_conn);
-                            if data_transfer_conn.is_none() {
-                                to_write.reset(create_response(
-                                    ResponseCode::file_unavailable(),
-                                    "Requested action not taken. File unavailable, no access.",
-                                ));
-                                return Ok(None);
-                            }
-                            let data_transfer_conn = data_transfer_conn.unwrap().clone();
-                            // Drop mutex because we are gonna do more stuff
-                            drop(connection_db);
-                            let mut data_transfer_conn_mutex = data_transfer_conn.lock().unwrap();
-                            if let Err(_) = self
-                                .handle_file_transfer_download(&mut data_transfer_conn_mutex, file)
-                            {
-                                to_write.reset(create_response(
-                                    ResponseCode::file_unavailable(),
-                                    "Requested action not taken. File unavailable, file not found.",
-                                ));
-                            } else {
-                                to_write.reset(create_response(
-                                    ResponseCode::file_status_okay(),
-                                    "File status okay; about to open data connection.",
-                                ));
-                                let ctx = data_transfer_conn.clone();
-                                let cb = move || {
-                                    actions.lock().unwrap().push((
-                                        token_data_conn,
-                                        ctx,
-                                        Interest::WRITABLE,
-                                    ))
-                                };
-                                to_write.callback_after_sending = Some(Box::new(cb));
-                            }
-                        } else {
-                            to_write.reset(create_response(
-                                ResponseCode::file_unavailable(),
-                                "Requested action not taken. File unavailable, file not found.",
-                            ));
-                            return Ok(None);
-                        }
-                    }
-
-                    Command::Mkdir(path) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE
-                        ));
-                        let mut callback_error = || {
-                            to_write.reset(create_response(
-                                ResponseCode::file_unavailable(),
-                                "Requested action not taken. File unavailable, no access.",
-                            ));
-                        };
-                        let path_user = self.get_user_path();
-                        if let None = path_user {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let base = path_user.unwrap();
-                        let root_path = Path::new(base.as_str()).canonicalize().unwrap();
-                        let true_base = root_path.to_str().unwrap();
-                        let mut path = path.to_path_buf();
-                        let child = path.file_name().map(|el| el.to_str().unwrap().to_string()).clone();
-                        path.pop();
-                        let el = path.as_path();
-                        let parent = if el == Path::new("/") { Path::new("./") } else { el };
-                        if child.is_none() {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let child = child.unwrap();                                            
-                        let parent = Path::new(self.get_user_path_non_canon().as_str()).join(parent);
-                        let path = format!("./{}", parent.to_str().unwrap());                        
-                        let total_path = root_path.join(path).canonicalize();
-                        if let Ok(path) = total_path {
-                            if !path.starts_with(true_base) {
-                                callback_error();
-                                return Ok(None);
-                            }
-                            let end_path = path.join(&child);
-                            let result = std::fs::create_dir(end_path);
-                            if result.is_err() {
-                                callback_error();
-                                return Ok(None);
-                            }
-                            let resp = format!("'{}' directory created.", child);
-                            to_write.reset(create_response(
-                                ResponseCode::directory_action_okay(),
-                                resp.as_str(),
-                            ));
-                        } else {
-                            callback_error();
-                        }
-                        return Ok(None);
-                    }
-
-                    Command::Store(path) => {
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-                        let mut callback_error = || {
-                            to_write.reset(create_response(
-                                ResponseCode::file_unavailable(),
-                                "Requested action not taken. File unavailable, no access.",
-                            ));
-                        };
-                        if let None = data_connection {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let path_user = self.get_user_path();
-                        if let None = path_user {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let base = path_user.unwrap();
-                        let root_path = Path::new(base.as_str()).canonicalize();
-                        if root_path.is_err() {                            
-                            callback_error();
-                            return Ok(None);
-                        }                           
-                        let root_path = root_path.unwrap();                 
-                        let true_base = root_path.to_str().unwrap();
-                        let mut path = path.to_path_buf();
-                        let child = path.file_name().map(|el| el.to_str().unwrap().to_string()).clone();
-                        path.pop();
-                        let el = path.as_path();
-                        let parent = if el == Path::new("/") { Path::new("./") } else { el };
-                        if child.is_none() {
-                            callback_error();
-                            return Ok(None);
-                        }
-                        let child = child.unwrap();                                            
-                        let parent = Path::new(self.get_user_path_non_canon().as_str()).join(parent);
-                        let path = format!("./{}", parent.to_str().unwrap());
-                        let total_path = root_path.join(path).canonicalize();
-                        if let Ok(path) = total_path {
-                            if !path.starts_with(true_base) {
-                                callback_error();
-                                return Ok(None);
-                            }
-                            let end_path = path.join(child);
-                            let _ = fs::remove_file(&end_path);
-                            let file_options = fs::OpenOptions::new()
-                                .append(false)
-                                .create(true)
-                                .write(true)
-                                .open(end_path.clone());
-                            if let Ok(file) = file_options {
-                                // Check that the file is really on a good position to exist
-                                if end_path.canonicalize().is_err() {
-                                    callback_error();
-                                    return Ok(None);
-                                }
-                                let db = self.connection_db.lock().unwrap();
-                                let token_data = data_connection.take().unwrap();
-                                let conn = db.get(&token_data);
-                                if let Some(conn) = conn {
-                                    // Clone Arc because we must drop DB lock
-                                    let conn = conn.clone();
-                                    drop(db);
-                                    let mut conn_lock = conn.lock().unwrap();
-                                    if let Err(_) =
-                                        self.handle_file_transfer_upload(&mut conn_lock, file)
-                                    {
-                                        callback_error();
-                                        return Ok(None);
-                                    }
-                                    // HEH... I don't know but Rust doesn't get that this really needs to die here!
-                                    drop(conn_lock);
-                                    to_write.reset(create_response(
-                                        ResponseCode::file_status_okay(),
-                                        "File status okay; about to open data connection.",
-                                    ));
-                                    to_write.callback_after_sending =
-                                        Some(Box::new(move || {                                         
-                                            let mut actions = actions.lock().unwrap();
-                                            actions.push((token_data, conn, Interest::READABLE));
-                                        }));                            
-                                    return Ok(None);
-                                }
-                            }
-                            callback_error();
-                            return Ok(None);
-                        } 
-                        callback_error();       
-                        return Ok(None);                 
-                    }
-
-                    Command::List(path) => {
-                        // Inform that we are interested in writing a command again
-                        self.actions.push((
-                            self.connection_token,
-                            self.connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-
-                        // This means that the user hasn't opened a port or connected
-                        if let None = data_connection {
-                            to_write.reset(create_response(
-                                ResponseCode::bad_sequence_of_commands(),
-                                "Bad sequence of commands.",
-                            ));
-                            return Ok(None);
-                        }
-                        // All okay, transition
-                        to_write.reset(create_response(
-                            ResponseCode::file_status_okay(),
-                            "File status okay; about to open data connection.",
-                        ));
-
-                        // Now here we are gonna prepare the callback for when the
-                        // transition command is sent
-                        let data_connection = data_connection.unwrap();
-
-                        // get the connection database
-                        let mut connection_db = self.connection_db.lock().unwrap();
-
-                        // Get the data transfer connection
-                        let connection = connection_db.get_mut(&data_connection);
-
-                        // Clone waker smart reference
-                        let waker = waker.clone();
-
-                        // Unwrap the connection
-                        if let Some(connection) = connection {
-                            // Clone the smart reference of this request context
-                            let connection = connection.clone();
-                            let res = self.handle_user_path(path);
-                            if let Ok(path) = res {
-
-                                let list = system::ls(path.as_str()).unwrap();
-                                // Create a callback that captures everything it needs
-                                let callback = move || {
-                                    // Lock the request context
-                                    let mut connection_m = connection.lock().unwrap();
-
-                                    // Remember that this is the data connection,
-                                    // fill the data to write
-                                    match &mut connection_m.request_type {
-                                        RequestType::FileTransferPassive(_, ftt, _)
-                                        | RequestType::FileTransferActive(_, ftt, _) => {
-                                            *ftt = FileTransferType::Buffer(BufferToWrite::new(
-                                                list
-                                            ));
-                                        }
-                                        _ => unimplemented!(),
-                                    }
-
-                                    // Now that we are free of the connection mutex, it's safe
-                                    // to add to the actions array
-                                    actions.lock().unwrap().push((
-                                        data_connection,
-                                        connection.clone(),
-                                        Interest::WRITABLE,
-                                    ));
-
-                                    // wake the Poll
-                                    let _ = waker.wake();
-                                };
-
-                                // Set the callback
-                                to_write.callback_after_sending = Some(Box::new(callback));
-                            } else {
-                                to_write.reset(create_response(
-                                    ResponseCode::file_unavailable(),
-                                    "Requested action not taken. File unavailable, no access.",
-                                ));
-                            }
-                        } else {
-                            // Inform the user that we couldn't find the data connection
-                            to_write.reset(create_response(
-                                ResponseCode::cant_open_data_connection(),
-                                "Can't open data connection.",
-                            ));
-                        }
+                // Whatever the command is we answer it
+                self.actions.push((
+                    self.connection_token,
+                    self.connection.clone(),
+                    Interest::WRITABLE,
+                ));
+                let mut control = Control {
+                    to_write,
+                    data_connection,
+                    path_from,
+                    waker,
+                    actions,
+                    next_id,
+                    after: None,
+                };
+                let reply = self.dispatch(&mut control, &buff[..read]);
+                control.to_write.reset(reply.into_bytes());
+                let cwd = self.cwd.clone();
+                let after = control.after;
+                Ok(Some(Box::new(move |ctx: &mut RequestContext| {
+                    ctx.cwd = cwd;
+                    if let Some(after) = after {
+                        after(ctx);
                     }
-
-                    // When this command is fired we should connect to the desired port by the user
-                    Command::Port(ip, port) => {
-                        // Clone the database conn reference
-                        let map_conn = self.connection_db.clone();
-
-                        let connection =
-                            TcpStream::connect(format!("{}:{}", ip, port).parse().unwrap());
-
-                        let mut connections = map_conn.lock().unwrap();
-
-                        let command_connection = connections
-                            .get_mut(&self.connection_token)
-                            .expect("TODO handle this error");
-
-                        // Tell the server that we want to write
-                        self.actions.push((
-                            self.connection_token,
-                            command_connection.clone(),
-                            Interest::WRITABLE,
-                        ));
-
-                        // Handle error where the connection is not opened by the client
-                        if connection.is_err() {
-                            to_write.reset(create_response(
-                                ResponseCode::bad_sequence_of_commands(),
-                                "Bad sequence of commands.",
-                            ));
-                            return Ok(None);
-                        }
-
-                        // fill data connection token (so later on the request context command keeps a reference
-                        // to the request context of the file transfer)
-                        *data_connection = Some(Token(next_id));
-
-                        to_write.reset(create_response(ResponseCode::command_okay(), "Command okay."));
-
-                        drop(command_connection);
-
-                        let connection = connection.unwrap();
-                        let request_ctx = Arc::new(Mutex::new(RequestContext::new(
-                            RequestType::FileTransferActive(
-                                connection,
-                                FileTransferType::Buffer(BufferToWrite::default()),
-                                self.connection_token,
-                            ),
-                        )));
-                        connections.insert(Token(next_id), request_ctx);
-                        return Ok(None);
-                    }                   
-                }
-
-                Ok(None)
+                })))
             }
 
             RequestType::PassiveModePort(listener, command_conn_ref) => {
@@ -919,7 +385,7 @@ impl HandlerRead {
 
             RequestType::FileTransferActive(stream, type_connection, _data_conn_token)
             | RequestType::FileTransferPassive(stream, type_connection, _data_conn_token) => {
-                print_stdout!("[HANDLE_READ] Yeah let's go");
+                debug!("[HANDLE_READ] Yeah let's go");
                 if let Ok(should_close) = self.handle_file_type(stream, type_connection) {
                     if should_close {
                         let _ = stream.shutdown(Shutdown::Both);
@@ -941,13 +407,32 @@ impl HandlerRead {
         transfer_type: &mut FileTransferType,
     ) -> Result<bool, ()> {
         match transfer_type {
-            FileTransferType::FileUpload(file, possible_response) => {
-                print_stdout!(
+            FileTransferType::FileUpload(file, possible_response, transfer, charge) => {
+                debug!(
                     "[HANDLE_FILE_TYPE] {} - Reading from file transfer...",
                     self.connection_token.0
                 );
                 let mut buff = [0; 10024];
-                let read_result = stream.read(&mut buff);
+                let allowed = match self.rate_limiter.allowance(buff.len()) {
+                    Ok(allowed) => allowed,
+                    Err(wait) => {
+                        debug!(
+                            "[HANDLE_FILE_TYPE] {} - Throttled, reading again in {:?}",
+                            self.connection_token.0,
+                            wait
+                        );
+                        self.shared.delayed_actions.lock().unwrap().push((
+                            Instant::now() + wait,
+                            (
+                                self.connection_token,
+                                self.connection.clone(),
+                                Interest::READABLE,
+                            ),
+                        ));
+                        return Ok(false);
+                    }
+                };
+                let read_result = stream.read(&mut buff[..allowed]);
                 self.actions.push((
                     self.connection_token,
                     self.connection.clone(),
@@ -955,29 +440,60 @@ impl HandlerRead {
                 ));
                 if let Ok(read_bytes) = read_result {                   
                     if read_bytes == 0 {
+                        if let Err(err) = file.finish() {
+                            *possible_response = Some(b"451 Requested action aborted: local error in processing.\r\n".to_vec());
+                            warn!(
+                                "[HANDLE_FILE_TYPE] {} - Error finishing the file {}...",
+                                self.connection_token.0, err
+                            );
+                            return Err(());
+                        }
                         *possible_response = 
                         Some(create_response(
                             ResponseCode::success_uploading_file(), 
                             "Closing data connection. Requested file action successful (file transfer)."
                         ));             
+                        transfer.finish(true);
                         return Ok(true);
                     }
-                    let err = file.write(&buff[..read_bytes]);
-                    if err.is_err() {
-                        print_stdout!(
+                    self.rate_limiter.consume(read_bytes);
+                    transfer.bytes += read_bytes as u64;
+                    metrics::bytes(Direction::Upload, read_bytes as u64);
+                    if !charge.add(read_bytes as u64) {
+                        *possible_response = Some(create_response(
+                            ResponseCode::exceeded_storage_allocation(),
+                            "Requested file action aborted. Exceeded storage allocation (bytes).",
+                        ));
+                        info!(
+                            "[HANDLE_FILE_TYPE] {} - Upload stopped, the quota is exceeded",
+                            self.connection_token.0
+                        );
+                        return Err(());
+                    }
+                    let err = file.write_all(&buff[..read_bytes]);
+                    if let Err(write_err) = &err {
+                        *possible_response = Some(if write_err.kind() == ErrorKind::StorageFull {
+                            create_response(
+                                ResponseCode::insufficient_storage(),
+                                "Requested action not taken. Insufficient storage space in system.",
+                            )
+                        } else {
+                            b"451 Requested action aborted: local error in processing.\r\n".to_vec()
+                        });
+                        warn!(
                             "[HANDLE_FILE_TYPE] {} - Error writing to file {}...",
                             self.connection_token.0,
                             err.as_ref().unwrap_err()
                         );
                         return Err(());
                     }                                      
-                    print_stdout!(
+                    debug!(
                         "[HANDLE_FILE_TYPE] {} - Successfully read...",
                         self.connection_token.0
                     );
                 } else if let Err(err) = read_result {
                     if err.kind() == ErrorKind::WouldBlock {
-                        print_stdout!(
+                        debug!(
                             "[HANDLE_FILE_TYPE] {} - Would block...",
                             self.connection_token.0
                         );                        
@@ -989,7 +505,7 @@ impl HandlerRead {
                         return Ok(false);
                     }                    
                     *possible_response = Some(b"451 Requested action aborted: local error in processing.\r\n".to_vec());
-                    print_stdout!(
+                    warn!(
                         "[HANDLE_FILE_TYPE] {} - Error Reading File: {}...",
                         self.connection_token.0, err
                     );
@@ -1002,3 +518,833 @@ impl HandlerRead {
         }
     }
 }
+
+type ContextChange = Box<dyn FnOnce(&mut RequestContext) + Send>;
+
+/// Parts of the control connection that the built-in commands use
+struct Control<'a> {
+    to_write: &'a mut BufferToWrite,
+
+    /// Data connection opened with PORT or PASV
+    data_connection: &'a mut Option<Token>,
+
+    /// Path sent with RNFR
+    path_from: &'a mut Option<String>,
+
+    waker: &'a Arc<Waker>,
+
+    actions: ActionList,
+
+    /// Token of the connection that the command opens, if it opens one
+    next_id: usize,
+
+    /// Change to the request context once the command has been handled
+    after: Option<ContextChange>,
+}
+
+impl HandlerRead {
+    /// What the handlers of the registry can see of this session
+    fn session(&self) -> Session<'_> {
+        Session {
+            id: self.connection_token.0,
+            peer: self.peer,
+            user: self.user_id.as_deref().filter(|_| self.loged),
+            cwd: &self.cwd,
+            shared: &self.shared,
+        }
+    }
+
+    /// Looks up the handler of the command in the registry and runs it
+    fn dispatch(&mut self, control: &mut Control, raw: &[u8]) -> Reply {
+        let verb = control
+            .to_write
+            .command
+            .as_ref()
+            .map(|command| command.command.clone())
+            .unwrap_or_default();
+        let disabled = self
+            .shared
+            .config
+            .lock()
+            .unwrap()
+            .disabled_commands
+            .iter()
+            .any(|disabled| disabled.eq_ignore_ascii_case(&verb));
+        let commands = self.shared.commands.clone();
+        let handler = if disabled { None } else { commands.get(&verb) };
+        let needs_login = match handler {
+            Some(Handler::BuiltIn { needs_login }) => *needs_login,
+            Some(Handler::Custom(handler)) => handler.needs_login(),
+            None => false,
+        };
+        if needs_login && (self.user_id.is_none() || !self.loged) {
+            return Reply::new(ResponseCode::unauthorized(), "Unauthorized.");
+        }
+        match handler {
+            Some(Handler::BuiltIn { .. }) => match Command::try_from(raw) {
+                Ok(command) if !self.is_allowed(&command) => {
+                    info!(
+                        "[HANDLE_READ] {} - {} denied by the permissions of the user",
+                        self.connection_token.0, verb
+                    );
+                    Reply::new(
+                        ResponseCode::file_unavailable(),
+                        "Requested action not taken. Permission denied.",
+                    )
+                }
+                Ok(command) => self.builtin(control, command),
+                Err(message) => {
+                    debug!(
+                        "[HANDLE_READ] {} - User sent a bad command {}",
+                        self.connection_token.0, message
+                    );
+                    Reply::new(ResponseCode::bad_sequence_of_commands(), message)
+                }
+            },
+            Some(Handler::Custom(handler)) => {
+                let argument = control
+                    .to_write
+                    .command
+                    .as_ref()
+                    .and_then(|command| command.argument.clone());
+                handler.handle(&self.session(), argument.as_deref())
+            }
+            None if disabled => Reply::new(
+                ResponseCode::command_not_implemented(),
+                "Command not implemented.",
+            ),
+            // Keep the hint of the parser for the typos
+            None => match Command::try_from(raw) {
+                Err(message) => Reply::new(ResponseCode::bad_sequence_of_commands(), message),
+                Ok(_) => Reply::new(
+                    ResponseCode::command_not_implemented(),
+                    "Command not implemented.",
+                ),
+            },
+        }
+    }
+
+    /// Checks the `permissions` of the user for the path that the command works with
+    fn is_allowed(&self, command: &Command) -> bool {
+        let (permission, path) = match command {
+            Command::List(path) => (Permission::List, path),
+            Command::Retr(path) => (Permission::Read, path),
+            Command::Store(path) => (Permission::Write, path),
+            Command::Append(path) => (Permission::Append, path),
+            Command::Delete(path) => (Permission::Delete, path),
+            Command::RenameFrom(path) | Command::RenameTo(path) => (Permission::Rename, path),
+            Command::Mkdir(path) => (Permission::Mkdir, path),
+            Command::RemoveDirectory(path) => (Permission::Rmdir, path),
+            _ => return true,
+        };
+        match self.logged_user() {
+            Some((_, user)) => user.is_allowed(permission, &self.resolve(path)),
+            None => true,
+        }
+    }
+
+    fn builtin(&mut self, control: &mut Control, command: Command) -> Reply {
+        match command {
+            Command::User(username) => self.user(control, username),
+            Command::Password(password) => self.pass(control, password),
+            Command::Account(code) => self.acct(control, code),
+            Command::Quit => self.quit(control),
+            Command::Port(ip, port) => self.port(control, ip, port),
+            Command::Passive => self.pasv(control),
+            Command::List(path) => self.list(control, path),
+            Command::Retr(path) => self.retr(control, path),
+            Command::Store(path) => self.stor(control, path, false),
+            Command::Append(path) => self.stor(control, path, true),
+            Command::CurrentDirectory => self.pwd(),
+            Command::ChangeDirectory(path) => self.cwd(path),
+            Command::Mkdir(path) => self.mkd(path),
+            Command::RemoveDirectory(path) => self.rmd(path),
+            Command::Delete(path) => self.dele(path),
+            Command::RenameFrom(path) => self.rnfr(control, path),
+            Command::RenameTo(path) => self.rnto(control, path),
+            Command::Site(argument) => self.site(argument),
+        }
+    }
+
+    fn user(&mut self, control: &mut Control, username: &str) -> Reply {
+        info!(
+            "[HANDLE_READ] {} - New user {}",
+            self.connection_token.0, username
+        );
+        let anonymous = self.shared.config.lock().unwrap().anonymous.enabled;
+        let username = if anonymous && anonymous::is_anonymous_name(username) {
+            ANONYMOUS.to_string()
+        } else {
+            username.to_string()
+        };
+        if self.loged {
+            hooks::trigger(HookEvent::Logout, None, None, None);
+        }
+        logger::set_session_user(self.connection_token.0, Some(&username));
+        let user_sessions = self.shared.user_sessions.clone();
+        control.after = Some(Box::new(move |ctx| {
+            // Changing the user finishes the previous session
+            if ctx.loged {
+                if let Some(previous) = &ctx.user_id {
+                    user_sessions.remove(previous);
+                }
+            }
+            ctx.user_id = Some(username);
+            ctx.loged = false;
+            ctx.pending_password = None;
+            ctx.cwd = "/".to_string();
+        }));
+        Reply::new(ResponseCode::username_okay(), "User name okay, need password.")
+    }
+
+    fn pass(&mut self, control: &mut Control, pwd: &str) -> Reply {
+        let user_id = match &self.user_id {
+            Some(user_id) => user_id.clone(),
+            None => return self.login_failed("no user"),
+        };
+        let registration = registration::check(
+            &self.shared.config.lock().unwrap().registration,
+            &user_id,
+            self.peer.map(|peer| peer.ip()),
+        );
+        let exists = {
+            let db = self.shared.users_db.lock().unwrap();
+            db.audit(AuthEvent::Attempt, &user_id, self.peer.map(|peer| peer.ip()));
+            db.user_exists(&user_id)
+        };
+        if !exists {
+            match registration {
+                Registration::Create => {
+                    // Hashed before locking the users, it takes a while
+                    let hash = password::hash(pwd);
+                    let created = self
+                        .shared
+                        .users_db
+                        .lock()
+                        .unwrap()
+                        .create_user_hashed(&user_id, hash);
+                    if created.is_err() {
+                        return self.login_failed("user can't be created");
+                    }
+                    info!(
+                        "[HANDLE_READ] {} - User {} registered",
+                        self.connection_token.0, user_id
+                    );
+                }
+                Registration::NeedsInvite => {
+                    let password = pwd.to_string();
+                    control.after = Some(Box::new(move |ctx| {
+                        ctx.pending_password = Some(password);
+                    }));
+                    return Reply::new(
+                        ResponseCode::need_account(),
+                        "Need account for login, send the invite code with ACCT.",
+                    );
+                }
+                // Checked like a wrong password so the answer and the time are the same
+                Registration::Denied => {}
+            }
+        }
+        if let Err(reason) = SystemUsers::authenticate(&self.shared.users_db, &user_id, pwd) {
+            return self.login_failed(reason);
+        }
+        self.login(control, &user_id, pwd)
+    }
+
+    /// ACCT, creates the user that sent PASS if `code` is one of the invite codes
+    fn acct(&mut self, control: &mut Control, code: &str) -> Reply {
+        let (user_id, password) = match (&self.user_id, &self.pending_password) {
+            (Some(user_id), Some(password)) if !self.loged => (user_id.clone(), password.clone()),
+            _ => {
+                return Reply::new(
+                    ResponseCode::bad_sequence_of_commands(),
+                    "Bad sequence of commands.",
+                )
+            }
+        };
+        // The client has to send PASS again to try another code
+        control.after = Some(Box::new(|ctx| {
+            ctx.pending_password = None;
+        }));
+        let accepted = {
+            let config = self.shared.config.lock().unwrap();
+            config.registration.policy == RegistrationPolicy::Invite
+                && registration::is_invite_code(&config.registration, code)
+        };
+        if !accepted {
+            return self.login_failed("wrong invite code");
+        }
+        let hash = password::hash(&password);
+        let created = self
+            .shared
+            .users_db
+            .lock()
+            .unwrap()
+            .create_user_hashed(&user_id, hash);
+        if created.is_err() {
+            return self.login_failed("user can't be created");
+        }
+        info!(
+            "[HANDLE_READ] {} - User {} registered with an invite code",
+            self.connection_token.0, user_id
+        );
+        self.login(control, &user_id, &password)
+    }
+
+    /// Records a login that failed in the audit log, the events and the metrics
+    fn audit_failure(&self, reason: &'static str) {
+        let user_id = self.user_id.as_deref().unwrap_or("");
+        self.shared.users_db.lock().unwrap().audit(
+            AuthEvent::Failure(reason),
+            user_id,
+            self.peer.map(|peer| peer.ip()),
+        );
+        events::emit(&Event::Login { success: false, reason: Some(reason) });
+        metrics::login(false);
+    }
+
+    /// Answer to a login that failed, the client only gets to know that it failed
+    fn login_failed(&self, reason: &'static str) -> Reply {
+        self.audit_failure(reason);
+        Reply::new(ResponseCode::unauthorized(), "Not logged in.")
+    }
+
+    /// Starts the session of the user once its password has been checked
+    fn login(&mut self, control: &mut Control, user_id: &str, pwd: &str) -> Reply {
+        let user = match self.shared.users_db.lock().unwrap().get_user_clone(user_id) {
+            Some(user) => user,
+            None => return self.login_failed("no user"),
+        };
+        if let Err(err) = self.shared.storage.create_user_root(&user) {
+            warn!(
+                "[HANDLE_READ] {} - The files of user {} can't be opened: {}",
+                self.connection_token.0, user_id, err
+            );
+            return self.login_failed("storage unavailable");
+        }
+        if !self.add_user_session(&user) {
+            warn!(
+                "[HANDLE_READ] {} - Too many sessions for user {}",
+                self.connection_token.0, user_id
+            );
+            self.audit_failure("too many sessions");
+            return Reply::new(
+                ResponseCode::unauthorized(),
+                "Not logged in, too many sessions for this user.",
+            );
+        }
+        self.shared.users_db.lock().unwrap().audit(
+            AuthEvent::Success,
+            user_id,
+            self.peer.map(|peer| peer.ip()),
+        );
+        events::emit(&Event::Login { success: true, reason: None });
+        metrics::login(true);
+        hooks::trigger(HookEvent::Login, None, None, None);
+        control.after = Some(Box::new(move |ctx| {
+            ctx.loged = true;
+            ctx.pending_password = None;
+        }));
+        if self.shared.users_db.lock().unwrap().is_anonymous(user_id) {
+            info!("[HANDLE_READ] {} - Anonymous login", self.connection_token.0);
+            // The password of an anonymous session is its identification, for the xferlog
+            logger::set_session_guest(self.connection_token.0, pwd);
+            return Reply::new(
+                ResponseCode::login_success(),
+                "Guest login okay, access restrictions apply.",
+            );
+        }
+        if quota::has_quota(&user) {
+            let report = self
+                .shared
+                .quotas
+                .report(user_id, &user, self.shared.storage.as_ref());
+            if let Ok(report) = report {
+                return Reply::new(
+                    ResponseCode::login_success(),
+                    &format!("User logged in, proceed. Quota: {}.", report),
+                );
+            }
+        }
+        Reply::new(ResponseCode::login_success(), "User logged in, proceed.")
+    }
+
+    fn quit(&mut self, control: &mut Control) -> Reply {
+        let conn = self.connection.clone();
+        control.to_write.callback_after_sending = Some(Box::new(move || {
+            let connection = conn.lock().unwrap();
+            if let RequestType::CommandTransfer(stream, _, _, _) = &connection.request_type {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }));
+        Reply::new(
+            ResponseCode::closing_control_connection_success(),
+            "Service closing control connection.",
+        )
+    }
+
+    /// When this command is fired we should connect to the desired port by the user
+    fn port(&mut self, control: &mut Control, ip: Ipv4Addr, port: u16) -> Reply {
+        let connection = TcpStream::connect(format!("{}:{}", ip, port).parse().unwrap());
+
+        // Handle error where the connection is not opened by the client
+        let connection = match connection {
+            Ok(connection) => connection,
+            Err(_) => {
+                return Reply::new(
+                    ResponseCode::bad_sequence_of_commands(),
+                    "Bad sequence of commands.",
+                )
+            }
+        };
+
+        // fill data connection token (so later on the request context command keeps a reference
+        // to the request context of the file transfer)
+        *control.data_connection = Some(Token(control.next_id));
+
+        let request_ctx = Arc::new(Mutex::new(RequestContext::new(
+            RequestType::FileTransferActive(
+                connection,
+                FileTransferType::Buffer(BufferToWrite::default()),
+                self.connection_token,
+            ),
+        )));
+        self.connection_db
+            .lock()
+            .unwrap()
+            .insert(Token(control.next_id), request_ctx);
+        Reply::new(ResponseCode::command_okay(), "Command okay.")
+    }
+
+    fn pasv(&mut self, control: &mut Control) -> Reply {
+        let random_port = get_random_port();
+        let port = match random_port {
+            Some(port) => port,
+            None => return Reply::new(ResponseCode::all_ports_taken(), "All ports taken."),
+        };
+        // Create tcp listener and add it to the connections database
+        let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", port).parse().unwrap())
+            .expect("port to be init");
+        let mut db = self.connection_db.lock().unwrap();
+        // Smart multithread safe pointer where we got a mutex of a socket
+        let arc = Arc::new(Mutex::new(RequestContext::new(
+            RequestType::PassiveModePort(tcp_listener, self.connection_token),
+        )));
+        db.insert(Token(control.next_id), arc.clone());
+        // Mark the listener as readable so we can read new connections
+        self.actions
+            .push((Token(control.next_id), arc, Interest::READABLE));
+        let (first_part, second_part) = get_ftp_port_pair(port);
+        Reply::new(
+            ResponseCode::passive_ok(),
+            &format!(
+                "Entering Passive Mode (0,0,0,0,{},{})",
+                first_part, second_part
+            ),
+        )
+    }
+
+    fn list(&mut self, control: &mut Control, path: &Path) -> Reply {
+        // This means that the user hasn't opened a port or connected
+        let data_connection = match *control.data_connection {
+            Some(data_connection) => data_connection,
+            None => {
+                return Reply::new(
+                    ResponseCode::bad_sequence_of_commands(),
+                    "Bad sequence of commands.",
+                )
+            }
+        };
+
+        // Get the data transfer connection
+        let connection = self
+            .connection_db
+            .lock()
+            .unwrap()
+            .get(&data_connection)
+            .cloned();
+        let connection = match connection {
+            Some(connection) => connection,
+            // Inform the user that we couldn't find the data connection
+            None => {
+                return Reply::new(
+                    ResponseCode::cant_open_data_connection(),
+                    "Can't open data connection.",
+                )
+            }
+        };
+        let path = self.resolve(path);
+        let entries = match self.storage().and_then(|storage| storage.list(&path)) {
+            Ok(entries) => entries,
+            Err(_) => {
+                return Reply::new(
+                    ResponseCode::file_unavailable(),
+                    "Requested action not taken. File unavailable, no access.",
+                )
+            }
+        };
+        let list = self.list_lines(&path, entries);
+        let actions = control.actions.clone();
+        let waker = control.waker.clone();
+
+        // Create a callback for when the transition command is sent, it captures everything it needs
+        let callback = move || {
+            // Lock the request context
+            let mut connection_m = connection.lock().unwrap();
+
+            // Remember that this is the data connection,
+            // fill the data to write
+            match &mut connection_m.request_type {
+                RequestType::FileTransferPassive(_, ftt, _)
+                | RequestType::FileTransferActive(_, ftt, _) => {
+                    *ftt = FileTransferType::Buffer(BufferToWrite::new(list));
+                }
+                _ => unimplemented!(),
+            }
+            drop(connection_m);
+
+            // Now that we are free of the connection mutex, it's safe
+            // to add to the actions array
+            actions.lock().unwrap().push((
+                data_connection,
+                connection.clone(),
+                Interest::WRITABLE,
+            ));
+
+            // wake the Poll
+            let _ = waker.wake();
+        };
+        control.to_write.callback_after_sending = Some(Box::new(callback));
+
+        // All okay, transition
+        Reply::new(
+            ResponseCode::file_status_okay(),
+            "File status okay; about to open data connection.",
+        )
+    }
+
+    fn retr(&mut self, control: &mut Control, path: &Path) -> Reply {
+        let not_found = Reply::new(
+            ResponseCode::file_unavailable(),
+            "Requested action not taken. File unavailable, file not found.",
+        );
+        if control.data_connection.is_none() {
+            return Reply::new(
+                ResponseCode::bad_sequence_of_commands(),
+                "Bad sequence of commands.",
+            );
+        }
+        let path = self.resolve(path);
+        let file = match self.storage().and_then(|storage| storage.open_read(&path)) {
+            Ok(file) => file,
+            Err(_) => return not_found,
+        };
+        let token_data_conn = control.data_connection.take().unwrap();
+        let data_transfer_conn = self
+            .connection_db
+            .lock()
+            .unwrap()
+            .get(&token_data_conn)
+            .cloned();
+        let data_transfer_conn = match data_transfer_conn {
+            Some(data_transfer_conn) => data_transfer_conn,
+            None => {
+                return Reply::new(
+                    ResponseCode::file_unavailable(),
+                    "Requested action not taken. File unavailable, no access.",
+                )
+            }
+        };
+        let mut data_transfer_conn_mutex = data_transfer_conn.lock().unwrap();
+        if self
+            .handle_file_transfer_download(&mut data_transfer_conn_mutex, file, path)
+            .is_err()
+        {
+            return not_found;
+        }
+        drop(data_transfer_conn_mutex);
+        let actions = control.actions.clone();
+        control.to_write.callback_after_sending = Some(Box::new(move || {
+            actions
+                .lock()
+                .unwrap()
+                .push((token_data_conn, data_transfer_conn, Interest::WRITABLE))
+        }));
+        Reply::new(
+            ResponseCode::file_status_okay(),
+            "File status okay; about to open data connection.",
+        )
+    }
+
+    /// STOR, or APPE if `append` is set
+    fn stor(&mut self, control: &mut Control, path: &Path, append: bool) -> Reply {
+        let no_access = Reply::new(
+            ResponseCode::file_unavailable(),
+            "Requested action not taken. File unavailable, no access.",
+        );
+        if control.data_connection.is_none() {
+            return no_access;
+        }
+        let path = self.resolve(path);
+        if storage::file_name(&path).is_none() {
+            return no_access;
+        }
+        let (user_id, user) = match self.logged_user() {
+            Some(user) => user,
+            None => return no_access,
+        };
+        let tree = match self.shared.storage.user_root(&user) {
+            Ok(tree) => tree,
+            Err(_) => return no_access,
+        };
+        let existing = tree
+            .stat(&path)
+            .ok()
+            .filter(|metadata| !metadata.is_dir)
+            .map(|metadata| metadata.size);
+        // The anonymous uploads can't replace the ones of others
+        if existing.is_some() && self.shared.users_db.lock().unwrap().is_anonymous(&user_id) {
+            return Reply::new(
+                ResponseCode::file_unavailable(),
+                "Requested action not taken. File exists.",
+            );
+        }
+        let charge = match self.shared.quotas.start_upload(
+            &user_id,
+            &user,
+            self.shared.storage.as_ref(),
+            &path,
+            existing,
+            append,
+        ) {
+            Ok(charge) => charge,
+            Err(reply) => return reply,
+        };
+        let file = match tree.open_write(&path, append) {
+            Ok(file) => file,
+            Err(_) => {
+                charge.cancel();
+                return no_access;
+            }
+        };
+        let token_data = control.data_connection.take().unwrap();
+        let conn = self.connection_db.lock().unwrap().get(&token_data).cloned();
+        let conn = match conn {
+            Some(conn) => conn,
+            None => return no_access,
+        };
+        let mut conn_lock = conn.lock().unwrap();
+        if self
+            .handle_file_transfer_upload(&mut conn_lock, file, path, charge)
+            .is_err()
+        {
+            return no_access;
+        }
+        drop(conn_lock);
+        let actions = control.actions.clone();
+        control.to_write.callback_after_sending = Some(Box::new(move || {
+            let mut actions = actions.lock().unwrap();
+            actions.push((token_data, conn, Interest::READABLE));
+        }));
+        Reply::new(
+            ResponseCode::file_status_okay(),
+            "File status okay; about to open data connection.",
+        )
+    }
+
+    fn pwd(&mut self) -> Reply {
+        Reply::new(ResponseCode::directory_action_okay(), &self.working_dir())
+    }
+
+    fn cwd(&mut self, dir: &Path) -> Reply {
+        let path = self.resolve(dir);
+        let is_dir = self
+            .storage()
+            .and_then(|storage| storage.stat(&path))
+            .map(|metadata| metadata.is_dir)
+            .unwrap_or(false);
+        if !is_dir {
+            return Reply::new(
+                ResponseCode::file_unavailable(),
+                "Requested action not taken. File unavailable, file not found.",
+            );
+        }
+        self.set_working_dir(&path);
+        Reply::new(
+            ResponseCode::file_action_okay(),
+            "Requested file action okay, completed.",
+        )
+    }
+
+    fn mkd(&mut self, path: &Path) -> Reply {
+        let path = self.resolve(path);
+        let child = match storage::file_name(&path) {
+            Some(child) => child,
+            None => {
+                return Reply::new(
+                    ResponseCode::file_unavailable(),
+                    "Requested action not taken. File unavailable, no access.",
+                )
+            }
+        };
+        if self.storage().and_then(|storage| storage.mkdir(&path)).is_err() {
+            return Reply::new(
+                ResponseCode::file_unavailable(),
+                "Requested action not taken. File unavailable, no access.",
+            );
+        }
+        hooks::trigger(HookEvent::Mkdir, Some(&path), None, None);
+        Reply::new(
+            ResponseCode::directory_action_okay(),
+            &format!("'{}' directory created.", child),
+        )
+    }
+
+    fn rmd(&mut self, directory: &Path) -> Reply {
+        let not_found = Reply::new(
+            ResponseCode::file_unavailable(),
+            "Requested action not taken. File unavailable, file not found.",
+        );
+        let path = self.resolve(directory);
+        let storage = match self.storage() {
+            Ok(storage) => storage,
+            Err(_) => return not_found,
+        };
+        // Only counted if the quota usage of the user is being kept up to date
+        let tracked = self
+            .logged_user()
+            .is_some_and(|(user_id, user)| self.shared.quotas.tracks(&user_id, &user, &path));
+        let freed = if tracked {
+            Usage::scan(storage.as_ref(), &path, &[]).ok()
+        } else {
+            None
+        };
+        if storage.remove_dir(&path).is_err() {
+            return not_found;
+        }
+        if let Some(freed) = freed {
+            self.release(&path, freed);
+        }
+        hooks::trigger(HookEvent::Delete, Some(&path), None, None);
+
+        // Check if the client is a bit dumbass and deleted its own directory
+        self.safe_change_dir_for_user(storage.as_ref());
+
+        Reply::new(
+            ResponseCode::file_action_okay(),
+            "Requested file action okay, completed.",
+        )
+    }
+
+    fn dele(&mut self, path: &Path) -> Reply {
+        let path = self.resolve(path);
+        let size = self.storage().and_then(|storage| {
+            let size = storage.stat(&path)?.size;
+            storage.remove_file(&path)?;
+            Ok(size)
+        });
+        let size = match size {
+            Ok(size) => size,
+            Err(_) => {
+                return Reply::new(
+                    ResponseCode::file_unavailable(),
+                    "Requested action not taken. File unavailable, file not found.",
+                )
+            }
+        };
+        self.release(&path, Usage { bytes: size, files: 1 });
+        hooks::trigger(HookEvent::Delete, Some(&path), None, None);
+        Reply::new(
+            ResponseCode::file_action_okay(),
+            "Requested file action okay, completed.",
+        )
+    }
+
+    fn rnfr(&mut self, control: &mut Control, from: &Path) -> Reply {
+        let path = self.resolve(from);
+        if self.storage().and_then(|storage| storage.stat(&path)).is_ok() {
+            *control.path_from = Some(path);
+            return Reply::new(
+                ResponseCode::file_action_pending(),
+                "Requested file action pending further information.",
+            );
+        }
+        Reply::new(
+            ResponseCode::file_unavailable(),
+            "File unavailable, file not found.",
+        )
+    }
+
+    fn rnto(&mut self, control: &mut Control, to: &Path) -> Reply {
+        let not_allowed = Reply::new(
+            ResponseCode::file_action_not_taken(),
+            "Requested action not taken. File name not allowed.",
+        );
+        let from = match control.path_from.take() {
+            Some(from) => from,
+            None => return not_allowed,
+        };
+        let to = self.resolve(to);
+        let storage = match (storage::file_name(&to), self.storage()) {
+            (Some(_), Ok(storage)) => storage,
+            _ => return not_allowed,
+        };
+        // Moving between the tree of the user and a shared directory changes whose quota it is
+        let user = self.logged_user();
+        let moved = match &user {
+            Some((user_id, user)) => match self.shared.quotas.start_rename(
+                user_id,
+                user,
+                self.shared.storage.as_ref(),
+                storage.as_ref(),
+                &from,
+                &to,
+            ) {
+                Ok(moved) => moved,
+                Err(reply) => return reply,
+            },
+            None => None,
+        };
+        if storage.rename(&from, &to).is_err() {
+            return not_allowed;
+        }
+        if let (Some((user_id, user)), Some(moved)) = (&user, moved) {
+            self.shared.quotas.renamed(user_id, user, &from, &to, moved);
+        }
+        hooks::trigger(HookEvent::Rename, Some(&from), Some(&to), None);
+        Reply::new(
+            ResponseCode::file_action_okay(),
+            "Requested file action okay, completed.",
+        )
+    }
+
+    /// `SITE <name> [argument]`, runs the registered `SITE` command
+    fn site(&mut self, argument: &str) -> Reply {
+        let mut parts = argument.trim().splitn(2, ' ');
+        let name = parts.next().unwrap_or_default();
+        let argument = parts.next().map(|argument| argument.trim());
+        let commands = self.shared.commands.clone();
+        if name.eq_ignore_ascii_case("HELP") {
+            let names = commands.site_names();
+            let message = if names.is_empty() {
+                "There are no SITE commands.".to_string()
+            } else {
+                format!("SITE commands: HELP {}.", names.join(" "))
+            };
+            return Reply::new(ResponseCode::help_message(), &message);
+        }
+        let handler = match commands.get_site(name) {
+            Some(handler) => handler,
+            None => {
+                return Reply::new(
+                    ResponseCode::parameter_not_implemented(),
+                    "Unknown SITE command, try SITE HELP.",
+                )
+            }
+        };
+        if handler.needs_login() && (self.user_id.is_none() || !self.loged) {
+            return Reply::new(ResponseCode::unauthorized(), "Unauthorized.");
+        }
+        handler.handle(&self.session(), argument)
+    }
+}
@@ -1,11 +1,18 @@
 use super::{
-    create_response, Action, BufferToWrite, HashMutex, RequestContextMutex, RequestType, Token,
+    create_response, Action, BufferToWrite, HashMutex, RequestContextMutex, RequestType,
+    SharedState, Token,
 };
-use super::{response::ResponseCode, FileTransferType};
+use super::throttle::{Direction, RateLimiter};
+use super::{admin, events, metrics, response::ResponseCode, FileTransferType};
+use log::{debug, info, warn};
 use mio::{net::TcpStream, Interest, Waker};
-use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Write};
+use std::time::Instant;
 use std::{io::Error, net::Shutdown};
 
+/// Maximum bytes that we try to send in a single call while serving a file
+const SEND_CHUNK: usize = 1024 * 1024;
+
 pub struct HandlerWrite {
     connection_token: Token,
 
@@ -14,6 +21,11 @@ pub struct HandlerWrite {
     pub actions: Vec<Action>,
 
     connection: RequestContextMutex,
+
+    shared: SharedState,
+
+    /// Bandwidth limits of the connection that we are writing to
+    rate_limiter: RateLimiter,
 }
 
 impl HandlerWrite {
@@ -21,12 +33,16 @@ impl HandlerWrite {
         connection_token: Token,
         connection_db: HashMutex<Token, RequestContextMutex>,
         connection: RequestContextMutex,
+        shared: SharedState,
+        rate_limiter: RateLimiter,
     ) -> Self {
         Self {
             connection_token,
             connection_db,
             actions: Vec::new(),
             connection,
+            shared,
+            rate_limiter,
         }
     }
 
@@ -56,24 +72,34 @@ impl HandlerWrite {
         waker: &Waker,
     ) -> Result<Option<Box<dyn FnOnce() + Send>>, Error> {
         match request_type {
-            RequestType::Closed(stream) => {
-                let _ = stream.write("Bye...".as_bytes());
+            RequestType::Closed(stream, reply) => {
+                let _ = stream.write(reply);
                 stream.shutdown(Shutdown::Both)?;
             }
 
             RequestType::CommandTransfer(stream, to_write, _t, _path_from) => {
                 let maybe_error = stream.flush();
                 if let Err(err) = maybe_error {
-                    print_stdout!("[HANDLE_WRITE] CMD Error flushing the stream: {}", err);
+                    debug!("[HANDLE_WRITE] CMD Error flushing the stream: {}", err);
+                }
+                if to_write.offset == 0 && events::reply_code(&to_write.buffer) != 0 {
+                    if let Some(notice) = to_write.notice.take() {
+                        to_write.buffer = admin::add_notice(&to_write.buffer, &notice);
+                    }
                 }
                 let written = stream.write(&to_write.buffer[to_write.offset..]);
                 if let Ok(written) = written {
-                    print_stdout!("[HANDLE_WRITE] CMD Writing {} bytes", written);
+                    debug!("[HANDLE_WRITE] CMD Writing {} bytes", written);
                     if written + to_write.offset >= to_write.buffer.len() {
-                        print_stdout!(
+                        debug!(
                             "[HANDLE_WRITE] - {} - Going back to readable...",
                             self.connection_token.0
                         );
+                        if let Some(command) = to_write.command.take() {
+                            let code = events::reply_code(&to_write.buffer);
+                            command.emit(code);
+                            metrics::command(&command.command, code);
+                        }
                         to_write.buffer.clear();
                         to_write.offset = 0;
                         self.keep_interest(waker, Interest::READABLE)?;
@@ -88,13 +114,13 @@ impl HandlerWrite {
                     }
                 } else if let Err(err) = written {
                     if err.kind() == ErrorKind::WouldBlock {
-                        print_stdout!(
+                        debug!(
                             "[HANDLE_WRITE] - {} - Got would block error, keep writing",
                             self.connection_token.0
                         );
                         self.keep_interest(waker, Interest::WRITABLE)?;
                     } else {
-                        print_stdout!(
+                        warn!(
                             "[HANDLE_WRITE] - {} - Error writing to socket, closing connection. Error: {}",
                             self.connection_token.0,
                             err
@@ -150,34 +176,36 @@ impl HandlerWrite {
                 self.write_buffer_file_transfer(stream, to_write, waker, cmd_connection_token)
             }
 
-            FileTransferType::FileDownload(file) => {
-                let mut buf = [0; 1024];
+            FileTransferType::FileDownload(download, transfer) => {
                 loop {
-                    let read = file.read(&mut buf);
-                    if read.is_err() {
-                        break;
-                    }
-                    let read = read.unwrap();
-                    if read == 0 {
-                        break;
-                    }
-                    let err = stream.write(&buf[0..read]);
-                    if let Err(err) = &err {
-                        if err.kind() == ErrorKind::WouldBlock {
-                            let err_seek = file.seek(SeekFrom::Current(-(read as i64)));
-                            if err_seek.is_err() {
-                                print_stdout!(
-                                    "[ERROR SEEK] Unknown error with seek :( {:?}",
-                                    err_seek
-                                );
-                                let _ = self.close_connection(stream);
-                                self.answer_command(
-                                    cmd_connection_token,
-                                    "Unknown error with file transfer",
-                                );
-                                return Ok(());
-                            }
-                            print_stdout!(
+                    let allowed = match self.rate_limiter.allowance(SEND_CHUNK) {
+                        Ok(allowed) => allowed,
+                        Err(wait) => {
+                            debug!(
+                                "[HANDLE_FILE_TRANSFER] {} - Throttled, writing again in {:?}",
+                                self.connection_token.0,
+                                wait
+                            );
+                            self.shared.delayed_actions.lock().unwrap().push((
+                                Instant::now() + wait,
+                                (
+                                    self.connection_token,
+                                    self.connection.clone(),
+                                    Interest::WRITABLE,
+                                ),
+                            ));
+                            return Ok(());
+                        }
+                    };
+                    match download.send(stream, allowed) {
+                        Ok(0) => break,
+                        Ok(sent) => {
+                            self.rate_limiter.consume(sent);
+                            transfer.bytes += sent as u64;
+                            metrics::bytes(Direction::Download, sent as u64);
+                        }
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            debug!(
                                 "[HANDLE_FILE_TRANSFER] {} - Is would block, let's write again",
                                 self.connection_token.0
                             );
@@ -187,26 +215,28 @@ impl HandlerWrite {
                                 Interest::WRITABLE,
                             ));
                             return Ok(());
-                        } else {
-                            print_stdout!(
+                        }
+                        Err(err) => {
+                            warn!(
                                 "[HANDLE_FILE_TRANSFER] Error transfering file {:?}",
                                 err
                             );
+                            transfer.finish(false);
                             let _ = self.close_connection(stream);
                             self.answer_command(
                                 cmd_connection_token,
                                 "Error with file transfer connection",
                             );
+                            return Ok(());
                         }
-                    } else {
-                        let read_end = err.unwrap();
-                        assert!(read_end == read);
                     }
                 }
-                print_stdout!(
-                    "[HANDLE_FILE_TRANSFER] {} - Closing connection file transfer",
-                    self.connection_token.0
+                info!(
+                    "[HANDLE_FILE_TRANSFER] {} - Closing connection file transfer, {} bytes sent",
+                    self.connection_token.0,
+                    download.sent()
                 );
+                transfer.finish(true);
                 let _ = self.close_connection(stream);
                 self.answer_command(
                     cmd_connection_token,
@@ -228,7 +258,8 @@ impl HandlerWrite {
     ) -> Result<(), Error> {
         let written = stream.write(&to_write.buffer[to_write.offset..]);
         if let Ok(written) = written {
-            print_stdout!(
+            metrics::bytes(Direction::Download, written as u64);
+            debug!(
                 "[WRITE_BUFFER_FILE_TRANSFER] {} - {} bytes written",
                 self.connection_token.0,
                 written
@@ -244,7 +275,7 @@ impl HandlerWrite {
                         &mut command_connection_mutex.request_type
                     {
                         t.take();
-                        print_stdout!(
+                        debug!(
                             "[WRITE_BUFFER_FILE_TRANSFER] {} - Succesfully sending to the client, sending close data connection for token: {:?}", 
                             self.connection_token.0,
                             cmd_connection_token
@@ -255,7 +286,7 @@ impl HandlerWrite {
                         );
                         buffer_to_write.offset = 0;
                     } else {
-                        print_stdout!("[WRITE_BUFFER_FILE_TRANSFER] {} - Unexpected request type for command transfer", self.connection_token.0);
+                        debug!("[WRITE_BUFFER_FILE_TRANSFER] {} - Unexpected request type for command transfer", self.connection_token.0);
                     }
                     drop(command_connection_mutex);
                     self.actions.push((
@@ -264,7 +295,7 @@ impl HandlerWrite {
                         Interest::WRITABLE,
                     ));
                 } else {
-                    print_stdout!(
+                    debug!(
                         "[WRITE_BUFFER_FILE_TRANSFER] {} - Not found connection in DB",
                         self.connection_token.0
                     );
@@ -273,19 +304,19 @@ impl HandlerWrite {
             }
             to_write.offset += written;
             self.keep_interest(waker, Interest::WRITABLE)?;
-            print_stdout!(
+            debug!(
                 "[WRITE_BUFFER_FILE_TRANSFER] {} - Keep writing...",
                 self.connection_token.0
             );
         } else if let Err(err) = written {
             if err.kind() == ErrorKind::WouldBlock {
-                print_stdout!(
+                debug!(
                     "[WRITE_BUFFER_FILE_TRANSFER] {} - Would block error, keep writing",
                     self.connection_token.0
                 );
                 self.keep_interest(waker, Interest::WRITABLE)?;
             } else {
-                print_stdout!(
+                warn!(
                     "[WRITE_BUFFER_FILE_TRANSFER] {} - Closing connection because {}",
                     self.connection_token.0,
                     err
@@ -0,0 +1,306 @@
+//! Hooks that let other programs react to what happens in the sessions.
+//!
+//! Every hook of the `hooks` key of the configuration says on which events it runs
+//! and either a `command` or a `url`:
+//!
+//! * `command` is executed without arguments and gets the event in environment variables:
+//!   `FTP_EVENT`, `FTP_TIME`, `FTP_SESSION`, `FTP_PEER`, `FTP_USER`, `FTP_PATH`,
+//!   `FTP_TO` (new path of a rename) and `FTP_BYTES` (size of a transfer).
+//!   The variables of the fields that the event doesn't have aren't set.
+//! * `url` gets a `POST` with the same fields as a JSON object, only `http://` is supported.
+//!
+//! The hooks run one after another on a thread of their own, the events wait in a queue of
+//! `hook_queue_size` entries and they are dropped when it's full, so a slow hook can't stop
+//! the transfers. A hook that takes longer than its `timeout` is killed (or its request abandoned).
+
+use super::{config::HookConfig, logger};
+use chrono::SecondsFormat;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+static HOOKS: Mutex<Option<Hooks>> = Mutex::new(None);
+
+/// How often we check if a command has finished
+const WAIT_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// A STOR finished successfully
+    Upload,
+    /// A RETR finished successfully
+    Download,
+    /// A file or a directory was removed with DELE or RMD
+    Delete,
+    Rename,
+    Mkdir,
+    Login,
+    Logout,
+}
+
+/// What is sent to the hooks
+#[derive(Serialize, Debug, Clone)]
+struct Payload {
+    event: HookEvent,
+    time: String,
+    session: Option<usize>,
+    peer: Option<SocketAddr>,
+    user: Option<String>,
+    path: Option<String>,
+    to: Option<String>,
+    bytes: Option<u64>,
+}
+
+impl Payload {
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        let event = serde_json::to_value(self.event)
+            .ok()
+            .and_then(|event| event.as_str().map(|event| event.to_string()))
+            .unwrap_or_default();
+        let mut env = vec![("FTP_EVENT", event), ("FTP_TIME", self.time.clone())];
+        let optional = [
+            ("FTP_SESSION", self.session.map(|session| session.to_string())),
+            ("FTP_PEER", self.peer.map(|peer| peer.to_string())),
+            ("FTP_USER", self.user.clone()),
+            ("FTP_PATH", self.path.clone()),
+            ("FTP_TO", self.to.clone()),
+            ("FTP_BYTES", self.bytes.map(|bytes| bytes.to_string())),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                env.push((name, value));
+            }
+        }
+        env
+    }
+}
+
+struct Hooks {
+    hooks: Vec<HookConfig>,
+    queue: SyncSender<(Payload, Vec<HookConfig>)>,
+}
+
+/// Sets the hooks of the configuration. The old queue is drained by its thread
+/// with the hooks that were configured when the events happened
+pub fn configure(hooks: &[HookConfig], queue_size: usize) {
+    let new_hooks = if hooks.is_empty() {
+        None
+    } else {
+        let (queue, receiver) = sync_channel(queue_size.max(1));
+        thread::spawn(move || run_queue(receiver));
+        Some(Hooks {
+            hooks: hooks.to_vec(),
+            queue,
+        })
+    };
+    *HOOKS.lock().unwrap() = new_hooks;
+}
+
+/// Queues the hooks of `event` with the fields of the session that the current thread is serving.
+/// It never blocks, if the queue is full the event is dropped
+pub fn trigger(event: HookEvent, path: Option<&str>, to: Option<&str>, bytes: Option<u64>) {
+    let hooks = HOOKS.lock().unwrap();
+    let hooks = match hooks.as_ref() {
+        Some(hooks) => hooks,
+        None => return,
+    };
+    let matching: Vec<HookConfig> = hooks
+        .hooks
+        .iter()
+        .filter(|hook| hook.events.contains(&event))
+        .cloned()
+        .collect();
+    if matching.is_empty() {
+        return;
+    }
+    let session = logger::current_session();
+    let payload = Payload {
+        event,
+        time: chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        session: session.as_ref().map(|session| session.id),
+        peer: session.as_ref().and_then(|session| session.peer),
+        user: session.and_then(|session| session.user),
+        path: path.map(|path| path.to_string()),
+        to: to.map(|to| to.to_string()),
+        bytes,
+    };
+    match hooks.queue.try_send((payload, matching)) {
+        Ok(()) => {}
+        Err(TrySendError::Full((payload, _))) => {
+            warn!("[HOOKS] The queue is full, dropping {:?}", payload.event)
+        }
+        Err(TrySendError::Disconnected((payload, _))) => {
+            warn!("[HOOKS] The hooks thread is gone, dropping {:?}", payload.event)
+        }
+    }
+}
+
+fn run_queue(receiver: Receiver<(Payload, Vec<HookConfig>)>) {
+    for (payload, hooks) in receiver {
+        for hook in hooks {
+            let timeout = Duration::from_secs(hook.timeout);
+            if let Some(command) = &hook.command {
+                if let Err(err) = run_command(command, &payload, timeout) {
+                    warn!("[HOOKS] Error running {}: {}", command, err);
+                }
+            }
+            if let Some(url) = &hook.url {
+                if let Err(err) = post(url, &payload, timeout) {
+                    warn!("[HOOKS] Error posting to {}: {}", url, err);
+                }
+            }
+        }
+    }
+}
+
+fn run_command(command: &str, payload: &Payload, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let mut child = Command::new(command)
+        .envs(payload.environment())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                return Err(format!("it exited with {}", status).into());
+            }
+            debug!("[HOOKS] {} finished", command);
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err("it timed out and has been killed".into());
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+/// Splits an `http://host[:port]/path` URL into the address and the path
+fn parse_url(url: &str) -> Result<(&str, &str), &'static str> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or("only http:// URLs are supported")?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err("the URL doesn't have a host");
+    }
+    Ok((host, path))
+}
+
+fn post(url: &str, payload: &Payload, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let (host, path) = parse_url(url)?;
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or("the host can't be resolved")?;
+    let body = serde_json::to_vec(payload)?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(format!("it answered {}", status_line.trim()).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_url, post, HookEvent, Payload};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn upload() -> Payload {
+        Payload {
+            event: HookEvent::Upload,
+            time: "2021-01-01T00:00:00.000Z".to_string(),
+            session: Some(3),
+            peer: Some("127.0.0.1:2000".parse().unwrap()),
+            user: Some("user".to_string()),
+            path: Some("/file.txt".to_string()),
+            to: None,
+            bytes: Some(10),
+        }
+    }
+
+    #[test]
+    fn hook_environment() {
+        let env = upload().environment();
+        assert!(env.contains(&("FTP_EVENT", "upload".to_string())));
+        assert!(env.contains(&("FTP_PATH", "/file.txt".to_string())));
+        assert!(env.contains(&("FTP_BYTES", "10".to_string())));
+        assert!(!env.iter().any(|(name, _)| *name == "FTP_TO"));
+        assert_eq!(parse_url("http://host:8000"), Ok(("host:8000", "/")));
+        assert_eq!(parse_url("http://host/ingest"), Ok(("host", "/ingest")));
+        assert!(parse_url("https://host/ingest").is_err());
+    }
+
+    #[test]
+    fn posts_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ingest", listener.local_addr().unwrap());
+        let stub = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .into_inner()
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            (request_line, String::from_utf8(body).unwrap())
+        });
+        post(&url, &upload(), Duration::from_secs(5)).unwrap();
+        let (request_line, body) = stub.join().unwrap();
+        assert_eq!(request_line, "POST /ingest HTTP/1.1\r\n");
+        assert!(body.starts_with("{\"event\":\"upload\""));
+        assert!(body.contains("\"path\":\"/file.txt\""));
+    }
+}
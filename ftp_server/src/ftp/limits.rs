@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Counts the control connections opened by every client address.
+/// Only used from the poll thread, that's why it doesn't need a mutex
+#[derive(Default, Debug)]
+pub struct IpConnections {
+    connections: HashMap<IpAddr, usize>,
+}
+
+impl IpConnections {
+    /// Adds a connection for `ip` if it doesn't surpass `limit` (0 means unlimited).
+    /// Returns false when the connection should be rejected
+    pub fn try_add(&mut self, ip: IpAddr, limit: usize) -> bool {
+        let current = self.connections.entry(ip).or_insert(0);
+        if limit != 0 && *current >= limit {
+            return false;
+        }
+        *current += 1;
+        true
+    }
+
+    pub fn remove(&mut self, ip: IpAddr) {
+        if let Some(current) = self.connections.get_mut(&ip) {
+            *current -= 1;
+            if *current == 0 {
+                self.connections.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Counts the logged in sessions of every user, shared between the worker threads
+#[derive(Default, Debug, Clone)]
+pub struct UserSessions {
+    sessions: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl UserSessions {
+    /// Adds a session for `user_name` if it doesn't surpass `limit` (0 means unlimited).
+    /// Returns false when the login should be rejected
+    pub fn try_add(&self, user_name: &str, limit: usize) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let current = sessions.entry(user_name.to_string()).or_insert(0);
+        if limit != 0 && *current >= limit {
+            return false;
+        }
+        *current += 1;
+        true
+    }
+
+    pub fn remove(&self, user_name: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(current) = sessions.get_mut(user_name) {
+            *current -= 1;
+            if *current == 0 {
+                sessions.remove(user_name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IpConnections, UserSessions};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn ip_connections_limit() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let mut connections = IpConnections::default();
+        assert!(connections.try_add(ip, 2));
+        assert!(connections.try_add(ip, 2));
+        assert!(!connections.try_add(ip, 2));
+        assert!(connections.try_add(other, 2));
+        connections.remove(ip);
+        assert!(connections.try_add(ip, 2));
+        assert!(connections.try_add(ip, 0));
+    }
+
+    #[test]
+    fn user_sessions_limit() {
+        let sessions = UserSessions::default();
+        assert!(sessions.try_add("user", 1));
+        assert!(!sessions.try_add("user", 1));
+        assert!(sessions.try_add("user", 2));
+        sessions.remove("user");
+        sessions.remove("user");
+        assert!(sessions.try_add("user", 1));
+        assert!(sessions.try_add("other", 0));
+    }
+}
@@ -0,0 +1,433 @@
+//! Logger behind the `log` macros.
+//! Every message carries the session that the current thread is serving
+//! (ID, peer address and user), so one misbehaving session can log at debug level
+//! while everything else stays at the configured levels.
+
+use super::config::{LogConfig, LogOutput};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+static LOGGER: Logger = Logger {
+    filter: RwLock::new(None),
+    output: Mutex::new(None),
+    overrides: RwLock::new(Overrides {
+        stdout: None,
+        file: None,
+    }),
+    sessions: RwLock::new(BTreeMap::new()),
+    write_failed: AtomicBool::new(false),
+};
+
+thread_local! {
+    /// Session that the current thread is working for
+    static CURRENT_SESSION: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Fields of a control connection that are added to its messages
+#[derive(Debug, Clone, Default)]
+struct Session {
+    peer: Option<SocketAddr>,
+    user: Option<String>,
+    /// What an anonymous session sent as its password, usually an email
+    guest: Option<String>,
+    debug: bool,
+}
+
+/// Levels that decide which messages are written
+#[derive(Debug, Clone)]
+struct Filter {
+    level: LevelFilter,
+    modules: HashMap<String, LevelFilter>,
+    debug_users: Vec<String>,
+    debug_addresses: Vec<IpAddr>,
+}
+
+impl Filter {
+    fn new(config: &LogConfig) -> Self {
+        Self {
+            level: config.level,
+            modules: config.modules.clone(),
+            debug_users: config.debug_users.clone(),
+            debug_addresses: config.debug_addresses.clone(),
+        }
+    }
+
+    /// Level of the most specific module that contains `target`
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module.as_str()
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn enabled(&self, target: &str, level: Level, session: Option<&Session>) -> bool {
+        level <= self.level_for(target) || session.is_some_and(|session| session.debug)
+    }
+
+    fn is_debug_session(&self, session: &Session) -> bool {
+        session
+            .user
+            .as_ref()
+            .is_some_and(|user| self.debug_users.contains(user))
+            || session
+                .peer
+                .is_some_and(|peer| self.debug_addresses.contains(&peer.ip()))
+    }
+
+    /// Most verbose level that some message can have
+    fn max_level(&self) -> LevelFilter {
+        if !self.debug_users.is_empty() || !self.debug_addresses.is_empty() {
+            return LevelFilter::Trace;
+        }
+        self.modules
+            .values()
+            .copied()
+            .fold(self.level, Ord::max)
+    }
+}
+
+/// Settings passed in the command line, they take precedence over the configuration file
+struct Overrides {
+    stdout: Option<bool>,
+    file: Option<String>,
+}
+
+struct Output {
+    stdout: bool,
+    file: Option<LogFile>,
+}
+
+struct LogFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl LogFile {
+    fn open(path: &str, max_size: u64) -> io::Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_string(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    /// Moves the current file to `<path>.1` and starts a new one, so the logs never take
+    /// more than twice the maximum size
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        *self = Self::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.max_size != 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+struct Logger {
+    /// `None` until the logger is configured, meanwhile it uses the default configuration
+    filter: RwLock<Option<Filter>>,
+
+    output: Mutex<Option<Output>>,
+
+    overrides: RwLock<Overrides>,
+
+    /// Sessions that are open right now by their ID
+    sessions: RwLock<BTreeMap<usize, Session>>,
+
+    /// So a broken log file only complains once in stderr
+    write_failed: AtomicBool,
+}
+
+impl Logger {
+    fn current_session(&self) -> Option<Session> {
+        let id = CURRENT_SESSION.with(|current| current.get())?;
+        self.sessions.read().unwrap().get(&id).cloned()
+    }
+
+    fn with_filter<T>(&self, f: impl FnOnce(&Filter) -> T) -> T {
+        let filter = self.filter.read().unwrap();
+        match filter.as_ref() {
+            Some(filter) => f(filter),
+            None => f(&Filter::new(&LogConfig::default())),
+        }
+    }
+
+    fn format(record: &Record, session: Option<&Session>) -> String {
+        let mut line = format!(
+            "{} {:<5} [{}]",
+            chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+            record.level(),
+            record.target()
+        );
+        if let Some(id) = CURRENT_SESSION.with(|current| current.get()) {
+            line.push_str(&format!(" session={}", id));
+        }
+        if let Some(session) = session {
+            if let Some(peer) = session.peer {
+                line.push_str(&format!(" peer={}", peer));
+            }
+            if let Some(user) = &session.user {
+                line.push_str(&format!(" user={}", user));
+            }
+        }
+        line.push_str(&format!(" {}\n", record.args()));
+        line
+    }
+
+    fn report_failure(&self, err: io::Error) {
+        if !self.write_failed.swap(true, Ordering::Relaxed) {
+            eprintln!("[LOGGER] Error writing the log file, ignoring it: {}", err);
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let session = self.current_session();
+        self.with_filter(|filter| {
+            filter.enabled(metadata.target(), metadata.level(), session.as_ref())
+        })
+    }
+
+    fn log(&self, record: &Record) {
+        let session = self.current_session();
+        let enabled = self.with_filter(|filter| {
+            filter.enabled(record.target(), record.level(), session.as_ref())
+        });
+        if !enabled {
+            return;
+        }
+        let line = Self::format(record, session.as_ref());
+        let mut output = self.output.lock().unwrap();
+        let output = match output.as_mut() {
+            Some(output) => output,
+            None => {
+                let _ = io::stdout().write_all(line.as_bytes());
+                return;
+            }
+        };
+        if output.stdout {
+            let _ = io::stdout().write_all(line.as_bytes());
+        }
+        if let Some(file) = output.file.as_mut() {
+            if let Err(err) = file.write_line(&line) {
+                self.report_failure(err);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+        if let Some(Output {
+            file: Some(file), ..
+        }) = self.output.lock().unwrap().as_mut()
+        {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// Installs the logger, `stdout` and `file` override the configuration file when they are set.
+/// Until `configure` is called it logs at info level to stdout
+pub fn init(stdout: Option<bool>, file: Option<String>) {
+    *LOGGER.overrides.write().unwrap() = Overrides { stdout, file };
+    install();
+}
+
+fn install() {
+    // It fails when the logger is already installed, that's fine
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+/// Applies the logging settings, it's called again every time the configuration is reloaded.
+/// If the log file can't be opened the previous output is kept
+pub fn configure(config: &LogConfig) -> io::Result<()> {
+    install();
+    let overrides = LOGGER.overrides.read().unwrap();
+    let (mut stdout, mut use_file) = match config.output {
+        LogOutput::Stdout => (true, false),
+        LogOutput::File => (false, true),
+        LogOutput::Both => (true, true),
+    };
+    let path = match &overrides.file {
+        Some(path) => {
+            use_file = true;
+            path.as_str()
+        }
+        None => config.file.as_str(),
+    };
+    if let Some(overridden) = overrides.stdout {
+        stdout = overridden;
+    }
+    let mut output = LOGGER.output.lock().unwrap();
+    let same_file = output
+        .as_ref()
+        .and_then(|output| output.file.as_ref())
+        .is_some_and(|file| file.path == path);
+    let file = if !use_file {
+        None
+    } else if same_file {
+        let mut file = output.take().unwrap().file.unwrap();
+        file.max_size = config.max_file_size;
+        Some(file)
+    } else {
+        Some(LogFile::open(path, config.max_file_size)?)
+    };
+    *output = Some(Output { stdout, file });
+    drop(output);
+    LOGGER.write_failed.store(false, Ordering::Relaxed);
+
+    let filter = Filter::new(config);
+    for session in LOGGER.sessions.write().unwrap().values_mut() {
+        session.debug = filter.is_debug_session(session);
+    }
+    log::set_max_level(filter.max_level());
+    *LOGGER.filter.write().unwrap() = Some(filter);
+    Ok(())
+}
+
+/// Marks the current thread as working for the session `id` until the guard is dropped
+pub fn enter_session(id: usize) -> SessionGuard {
+    let previous = CURRENT_SESSION.with(|current| current.replace(Some(id)));
+    SessionGuard { previous }
+}
+
+pub struct SessionGuard {
+    previous: Option<usize>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        CURRENT_SESSION.with(|current| current.set(self.previous));
+    }
+}
+
+/// Starts tracking the fields of a new control connection
+pub fn open_session(id: usize, peer: Option<SocketAddr>) {
+    let mut session = Session {
+        peer,
+        ..Session::default()
+    };
+    session.debug = LOGGER.with_filter(|filter| filter.is_debug_session(&session));
+    LOGGER.sessions.write().unwrap().insert(id, session);
+}
+
+/// Sets the user of the session, it's called as soon as the client sends USER
+pub fn set_session_user(id: usize, user: Option<&str>) {
+    let mut sessions = LOGGER.sessions.write().unwrap();
+    if let Some(session) = sessions.get_mut(&id) {
+        session.user = user.map(|user| user.to_string());
+        session.guest = None;
+        session.debug = LOGGER.with_filter(|filter| filter.is_debug_session(session));
+    }
+}
+
+/// Marks the session as an anonymous one that identified itself with `ident`
+pub fn set_session_guest(id: usize, ident: &str) {
+    if let Some(session) = LOGGER.sessions.write().unwrap().get_mut(&id) {
+        session.guest = Some(ident.to_string());
+    }
+}
+
+pub fn close_session(id: usize) {
+    LOGGER.sessions.write().unwrap().remove(&id);
+}
+
+/// Fields of the session that the current thread is working for
+pub struct SessionFields {
+    pub id: usize,
+    pub peer: Option<SocketAddr>,
+    pub user: Option<String>,
+    /// The identification of an anonymous session
+    pub guest: Option<String>,
+}
+
+pub fn current_session() -> Option<SessionFields> {
+    let id = CURRENT_SESSION.with(|current| current.get())?;
+    let session = LOGGER.current_session();
+    Some(SessionFields {
+        id,
+        peer: session.as_ref().and_then(|session| session.peer),
+        user: session.as_ref().and_then(|session| session.user.clone()),
+        guest: session.and_then(|session| session.guest),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, Session};
+    use crate::ftp::config::LogConfig;
+    use log::{Level, LevelFilter};
+
+    #[test]
+    fn module_levels() {
+        let mut config = LogConfig::default();
+        config
+            .modules
+            .insert("ftp_server::ftp".to_string(), LevelFilter::Warn);
+        config.modules.insert(
+            "ftp_server::ftp::handler_read".to_string(),
+            LevelFilter::Trace,
+        );
+        let filter = Filter::new(&config);
+        assert!(filter.enabled("ftp_server::tcp", Level::Info, None));
+        assert!(!filter.enabled("ftp_server::tcp", Level::Debug, None));
+        assert!(!filter.enabled("ftp_server::ftp::handler_write", Level::Info, None));
+        assert!(filter.enabled("ftp_server::ftp::handler_read", Level::Trace, None));
+        assert!(!filter.enabled("ftp_server::ftpx", Level::Debug, None));
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn debug_sessions() {
+        let mut config = LogConfig::default();
+        config.debug_users.push("broken".to_string());
+        config.debug_addresses.push("10.0.0.1".parse().unwrap());
+        let filter = Filter::new(&config);
+        let mut session = Session {
+            peer: Some("127.0.0.1:2000".parse().unwrap()),
+            ..Session::default()
+        };
+        assert!(!filter.is_debug_session(&session));
+        session.user = Some("broken".to_string());
+        assert!(filter.is_debug_session(&session));
+        session.user = None;
+        session.peer = Some("10.0.0.1:2000".parse().unwrap());
+        assert!(filter.is_debug_session(&session));
+        session.debug = true;
+        assert!(filter.enabled("ftp_server::ftp", Level::Trace, Some(&session)));
+    }
+}
@@ -0,0 +1,308 @@
+//! Counters of the server exposed in the Prometheus text format on `GET /metrics`
+
+use super::throttle::Direction;
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Upper bounds in seconds of the transfer duration buckets
+const DURATION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Commands that get their own label, anything else is counted as `OTHER`
+/// so clients can't create new series sending garbage
+const KNOWN_COMMANDS: [&str; 16] = [
+    "USER", "PASS", "ACCT", "PORT", "PASV", "LIST", "RETR", "STOR", "PWD", "CWD", "MKD", "RMD",
+    "DELE", "RNFR", "RNTO", "QUIT",
+];
+
+static METRICS: Metrics = Metrics {
+    gauges: [
+        AtomicI64::new(0),
+        AtomicI64::new(0),
+        AtomicI64::new(0),
+        AtomicI64::new(0),
+    ],
+    logins_ok: AtomicU64::new(0),
+    logins_failed: AtomicU64::new(0),
+    bytes_in: AtomicU64::new(0),
+    bytes_out: AtomicU64::new(0),
+    commands: Mutex::new(BTreeMap::new()),
+    upload_durations: Mutex::new(Histogram::new()),
+    download_durations: Mutex::new(Histogram::new()),
+};
+
+/// Things that are counted while they are alive
+#[derive(Debug, Clone, Copy)]
+pub enum Gauge {
+    ControlConnections = 0,
+    DataConnections = 1,
+    PassivePorts = 2,
+    WorkerTasks = 3,
+}
+
+impl Gauge {
+    fn name(self) -> (&'static str, &'static str) {
+        match self {
+            Gauge::ControlConnections => (
+                "ftp_control_connections",
+                "Control connections that are open",
+            ),
+            Gauge::DataConnections => ("ftp_data_connections", "Data connections that are open"),
+            Gauge::PassivePorts => (
+                "ftp_passive_ports",
+                "Ports opened with PASV waiting for the client",
+            ),
+            Gauge::WorkerTasks => (
+                "ftp_worker_tasks",
+                "Reads and writes that are being handled by a worker thread",
+            ),
+        }
+    }
+}
+
+/// Increments the gauge until it's dropped
+#[derive(Debug)]
+pub struct GaugeGuard(Gauge);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        METRICS.gauges[self.0 as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn track(gauge: Gauge) -> GaugeGuard {
+    METRICS.gauges[gauge as usize].fetch_add(1, Ordering::Relaxed);
+    GaugeGuard(gauge)
+}
+
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [0; DURATION_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bucket, bound) in self.buckets.iter().zip(DURATION_BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, bucket
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+struct Metrics {
+    gauges: [AtomicI64; 4],
+    logins_ok: AtomicU64,
+    logins_failed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    commands: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    upload_durations: Mutex<Histogram>,
+    download_durations: Mutex<Histogram>,
+}
+
+pub fn login(success: bool) {
+    let counter = if success {
+        &METRICS.logins_ok
+    } else {
+        &METRICS.logins_failed
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a command by its verb and the code of its reply
+pub fn command(verb: &str, code: u16) {
+    let verb = KNOWN_COMMANDS
+        .iter()
+        .find(|known| **known == verb)
+        .copied()
+        .unwrap_or("OTHER");
+    *METRICS
+        .commands
+        .lock()
+        .unwrap()
+        .entry((verb, code))
+        .or_insert(0) += 1;
+}
+
+/// Bytes moved through data connections, uploads go in and downloads go out
+pub fn bytes(direction: Direction, bytes: u64) {
+    let counter = match direction {
+        Direction::Upload => &METRICS.bytes_in,
+        Direction::Download => &METRICS.bytes_out,
+    };
+    counter.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn transfer(direction: Direction, duration: Duration) {
+    let histogram = match direction {
+        Direction::Upload => &METRICS.upload_durations,
+        Direction::Download => &METRICS.download_durations,
+    };
+    histogram.lock().unwrap().observe(duration.as_secs_f64());
+}
+
+/// Writes every metric in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    for gauge in [
+        Gauge::ControlConnections,
+        Gauge::DataConnections,
+        Gauge::PassivePorts,
+        Gauge::WorkerTasks,
+    ] {
+        let (name, help) = gauge.name();
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let value = METRICS.gauges[gauge as usize].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+
+    let _ = writeln!(out, "# HELP ftp_logins_total Login attempts by result");
+    let _ = writeln!(out, "# TYPE ftp_logins_total counter");
+    for (result, counter) in [("ok", &METRICS.logins_ok), ("failed", &METRICS.logins_failed)] {
+        let value = counter.load(Ordering::Relaxed);
+        let _ = writeln!(out, "ftp_logins_total{{result=\"{}\"}} {}", result, value);
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP ftp_commands_total Commands received by verb and reply code"
+    );
+    let _ = writeln!(out, "# TYPE ftp_commands_total counter");
+    for ((verb, code), value) in METRICS.commands.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "ftp_commands_total{{command=\"{}\",code=\"{}\"}} {}",
+            verb, code, value
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP ftp_transfer_bytes_total Bytes moved through data connections"
+    );
+    let _ = writeln!(out, "# TYPE ftp_transfer_bytes_total counter");
+    for (direction, counter) in [("in", &METRICS.bytes_in), ("out", &METRICS.bytes_out)] {
+        let value = counter.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "ftp_transfer_bytes_total{{direction=\"{}\"}} {}",
+            direction, value
+        );
+    }
+
+    let name = "ftp_transfer_duration_seconds";
+    let _ = writeln!(out, "# HELP {} Duration of RETR and STOR transfers", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    METRICS
+        .upload_durations
+        .lock()
+        .unwrap()
+        .render(&mut out, name, "direction=\"upload\"");
+    METRICS
+        .download_durations
+        .lock()
+        .unwrap()
+        .render(&mut out, name, "direction=\"download\"");
+    out
+}
+
+fn answer(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, we don't care about them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut stream = reader.into_inner();
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Binds the address of the metrics, before the sandbox so it can be a privileged port
+pub fn bind(address: &str) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(address)?;
+    info!("[METRICS] Serving metrics on http://{}/metrics", address);
+    Ok(listener)
+}
+
+/// Serves the metrics on the listener of `bind` from a thread of its own
+pub fn serve(listener: TcpListener) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(answer);
+            if let Err(err) = result {
+                warn!("[METRICS] Error answering a request: {}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::Histogram;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::new();
+        histogram.observe(0.3);
+        histogram.observe(20.0);
+        let mut out = String::new();
+        histogram.render(&mut out, "duration", "direction=\"upload\"");
+        assert!(out.contains("duration_bucket{direction=\"upload\",le=\"0.1\"} 0\n"));
+        assert!(out.contains("duration_bucket{direction=\"upload\",le=\"0.5\"} 1\n"));
+        assert!(out.contains("duration_bucket{direction=\"upload\",le=\"30\"} 2\n"));
+        assert!(out.contains("duration_bucket{direction=\"upload\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("duration_sum{direction=\"upload\"} 20.3\n"));
+        assert!(out.contains("duration_count{direction=\"upload\"} 2\n"));
+    }
+}
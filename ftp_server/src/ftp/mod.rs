@@ -1,26 +1,53 @@
 use std::{
-    collections::HashMap,
-    fs::{self, File},
+    collections::{HashMap, HashSet},
+    fs,
     io::Write,
     path::Path,
 };
 
+mod admin;
+mod anonymous;
 mod command;
-#[macro_use]
+pub mod commands;
 pub mod config;
+mod download;
+mod events;
 mod handler_read;
 mod handler_write;
-mod response;
+mod hooks;
+mod limits;
+pub mod logger;
+mod metrics;
+mod quota;
+mod registration;
+pub mod response;
+mod sandbox;
+pub mod storage;
+mod throttle;
+mod transfer;
+mod xferlog;
+use commands::{CommandHandler, Commands};
+use config::{ServerConfig, StorageConfig, CONFIG_PATH};
+use download::FileDownload;
+use events::CommandLine;
+use limits::{IpConnections, UserSessions};
+use metrics::{Gauge, GaugeGuard};
+use quota::{Charge, Quotas};
+use log::{debug, error, info, warn};
+use throttle::{RateLimiter, Throttle};
+use transfer::Transfer;
 use response::ResponseCode;
-use user_manage::SystemUsers;
+use storage::{FileWriter, Mounts, Storage};
+use user_manage::{SystemUsers, USER_PATH};
 
 // use handlers::write_buffer_file_transfer;
 use mio::net::{TcpListener, TcpStream};
 use mio::{event::Event, Interest, Poll, Token, Waker};
 use std::io::{Error, ErrorKind};
-use std::net::Shutdown;
+use std::net::{Shutdown, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::thread::spawn;
+use std::time::{Duration, Instant};
 
 use crate::tcp::TCPImplementation;
 
@@ -44,6 +71,12 @@ pub struct BufferToWrite {
     /// (For example starting a writable interest to the file transfer socket)
     /// Make sure that you use `.take()` for emptying the option
     callback_after_sending: Option<Box<dyn FnOnce() + Send>>,
+
+    /// Command that is being answered, it goes to the event log when the reply is sent
+    command: Option<CommandLine>,
+
+    /// Message of the administrator that goes before the next reply
+    notice: Option<String>,
 }
 
 impl BufferToWrite {
@@ -52,6 +85,8 @@ impl BufferToWrite {
             buffer: Vec::default(),
             offset: 0,
             callback_after_sending: None,
+            command: None,
+            notice: None,
         }
     }
 
@@ -60,6 +95,8 @@ impl BufferToWrite {
             buffer: vector,
             offset: 0,
             callback_after_sending: None,
+            command: None,
+            notice: None,
         }
     }
 
@@ -69,21 +106,39 @@ impl BufferToWrite {
     }
 }
 
+/// Replies 421 with `message` on the control connection and closes it after sending it.
+/// The caller still has to add the writable action
+fn close_with_reply(conn: &RequestContextMutex, to_write: &mut BufferToWrite, message: &str) {
+    to_write.reset(create_response(
+        ResponseCode::service_not_available(),
+        message,
+    ));
+    let closing = conn.clone();
+    to_write.callback_after_sending = Some(Box::new(move || {
+        let connection = closing.lock().unwrap();
+        if let RequestType::CommandTransfer(stream, _, _, _) = &connection.request_type {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }));
+}
+
 // #[derive(Debug)]
 pub enum FileTransferType {
     /// This kind of operation is when the server is saving a file from the client, Response is when there is a response, if there is none when closing, it assumes an error
-    FileUpload(File, Option<Vec<u8>>),
+    /// The charge counts the bytes in the quota of the user
+    FileUpload(Box<dyn FileWriter>, Option<Vec<u8>>, Transfer, Charge),
 
     /// This kind of operation is when the server is serving a file to the client
-    FileDownload(File),
+    FileDownload(FileDownload, Transfer),
 
     /// This kind of operation is when the server is just writing some data to the client
     Buffer(BufferToWrite),
 }
 
 pub enum RequestType {
-    /// This request_type is only when we are instantly closing the connection after accepting it
-    Closed(TcpStream),
+    /// This request_type is only when we are instantly closing the connection after accepting it,
+    /// the buffer is the reply that we send before closing
+    Closed(TcpStream, Vec<u8>),
 
     /// This requesst is a file transfer on passive mode.
 
@@ -112,24 +167,97 @@ pub struct RequestContext {
     user_id: Option<String>,
 
     loged: bool,
+
+    /// Password sent by a user that is registering, until it sends the invite code with ACCT
+    pending_password: Option<String>,
+
+    /// Working directory of the session, a virtual path
+    cwd: String,
+
+    /// Last time that the poll gave us an event for this connection, used for the timeouts
+    last_activity: Instant,
+
+    /// Address of the client, only set on control connections
+    peer: Option<SocketAddr>,
+
+    /// Bandwidth limits of the transfer, only set on data connections
+    rate_limiter: RateLimiter,
+
+    /// Counts the connection in the metrics while it's alive
+    _gauge: Option<GaugeGuard>,
 }
 
 impl RequestContext {
     fn new(request_type: RequestType) -> Self {
+        let gauge = match &request_type {
+            RequestType::Closed(_, _) => None,
+            RequestType::CommandTransfer(_, _, _, _) => Some(Gauge::ControlConnections),
+            RequestType::FileTransferActive(_, _, _) | RequestType::FileTransferPassive(_, _, _) => {
+                Some(Gauge::DataConnections)
+            }
+            RequestType::PassiveModePort(_, _) => Some(Gauge::PassivePorts),
+        };
         Self {
+            _gauge: gauge.map(metrics::track),
             request_type,
             user_id: None,
             loged: false,
+            pending_password: None,
+            cwd: "/".to_string(),
+            last_activity: Instant::now(),
+            peer: None,
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+}
+
+impl RequestContext {
+    /// Session that the connection belongs to, data connections belong to their control connection
+    fn session_id(&self, token: Token) -> usize {
+        match &self.request_type {
+            RequestType::FileTransferActive(_, _, cmd)
+            | RequestType::FileTransferPassive(_, _, cmd)
+            | RequestType::PassiveModePort(_, cmd) => cmd.0,
+            _ => token.0,
         }
     }
 }
 
 pub type RequestContextMutex = Arc<Mutex<RequestContext>>;
 
+/// State that the server shares with the handlers running on the worker threads
+#[derive(Clone)]
+pub struct SharedState {
+    users_db: Arc<Mutex<SystemUsers>>,
+
+    config: Arc<Mutex<ServerConfig>>,
+
+    user_sessions: UserSessions,
+
+    /// What the users have in their trees, for their quotas
+    quotas: Quotas,
+
+    throttle: Throttle,
+
+    delayed_actions: DelayedActionList,
+
+    /// Message that new connections get while maintenance mode is on
+    maintenance: Arc<Mutex<Option<String>>>,
+
+    /// Handlers of the commands, they can't change once the server is running
+    commands: Arc<Commands>,
+
+    /// Where the files of the users are
+    storage: Arc<Mounts>,
+}
+
 type Action = (Token, RequestContextMutex, Interest);
 
 type ActionList = Arc<Mutex<Vec<Action>>>;
 
+/// Actions that must wait until an instant to be added to the action list (e.g throttled transfers)
+type DelayedActionList = Arc<Mutex<Vec<(Instant, Action)>>>;
+
 type HashMutex<K, V> = Arc<Mutex<HashMap<K, V>>>;
 
 pub struct FTPServer {
@@ -139,55 +267,375 @@ pub struct FTPServer {
 
     current_id: usize,
 
-    // Maximum connections
-    max_connections: usize,
+    // Maximum connections passed by the command line, takes precedence over the config file
+    max_connections: Option<usize>,
 
     // Current connections
     current_connections: usize,
 
-    user_repository: Arc<Mutex<SystemUsers>>,
+    // Where the reloadable configuration lives
+    config_path: String,
+
+    // State shared with the handlers
+    shared: SharedState,
+
+    // Next time that we check the timeouts
+    next_tick: Instant,
+
+    // Control connections opened by every client address
+    connections_per_ip: IpConnections,
 }
 
 pub const ROOT: &'static str = "./root";
 
+/// How often we look for connections that timed out
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
 impl FTPServer {
     pub fn new() -> Self {
-        if !Path::new(ROOT).exists() {
+        Self::with_config(CONFIG_PATH, None)
+    }
+
+    pub fn with_connection_capacity(max_connections: usize) -> Self {
+        Self::with_config(CONFIG_PATH, Some(max_connections))
+    }
+
+    /// Creates the server reading the settings from `config_path`,
+    /// `max_connections` overrides the value of the file if it's set
+    pub fn with_config(config_path: &str, max_connections: Option<usize>) -> Self {
+        let config = ServerConfig::load(config_path).expect("error loading the configuration");
+        if matches!(config.storage, StorageConfig::Local { .. }) && !Path::new(ROOT).exists() {
             fs::create_dir(ROOT).expect("root dir hasn't been created");
         }
+        let storage = Mounts::from_config(&config.storage, &config.storages)
+            .expect("error opening the storage");
+        logger::configure(&config.log).expect("error opening the log file");
+        events::configure(&config.event_log).expect("error opening the event log");
+        xferlog::configure(&config.xferlog).expect("error opening the transfer log");
+        let users = SystemUsers::load_data(USER_PATH).expect("error loading the users");
+        let mut server = Self::with_mounts(config, users, storage);
+        server.max_connections = max_connections;
+        server.config_path = config_path.to_string();
+        server
+    }
+
+    /// Server of `users` that keeps their files in `storage` instead of the `chroot` directories,
+    /// it doesn't read any file (e.g for the tests). There is no configuration for `SIGHUP`
+    /// to reload, it only reloads the users
+    pub fn with_users(config: ServerConfig, users: SystemUsers, storage: Arc<dyn Storage>) -> Self {
+        let mut mounts = Mounts::from_config(&config.storage, &config.storages)
+            .expect("error opening the storage");
+        mounts.set_storage(storage);
+        Self::with_mounts(config, users, mounts)
+    }
+
+    fn with_mounts(config: ServerConfig, mut users: SystemUsers, storage: Mounts) -> Self {
+        anonymous::configure(&config.anonymous, &mut users, &storage);
+        let mut commands = Commands::with_builtins();
+        commands.register_site("QUOTA", Arc::new(quota::SiteQuota));
         Self {
             connections: Arc::new(Mutex::new(HashMap::new())),
             current_id: 0,
-            max_connections: 50,
+            max_connections: None,
             current_connections: 0,
             actions: Arc::new(Mutex::new(Vec::new())),
-            user_repository: Arc::new(Mutex::new(
-                SystemUsers::load_data("./etc/users.json").expect("didn't work"),
-            )),
+            config_path: String::new(),
+            shared: SharedState {
+                users_db: Arc::new(Mutex::new(users)),
+                config: Arc::new(Mutex::new(config)),
+                user_sessions: UserSessions::default(),
+                quotas: Quotas::default(),
+                throttle: Throttle::default(),
+                delayed_actions: Arc::new(Mutex::new(Vec::new())),
+                maintenance: Arc::new(Mutex::new(None)),
+                commands: Arc::new(commands),
+                storage: Arc::new(storage),
+            },
+            next_tick: Instant::now() + TICK_INTERVAL,
+            connections_per_ip: IpConnections::default(),
         }
     }
 
-    pub fn with_connection_capacity(max_connections: usize) -> Self {
-        if !Path::new(ROOT).exists() {
-            fs::create_dir(ROOT).expect("root dir hasn't been created");
+    /// Handles `verb` with `handler`, it replaces the built-in command if there is one
+    pub fn register_command(&mut self, verb: &str, handler: Arc<dyn CommandHandler>) {
+        Arc::make_mut(&mut self.shared.commands).register(verb, handler);
+    }
+
+    /// Adds the `SITE <name>` command
+    pub fn register_site_command(&mut self, name: &str, handler: Arc<dyn CommandHandler>) {
+        Arc::make_mut(&mut self.shared.commands).register_site(name, handler);
+    }
+
+    /// Keeps the files of the users in `storage` instead of their `chroot` directories
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        Arc::make_mut(&mut self.shared.storage).set_storage(storage);
+    }
+
+    /// Lets the `mounts` of the users use `storage` as `"storage": "<name>"`
+    pub fn add_storage(&mut self, name: &str, storage: Arc<dyn Storage>) {
+        Arc::make_mut(&mut self.shared.storage).add_storage(name, storage);
+    }
+
+    /// Applies the `sandbox` of the configuration (see `ftp::sandbox`), Landlock lets the server
+    /// write `etc`, `var` and the directories of the local disk that the users have now
+    fn sandbox(&self, config: &ServerConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let sandbox = &config.sandbox;
+        if !sandbox.user.is_empty() {
+            sandbox::drop_privileges(&sandbox.user)?;
         }
-        Self {
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            current_id: 0,
-            max_connections,
-            current_connections: 0,
-            actions: Arc::new(Mutex::new(Vec::new())),
-            user_repository: Arc::new(Mutex::new(
-                SystemUsers::load_data("./etc/users.json").expect("didn't work"),
-            )),
+        if sandbox.landlock {
+            let mut write_paths = vec!["./etc".to_string(), "./var".to_string()];
+            let is_local = |storage: &StorageConfig| matches!(storage, StorageConfig::Local { .. });
+            let local_root = is_local(&config.storage);
+            if local_root {
+                sandbox::prepare_dir(ROOT)?;
+                write_paths.push(ROOT.to_string());
+            }
+            let mut user_dirs = Vec::new();
+            let users = self.shared.users_db.lock().unwrap();
+            for (_, user) in users.users() {
+                let chroot = user.get_chroot();
+                if local_root && !Path::new(chroot).starts_with(ROOT) {
+                    user_dirs.push(chroot.clone());
+                }
+                for mount in user.get_mounts() {
+                    let on_disk = match &mount.storage {
+                        None => true,
+                        Some(name) => config.storages.get(name).is_some_and(is_local),
+                    };
+                    // They aren't created, the login of the user fails if they are missing
+                    if on_disk && Path::new(&mount.dir).is_dir() {
+                        user_dirs.push(mount.dir.clone());
+                    } else if on_disk {
+                        warn!("[SANDBOX] The mount directory {} doesn't exist", mount.dir);
+                    }
+                }
+            }
+            drop(users);
+            for dir in user_dirs {
+                sandbox::prepare_dir(&dir)?;
+                write_paths.push(dir);
+            }
+            write_paths.extend(sandbox.write_paths.iter().cloned());
+            let mut read_paths = sandbox.read_paths.clone();
+            if Path::new(&self.config_path).exists() {
+                read_paths.push(self.config_path.clone());
+            }
+            sandbox::landlock(&write_paths, &read_paths)?;
+        }
+        if sandbox.seccomp {
+            let hook_commands = config.hooks.iter().any(|hook| hook.command.is_some());
+            sandbox::seccomp(hook_commands)?;
         }
+        Ok(())
     }
 
-    fn add_connection(&mut self, token: Token, request_type: RequestType) {
-        self.connections.lock().unwrap().insert(
-            token,
-            Arc::new(Mutex::new(RequestContext::new(request_type))),
-        );
+    fn max_connections(&self) -> usize {
+        self.max_connections
+            .unwrap_or_else(|| self.shared.config.lock().unwrap().max_connections)
+    }
+
+    /// Usernames of the sessions that are currently logged in
+    fn logged_users(&self) -> Vec<String> {
+        // Clone the request contexts so we don't hold the database lock while locking each connection
+        let connections: Vec<RequestContextMutex> =
+            self.connections.lock().unwrap().values().cloned().collect();
+        connections
+            .iter()
+            .filter_map(|conn| {
+                let conn = conn.lock().unwrap();
+                if conn.loged {
+                    conn.user_id.clone()
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Sends a reply through the command connection, forgetting its data connection
+    fn reply_to_command(&self, cmd_token: Token, response: Vec<u8>, waker: &Arc<Waker>) {
+        let cmd_conn = self.connections.lock().unwrap().get(&cmd_token).cloned();
+        if let Some(cmd_conn) = cmd_conn {
+            let mut cmd = cmd_conn.lock().unwrap();
+            if let RequestType::CommandTransfer(_, to_write, data_connection, _) =
+                &mut cmd.request_type
+            {
+                data_connection.take();
+                to_write.reset(response);
+            }
+            drop(cmd);
+            self.actions
+                .lock()
+                .unwrap()
+                .push((cmd_token, cmd_conn, Interest::WRITABLE));
+            let _ = waker.wake();
+        }
+    }
+
+    /// Closes every connection that has been idle for longer than its configured timeout.
+    /// Connections that are being handled by a worker are skipped, they are obviously not idle
+    fn expire_connections(&mut self, poll: &Poll, waker: &Arc<Waker>) {
+        let now = Instant::now();
+        let config = self.shared.config.lock().unwrap().clone();
+        let control_timeout = ServerConfig::timeout(config.control_timeout);
+        let data_timeout = ServerConfig::timeout(config.data_timeout);
+        let passive_timeout = ServerConfig::timeout(config.passive_timeout);
+        let connections: Vec<(Token, RequestContextMutex)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(token, conn)| (*token, conn.clone()))
+            .collect();
+        // Command connections with an open data connection are waiting for the transfer,
+        // that one has its own timeout
+        let mut transferring = HashSet::new();
+        let mut expired = Vec::new();
+        for (token, conn) in connections.iter() {
+            let ctx = match conn.try_lock() {
+                Ok(ctx) => ctx,
+                Err(_) => continue,
+            };
+            let idle = now.saturating_duration_since(ctx.last_activity);
+            let timeout = match &ctx.request_type {
+                RequestType::CommandTransfer(_, _, _, _) | RequestType::Closed(_, _) => {
+                    control_timeout
+                }
+                RequestType::FileTransferActive(_, _, cmd)
+                | RequestType::FileTransferPassive(_, _, cmd) => {
+                    transferring.insert(*cmd);
+                    data_timeout
+                }
+                RequestType::PassiveModePort(_, _) => passive_timeout,
+            };
+            if let Some(timeout) = timeout {
+                if idle >= timeout {
+                    expired.push((*token, conn.clone()));
+                }
+            }
+        }
+        for (token, conn) in expired {
+            let mut guard = match conn.try_lock() {
+                Ok(guard) => guard,
+                Err(_) => continue,
+            };
+            let ctx = &mut *guard;
+            let _session = logger::enter_session(ctx.session_id(token));
+            match &mut ctx.request_type {
+                RequestType::CommandTransfer(_, to_write, _, _) => {
+                    if transferring.contains(&token) {
+                        continue;
+                    }
+                    info!("[TIMEOUT] - {} - Control connection timed out", token.0);
+                    // Don't fire the timeout again while we send the reply
+                    ctx.last_activity = now;
+                    close_with_reply(&conn, to_write, "Timeout, closing control connection.");
+                    drop(guard);
+                    self.actions
+                        .lock()
+                        .unwrap()
+                        .push((token, conn.clone(), Interest::WRITABLE));
+                    let _ = waker.wake();
+                }
+
+                RequestType::Closed(_, _) => {
+                    drop(guard);
+                    let _ = self.close_connection(poll, token, waker);
+                }
+
+                RequestType::FileTransferActive(_, ftt, cmd)
+                | RequestType::FileTransferPassive(_, ftt, cmd) => {
+                    info!("[TIMEOUT] - {} - Data connection stalled", token.0);
+                    let cmd = *cmd;
+                    let aborted = create_response(
+                        ResponseCode::transfer_aborted(),
+                        "Connection closed; transfer aborted.",
+                    );
+                    let response = match ftt {
+                        // The close path of the upload already answers the command connection
+                        FileTransferType::FileUpload(_, response, _, _) => {
+                            *response = Some(aborted);
+                            None
+                        }
+                        FileTransferType::FileDownload(_, transfer) => {
+                            transfer.finish(false);
+                            Some(aborted)
+                        }
+                        // Nothing was being transferred, just forget about the connection
+                        FileTransferType::Buffer(_) => None,
+                    };
+                    drop(guard);
+                    let _ = self.close_connection(poll, token, waker);
+                    match response {
+                        Some(response) => self.reply_to_command(cmd, response, waker),
+                        None => {
+                            let cmd_conn = self.connections.lock().unwrap().get(&cmd).cloned();
+                            if let Some(cmd_conn) = cmd_conn {
+                                if let RequestType::CommandTransfer(_, _, data_connection, _) =
+                                    &mut cmd_conn.lock().unwrap().request_type
+                                {
+                                    if *data_connection == Some(token) {
+                                        data_connection.take();
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+
+                RequestType::PassiveModePort(_, _) => {
+                    info!(
+                        "[TIMEOUT] - {} - Nobody connected to the passive port",
+                        token.0
+                    );
+                    drop(guard);
+                    let _ = self.close_connection(poll, token, waker);
+                }
+            }
+        }
+    }
+
+    /// Stops the transfers of a session that has been closed (e.g it was kicked).
+    /// Their streams are shut down so the poll closes them as usual
+    fn abort_transfers(&self, cmd_token: Token) {
+        let connections: Vec<RequestContextMutex> =
+            self.connections.lock().unwrap().values().cloned().collect();
+        for conn in connections {
+            let mut conn = conn.lock().unwrap();
+            if let RequestType::FileTransferActive(stream, ftt, cmd)
+            | RequestType::FileTransferPassive(stream, ftt, cmd) = &mut conn.request_type
+            {
+                if *cmd != cmd_token {
+                    continue;
+                }
+                match ftt {
+                    FileTransferType::FileDownload(_, transfer)
+                    | FileTransferType::FileUpload(_, _, transfer, _) => {
+                        transfer.finish(false);
+                    }
+                    FileTransferType::Buffer(_) => {}
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    fn add_connection(
+        &mut self,
+        token: Token,
+        request_type: RequestType,
+        peer: Option<SocketAddr>,
+    ) {
+        let mut request_context = RequestContext::new(request_type);
+        request_context.peer = peer;
+        if let RequestType::CommandTransfer(_, _, _, _) = &request_context.request_type {
+            logger::open_session(token.0, peer);
+        }
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(token, Arc::new(Mutex::new(request_context)));
     }
 
     fn deregister(&self, poll: &Poll, rc: &mut RequestContext) -> Result<(), Error> {
@@ -208,7 +656,7 @@ impl FTPServer {
                 poll.registry().deregister(port)?;
             }
 
-            RequestType::Closed(stream) => {
+            RequestType::Closed(stream, _) => {
                 poll.registry().deregister(stream)?;
             }
         }
@@ -217,7 +665,7 @@ impl FTPServer {
 
     fn shutdown(rc: &mut RequestContext) -> Result<(), Error> {
         match &mut rc.request_type {
-            RequestType::Closed(stream) => {
+            RequestType::Closed(stream, _) => {
                 let _ = stream.flush();
                 stream.shutdown(Shutdown::Both)?;
             }
@@ -257,6 +705,127 @@ impl TCPImplementation for FTPServer {
         self.current_id
     }
 
+    /// Reads again the configuration file and the users database,
+    /// if one of them can't be parsed we keep the old one
+    fn reload(&mut self) {
+        info!("[RELOAD] Reloading configuration and users");
+        match ServerConfig::load(&self.config_path) {
+            Ok(config) => {
+                if let Err(err) = logger::configure(&config.log) {
+                    warn!(
+                        "[RELOAD] Error opening the log file {}, keeping the old output: {}",
+                        config.log.file, err
+                    );
+                }
+                if let Err(err) = events::configure(&config.event_log) {
+                    warn!(
+                        "[RELOAD] Error opening the event log {}: {}",
+                        config.event_log, err
+                    );
+                }
+                if let Err(err) = xferlog::configure(&config.xferlog) {
+                    warn!(
+                        "[RELOAD] Error opening the transfer log {}: {}",
+                        config.xferlog, err
+                    );
+                }
+                hooks::configure(&config.hooks, config.hook_queue_size);
+                *self.shared.config.lock().unwrap() = config;
+            }
+            Err(err) => warn!(
+                "[RELOAD] Error reading {}, keeping the old configuration: {}",
+                self.config_path,
+                err
+            ),
+        }
+        let logged_users = self.logged_users();
+        let mut user_db = self.shared.users_db.lock().unwrap();
+        if let Err(err) = user_db.reload(&logged_users) {
+            warn!("[RELOAD] Error reading the users, keeping the old ones: {}", err);
+        }
+        let config = self.shared.config.lock().unwrap();
+        anonymous::configure(&config.anonymous, &mut user_db, self.shared.storage.as_ref());
+    }
+
+    fn next_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let next_delayed = self
+            .shared
+            .delayed_actions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(instant, _)| *instant)
+            .min();
+        let config = self.shared.config.lock().unwrap();
+        let next_tick = if config.control_timeout == 0
+            && config.data_timeout == 0
+            && config.passive_timeout == 0
+        {
+            None
+        } else {
+            Some(self.next_tick)
+        };
+        let next = match (next_delayed, next_tick) {
+            (Some(delayed), Some(tick)) => Some(delayed.min(tick)),
+            (delayed, tick) => delayed.or(tick),
+        };
+        next.map(|next| next.saturating_duration_since(now))
+    }
+
+    fn started(&mut self, waker: Arc<Waker>) {
+        let config = self.shared.config.lock().unwrap().clone();
+        // Bound before the sandbox, like the FTP port, so they can be privileged
+        let metrics = if config.metrics_address.is_empty() {
+            None
+        } else {
+            Some(metrics::bind(&config.metrics_address).expect("error serving the metrics"))
+        };
+        let admin_socket = if config.admin_socket.is_empty() {
+            None
+        } else {
+            Some(admin::bind(&config.admin_socket).expect("error opening the admin socket"))
+        };
+        self.sandbox(&config).expect("error applying the sandbox");
+        // The threads are started after the sandbox so they are restricted too
+        hooks::configure(&config.hooks, config.hook_queue_size);
+        if let Some(listener) = metrics {
+            metrics::serve(listener);
+        }
+        if let Some(listener) = admin_socket {
+            let admin = admin::Admin {
+                connections: self.connections.clone(),
+                actions: self.actions.clone(),
+                shared: self.shared.clone(),
+                waker,
+            };
+            admin::serve(listener, admin);
+        }
+    }
+
+    fn tick(&mut self, poll: &Poll, waker: &Arc<Waker>) {
+        let now = Instant::now();
+        // Move the delayed actions that are due to the action list
+        let mut delayed_actions = self.shared.delayed_actions.lock().unwrap();
+        if !delayed_actions.is_empty() {
+            let mut actions = self.actions.lock().unwrap();
+            let mut i = 0;
+            while i < delayed_actions.len() {
+                if delayed_actions[i].0 <= now {
+                    actions.push(delayed_actions.swap_remove(i).1);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        drop(delayed_actions);
+        if now < self.next_tick {
+            return;
+        }
+        self.next_tick = now + TICK_INTERVAL;
+        self.expire_connections(poll, waker);
+    }
+
     fn new_connection(
         &mut self,
         _: Token,
@@ -264,36 +833,64 @@ impl TCPImplementation for FTPServer {
         poll: &Poll,
         mut stream: TcpStream,
     ) -> Result<(), std::io::Error> {
-        print_stdout!(
+        info!(
             "[NEW_CONNECTION] {} - There is a brand new connection - Current connections: {} ",
             token.0,
             self.current_connections + 1
         );
-        if self.max_connections <= self.current_connections {
-            print_stdout!(
+        let peer = stream.peer_addr().ok();
+        let config = self.shared.config.lock().unwrap().clone();
+        let maintenance = self.shared.maintenance.lock().unwrap().clone();
+        // Registered before anything is counted, so an error doesn't leave a slot taken
+        poll.registry()
+            .register(&mut stream, token, Interest::WRITABLE)?;
+        let rejection = if self.max_connections() <= self.current_connections {
+            warn!(
                 "[NEW_CONNECTION] {} - Closing connection because it surpasses the maximum connections",
                 token.0
             );
-            poll.registry()
-                .register(&mut stream, token, Interest::WRITABLE)?;
-            self.add_connection(token, RequestType::Closed(stream));
+            Some("Too many users, try again later.".to_string())
+        } else if let Some(message) = maintenance {
+            info!(
+                "[NEW_CONNECTION] {} - Closing connection because of maintenance mode",
+                token.0
+            );
+            Some(message)
+        } else if let Some(peer) = peer.filter(|peer| {
+            !self
+                .connections_per_ip
+                .try_add(peer.ip(), config.max_connections_per_ip)
+        }) {
+            warn!(
+                "[NEW_CONNECTION] {} - Closing connection because {} surpasses the maximum connections per address",
+                token.0,
+                peer.ip()
+            );
+            Some("Too many connections from your address, try again later.".to_string())
+        } else {
+            None
+        };
+        if let Some(message) = rejection {
+            let reply = create_response(ResponseCode::service_not_available(), &message);
+            self.add_connection(token, RequestType::Closed(stream, reply), None);
             return Ok(());
         }
         self.current_connections += 1;
-        poll.registry()
-            .register(&mut stream, token, Interest::WRITABLE)?;
         self.add_connection(
             token,
             RequestType::CommandTransfer(
                 stream,
                 BufferToWrite::new(create_response(
                     ResponseCode::service_ready(),
-                    "Service ready for new user.",
+                    &config.banner,
                 )),
                 None,
                 None,
             ),
+            peer,
         );
+        let _session = logger::enter_session(token.0);
+        events::emit(&events::Event::Connect);
         Ok(())
     }
 
@@ -304,7 +901,7 @@ impl TCPImplementation for FTPServer {
         event: &Event,
     ) -> Result<(), Error> {
         let token = event.token();
-        print_stdout!("[WRITE_CONNECTION] - {} - Start Writing", token.0);
+        debug!("[WRITE_CONNECTION] - {} - Start Writing", token.0);
         let map_conn_arc = self.connections.clone();
         let map_conn = map_conn_arc.lock().unwrap();
         let connection = {
@@ -315,29 +912,42 @@ impl TCPImplementation for FTPServer {
         drop(map_conn);
         let mut connection_mutex = connection.lock().unwrap();
         self.deregister(poll, &mut connection_mutex)?;
+        connection_mutex.last_activity = Instant::now();
+        let session_id = connection_mutex.session_id(token);
         drop(connection_mutex);
         let actions_ref = self.action_list();
+        let shared = self.shared.clone();
         spawn(move || {
+            let _session = logger::enter_session(session_id);
+            let _worker = metrics::track(Gauge::WorkerTasks);
             let mut conn = connection.lock().unwrap();
-            let mut handler = HandlerWrite::new(token, map_conn_arc.clone(), connection.clone());
+            let mut handler = HandlerWrite::new(
+                token,
+                map_conn_arc.clone(),
+                connection.clone(),
+                shared,
+                conn.rate_limiter.clone(),
+            );
             let write_result = handler.handle_write(&mut conn.request_type, &waker);
             if let Err(err) = &write_result {
-                print_stdout!("[WRITE_CONNECTION] - {} - Fatal error -> {}", token.0, err);
+                error!("[WRITE_CONNECTION] - {} - Fatal error -> {}", token.0, err);
                 return;
             }
             // We drop the connection mutex here because we are promising the callback that it's 100% safe to take
             // any kind of mutex without getting a deadlock
             drop(conn);
-            if let Some(write_callback) = write_result.unwrap() {
-                write_callback();
-            }
+            // The actions go first, the callback can start a transfer that wants to write
+            // to this connection when it ends, and that must replace the interest added here
             let mut actions_locked = actions_ref.lock().unwrap();
             for action in handler.actions {
                 actions_locked.push(action);
             }
             drop(actions_locked);
+            if let Some(write_callback) = write_result.unwrap() {
+                write_callback();
+            }
             let _ = waker.wake();
-            print_stdout!("[WRITE_CONNECTION] - {} - Finished task", token.0);
+            debug!("[WRITE_CONNECTION] - {} - Finished task", token.0);
         });
         Ok(())
     }
@@ -348,7 +958,7 @@ impl TCPImplementation for FTPServer {
         waker: Arc<Waker>,
         event: &Event,
     ) -> Result<(), Error> {
-        print_stdout!("[READ_CONNECTION] - {} - Start read", event.token().0);
+        debug!("[READ_CONNECTION] - {} - Start read", event.token().0);
         // Connection database reference
         let map_conn = self.connections.clone();
         let map_conn = map_conn.lock().unwrap();
@@ -362,17 +972,18 @@ impl TCPImplementation for FTPServer {
         drop(map_conn);
         // Get the handler read component, basically in charge of reading and interpreting what is
         // getting sent by the client
-        let mut handler_read = {
+        let (session_id, mut handler_read) = {
             let conn_ref = &mut conn.lock().unwrap();
             self.deregister(poll, conn_ref)?;
-            HandlerRead::new(
+            conn_ref.last_activity = Instant::now();
+            let handler_read = HandlerRead::new(
                 token,
                 self.connections.clone(),
                 conn.clone(),
-                self.user_repository.clone(),
-                conn_ref.user_id.clone(),
-                conn_ref.loged,
-            )
+                self.shared.clone(),
+                conn_ref,
+            );
+            (conn_ref.session_id(token), handler_read)
         };
         // Get action list mutex
         let actions = self.action_list();
@@ -380,6 +991,8 @@ impl TCPImplementation for FTPServer {
         let next_id = self.next_id();
         // Spawn thread
         spawn(move || {
+            let _session = logger::enter_session(session_id);
+            let _worker = metrics::track(Gauge::WorkerTasks);
             let connection_arc = conn.clone();
             let mut connection_mutex = connection_arc.lock().unwrap();
             let response = handler_read.handle_read(
@@ -396,7 +1009,7 @@ impl TCPImplementation for FTPServer {
             let is_error_for_closing_connection = is_err && !is_would_block;
             if is_error_for_closing_connection {
                 if let Err(err) = response {
-                    print_stdout!(
+                    debug!(
                         "[READ_CONNECTION] - {} - Closing connection because error, {}",
                         token.0,
                         err
@@ -407,7 +1020,7 @@ impl TCPImplementation for FTPServer {
                 }
             } else if is_would_block {
                 drop(connection_mutex);
-                print_stdout!("[READ_CONNECTION] - {} - Would block", token.0);
+                debug!("[READ_CONNECTION] - {} - Would block", token.0);
                 let mut actions = actions.lock().unwrap();
                 actions.push((
                     handler_read.connection_token,
@@ -425,7 +1038,7 @@ impl TCPImplementation for FTPServer {
                 }
                 // Finally drop the mutex
                 drop(connection_mutex);
-                print_stdout!("[READ_CONNECTION] - {} - Adding actions", token.0);
+                debug!("[READ_CONNECTION] - {} - Adding actions", token.0);
                 let mut actions = actions.lock().unwrap();
                 for action in handler_read.actions {
                     actions.push(action);
@@ -433,7 +1046,7 @@ impl TCPImplementation for FTPServer {
                 drop(actions);
                 let _ = waker.wake();
             }
-            print_stdout!("[READ_CONNECTION] - {} - Finishing task", token.0);
+            debug!("[READ_CONNECTION] - {} - Finishing task", token.0);
         });
         Ok(())
     }
@@ -450,7 +1063,7 @@ impl TCPImplementation for FTPServer {
         token: Token,
         waker: &Arc<Waker>,
     ) -> Result<(), Error> {
-        print_stdout!("[CLOSE_CONNECTION] - {} - Closing connection", token.0);
+        debug!("[CLOSE_CONNECTION] - {} - Closing connection", token.0);
         let map_conn_arc = self.connections.clone();
         let map_conn = map_conn_arc.lock().unwrap();
         let conn = {
@@ -463,13 +1076,15 @@ impl TCPImplementation for FTPServer {
         };
         drop(map_conn);
         let mut conn = conn.lock().unwrap();
-        let user_name = conn.user_id.clone();
+        let _session = logger::enter_session(conn.session_id(token));
+        // Command connection waiting for a download that didn't finish
+        let mut aborted_download = None;
         match &mut conn.request_type {
-            RequestType::Closed(stream) => {
+            RequestType::Closed(stream, _) => {
                 let _ = poll.registry().deregister(stream);
                 let _ = stream.flush();
                 let _ = stream.shutdown(Shutdown::Both);
-                print_stdout!(
+                debug!(
                     "[CLOSE_CONNECTION] - {} - Closing connection because maximum connections reached",
                     token.0
                 );
@@ -477,12 +1092,20 @@ impl TCPImplementation for FTPServer {
 
             RequestType::FileTransferActive(stream, t, conn)
             | RequestType::FileTransferPassive(stream, t, conn) => {
-                if let FileTransferType::FileUpload(_, data_to_be_sent) = t {
+                if let FileTransferType::FileDownload(_, transfer) = t {
+                    // The client went away before we sent the whole file
+                    if transfer.finish(false) {
+                        aborted_download = Some(*conn);
+                    }
+                }
+                if let FileTransferType::FileUpload(_, data_to_be_sent, transfer, _) = t {
                     // As said in the function header, we shouldn't close this connection because
                     // we wanna keep reading
                     if data_to_be_sent.is_none() {
                         return Err(Error::from(ErrorKind::WriteZero));
                     }
+                    // A successful upload was already reported when the client closed its side
+                    transfer.finish(false);
                     let db = self.connections.clone();
                     let actions = self.actions.clone();
                     let conn = *conn;
@@ -491,7 +1114,7 @@ impl TCPImplementation for FTPServer {
                     let waker = waker.clone();
                     // Tell the command socket to send some stuff
                     spawn(move || {
-                        print_stdout!(
+                        debug!(
                             "[CLOSE_CONNECTION] - {} - Closing connection File Upload - {}",
                             token.0,
                             std::str::from_utf8(&data).unwrap()
@@ -513,7 +1136,7 @@ impl TCPImplementation for FTPServer {
                         Some(())
                     });
                 }
-                print_stdout!(
+                debug!(
                     "[CLOSE_CONNECTION] - {} - Closing connection FTA or FTP",
                     token.0
                 );
@@ -523,7 +1146,7 @@ impl TCPImplementation for FTPServer {
             }
 
             RequestType::CommandTransfer(stream, _, conn, _) => {
-                print_stdout!(
+                info!(
                     "[CLOSE_CONNECTION] - {} - Closing connection command",
                     token.0
                 );
@@ -532,19 +1155,12 @@ impl TCPImplementation for FTPServer {
                 let _ = stream.flush();
                 let _ = stream.shutdown(Shutdown::Both);
                 let conn = conn.take();
-                if let Some(user_name) = user_name {
-                    let mut user_db = self.user_repository.lock().unwrap();
-                    let u = user_db.get_user_mut(&user_name);
-                    if let Some(user) = u {
-                        let _ = user.change_dir("/");
-                    }
-                }
 
                 if let Some(conn) = &conn {
                     let mut map_conn = map_conn_arc.lock().unwrap();
                     let connection = map_conn.get_mut(conn);
                     if let Some(connection) = connection {
-                        print_stdout!(
+                        debug!(
                             "[CLOSE_CONNECTION] - {} - Closing dangling connection",
                             token.0
                         );
@@ -558,28 +1174,52 @@ impl TCPImplementation for FTPServer {
             }
 
             RequestType::PassiveModePort(stream, _) => {
-                print_stdout!("[CLOSE_CONNECTION] - {} - Closing port", token.0);
+                debug!("[CLOSE_CONNECTION] - {} - Closing port", token.0);
                 // We actually just deregister when we write
                 poll.registry().deregister(stream)?;
             }
         }
 
         // Now delete it from the database
+        let mut closed_session = false;
         if let Some(_) = self.connections.lock().unwrap().remove(&token) {
-            print_stdout!("[CLOSE_CONNECTION] Successfully removing the connection.");
+            debug!("[CLOSE_CONNECTION] Successfully removing the connection.");
             if let RequestType::CommandTransfer(_, _, _, _) = &conn.request_type {
+                closed_session = true;
                 self.current_connections -= 1;
+                if let Some(peer) = conn.peer {
+                    self.connections_per_ip.remove(peer.ip());
+                }
+                if conn.loged {
+                    hooks::trigger(hooks::HookEvent::Logout, None, None, None);
+                    if let Some(user_name) = &conn.user_id {
+                        self.shared.user_sessions.remove(user_name);
+                    }
+                }
             }
-            print_stdout!(
+            debug!(
                 "[CLOSE_CONNECTION] Current control connections - {}",
                 self.current_connections
             );
         }
 
-        print_stdout!(
+        debug!(
             "[CLOSE_CONNECTION] Current overall connections - {}",
             self.connections.lock().unwrap().len()
         );
+        drop(conn);
+        if closed_session {
+            self.abort_transfers(token);
+            events::emit(&events::Event::Disconnect);
+            logger::close_session(token.0);
+        }
+        if let Some(cmd) = aborted_download {
+            let aborted = create_response(
+                ResponseCode::transfer_aborted(),
+                "Connection closed; transfer aborted.",
+            );
+            self.reply_to_command(cmd, aborted, waker);
+        }
         // Closing the connection, returning ok...
         Ok(())
     }
@@ -587,188 +1227,242 @@ impl TCPImplementation for FTPServer {
 
 #[cfg(test)]
 mod ftp_server_testing {
-    use crate::port;
-    use std::io::{BufRead, BufReader, Write};
-    use std::net::TcpListener;
-    use std::net::TcpStream;
+    use super::config::{RegistrationPolicy, ServerConfig};
+    use super::storage::{MemoryRoot, MemoryStorage, StorageBackend};
+    use super::FTPServer;
+    use crate::{port, tcp};
+    use std::fs;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
     use std::{io::Read, time::Duration};
+    use user_manage::SystemUsers;
+
+    /// Server of a single test, it listens on a free port and keeps the files of the users in
+    /// memory, so the tests can run at the same time and don't change the repository
+    struct TestServer {
+        addr: SocketAddr,
+
+        storage: MemoryStorage,
+
+        /// Users file and log of the server
+        dir: PathBuf,
+    }
+
+    impl TestServer {
+        /// `users` log in with `123456`, their `chroot` is `./root/<user>` and it has a
+        /// `testfile.txt` with `Hello world!`
+        fn start(test: &str, users: &[&str]) -> Self {
+            Self::with_config(test, users, ServerConfig::default())
+        }
+
+        fn with_config(test: &str, users: &[&str], config: ServerConfig) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("ftp_server-{}-{}", test, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let users_file: serde_json::Map<String, serde_json::Value> = users
+                .iter()
+                .enumerate()
+                .map(|(uid, user)| {
+                    let fields = serde_json::json!({
+                        "passwd": "123456",
+                        "chroot": format!("./root/{}", user),
+                        "uid": uid,
+                    });
+                    (user.to_string(), fields)
+                })
+                .collect();
+            let users_path = dir.join("users.json");
+            fs::write(&users_path, serde_json::to_string(&users_file).unwrap()).unwrap();
+            let log_path = dir.join("ftpserver.log");
+            fs::write(&log_path, "").unwrap();
+            let system_users =
+                SystemUsers::load(users_path.to_str().unwrap(), log_path.to_str().unwrap())
+                    .unwrap();
+
+            let storage = MemoryStorage::new(0, 0);
+            let listener = mio::net::TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let server = Self {
+                addr: listener.local_addr().unwrap(),
+                storage: storage.clone(),
+                dir,
+            };
+            for user in users {
+                server.write(user, "/testfile.txt", b"Hello world!");
+            }
 
-    // use mio::net::{SocketAddr, TcpListener};
+            let mut ftp_server = FTPServer::with_users(config, system_users, Arc::new(storage));
+            std::thread::spawn(move || {
+                tcp::serve(listener, &mut ftp_server).expect("server returned an error");
+            });
+            server
+        }
+
+        fn connect(&self) -> TcpStream {
+            let mut stream = TcpStream::connect(self.addr).unwrap();
+            expect_response(&mut stream, "220 Service ready for new user.\r\n");
+            stream
+        }
+
+        fn log_in(&self, user: &str) -> TcpStream {
+            let mut stream = self.connect();
+            log_in(&mut stream, user, "123456");
+            stream
+        }
+
+        fn root(&self, user: &str) -> MemoryRoot {
+            self.storage.root(&format!("./root/{}", user))
+        }
+
+        fn write(&self, user: &str, path: &str, data: &[u8]) {
+            let mut file = self.root(user).open_write(path, false).unwrap();
+            file.write_all(data).unwrap();
+            file.finish().unwrap();
+        }
+
+        fn read(&self, user: &str, path: &str) -> Vec<u8> {
+            let file = self.root(user).open_read(path).unwrap();
+            let mut data = vec![0; file.size().unwrap() as usize];
+            let read = file.read_at(&mut data, 0).unwrap();
+            assert_eq!(read, data.len());
+            data
+        }
+
+        fn exists(&self, user: &str, path: &str) -> bool {
+            self.root(user).stat(path).is_ok()
+        }
+
+        /// What LIST sends for the root of `user`
+        fn listing(&self, user: &str) -> Vec<u8> {
+            self.root(user)
+                .list("/")
+                .unwrap()
+                .iter()
+                .map(|entry| format!("{}/{}\r\n", user, entry.name))
+                .collect::<String>()
+                .into_bytes()
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// A line of the server, byte by byte because a buffer could take the next reply too
+    fn read_reply(stream: &mut TcpStream) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0; 1];
+        while !line.ends_with(b"\n") {
+            let read = stream.read(&mut byte).expect("to work");
+            assert!(read == 1, "connection closed after {:?}", line);
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
 
     fn expect_response(stream: &mut TcpStream, response_expects: &str) {
-        // let mut buff = [0; 1024];
-        let mut b = BufReader::new(stream);
-        // let read = stream.read_until(&mut buff).expect("read didn't go well");
-        // let str = std::str::from_utf8(&buff[0..read]).expect("error parsing response");
-        let mut str = String::new();
-        b.read_line(&mut str).expect("to work");
-        assert_eq!(response_expects, str);
+        assert_eq!(response_expects, read_reply(stream));
     }
 
-    fn log_in(stream: &mut TcpStream, username: &str, password: &str) {
+    /// Sends USER and PASS, returns the reply to PASS
+    fn try_log_in(stream: &mut TcpStream, username: &str, password: &str) -> String {
         stream
-            .write_all(&format!("USER {}\r\n", username).as_bytes())
+            .write_all(format!("USER {}\r\n", username).as_bytes())
             .expect("user login didn't work");
         expect_response(stream, "331 User name okay, need password.\r\n");
         stream
-            .write_all(&format!("PASS {}\r\n", password).as_bytes())
+            .write_all(format!("PASS {}\r\n", password).as_bytes())
             .expect("user login didn't work");
-        expect_response(stream, "230 User logged in, proceed.\r\n");
+        read_reply(stream)
+    }
+
+    fn log_in(stream: &mut TcpStream, username: &str, password: &str) {
+        let reply = try_log_in(stream, username, password);
+        assert_eq!(reply, "230 User logged in, proceed.\r\n");
+    }
+
+    /// Listener for an active data connection on a free port, sent to the server with PORT
+    fn data_listener(stream: &mut TcpStream) -> TcpListener {
+        let srv = TcpListener::bind("127.0.0.1:0").expect("to create server");
+        let (first, second) = port::get_ftp_port_pair(srv.local_addr().unwrap().port());
+        let command = format!("PORT 127,0,0,1,{},{}\r\n", first, second);
+        stream
+            .write_all(&command.as_bytes())
+            .expect("writing everything");
+        srv
+    }
+
+    /// Everything that the server sends through the data connection of `srv`
+    fn receive(srv: TcpListener) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let (mut conn, _) = srv.accept().expect("expect to receive connection");
+            let mut data = Vec::new();
+            conn.read_to_end(&mut data).expect("to have read");
+            data
+        })
     }
 
-    use crate::system;
+    fn list_and_retr(server: &TestServer, user: &str) {
+        let mut stream = server.log_in(user);
+        let join = receive(data_listener(&mut stream));
+        expect_response(&mut stream, "200 Command okay.\r\n");
+        stream
+            .write_all(&"LIST\r\n".as_bytes())
+            .expect("writing everything");
+        expect_response(
+            &mut stream,
+            "150 File status okay; about to open data connection.\r\n",
+        );
+        expect_response(
+            &mut stream,
+            "226 Closing data connection. Requested file action successful (file transfer).\r\n",
+        );
+        assert_eq!(join.join().unwrap(), server.listing(user));
+        std::thread::sleep(Duration::from_millis(20));
+        let join = receive(data_listener(&mut stream));
+        expect_response(&mut stream, "200 Command okay.\r\n");
+        stream
+            .write_all(&"RETR ./testfile.txt\r\n".as_bytes())
+            .expect("writing everything");
+        expect_response(
+            &mut stream,
+            "150 File status okay; about to open data connection.\r\n",
+        );
+        expect_response(
+            &mut stream,
+            "226 Closing data connection. Requested file action successful (file transfer).\r\n",
+        );
+        assert_eq!(join.join().unwrap(), b"Hello world!");
+        std::thread::sleep(Duration::from_millis(20));
+    }
 
     #[test]
     fn it_works() {
+        let server = TestServer::start("it_works", &["user_012"]);
         for _ in 0..100 {
-            let result = TcpStream::connect("127.0.0.1:8080");
-            if let Err(err) = result {
-                panic!("{}", err);
-            }
-            let mut stream = result.unwrap();
-            expect_response(&mut stream, "220 Service ready for new user.\r\n");
-            log_in(&mut stream, "user_012", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2234").expect("to create server");
-            stream
-                .write_all(&"PORT 127,0,0,1,8,186\r\n".as_bytes())
-                .expect("writing everything");
-            let join = std::thread::spawn(move || {
-                let (mut conn, _) = srv.accept().expect("expect to receive connection");
-                let mut buff = [0; 1024];
-                let read = conn.read(&mut buff).expect("to have read");
-                let v = system::ls("./root/user_012").unwrap();
-                assert_eq!(v, &buff[..read]);
-                let possible_err = conn.read(&mut buff);
-                assert!(possible_err.unwrap() == 0);
-            });
-            expect_response(&mut stream, "200 Command okay.\r\n");
-            stream
-                .write_all(&"LIST\r\n".as_bytes())
-                .expect("writing everything");
-            expect_response(
-                &mut stream,
-                "150 File status okay; about to open data connection.\r\n",
-            );
-            expect_response(&mut stream, "226 Closing data connection. Requested file action successful (file transfer).\r\n");
-            join.join().unwrap();
-            std::thread::sleep(Duration::from_millis(20));
-            let srv = TcpListener::bind("127.0.0.1:2234").expect("to create server");
-            stream
-                .write_all(&"PORT 127,0,0,1,8,186\r\n".as_bytes())
-                .expect("writing everything");
-            let join = std::thread::spawn(move || {
-                let (mut conn, _) = srv.accept().expect("expect to receive connection");
-                let mut buff = [0; 1024];
-                let read = conn.read(&mut buff).expect("to have read");
-                let expected = "Hello world!";
-                assert_eq!(read, expected.len());
-                assert_eq!(std::str::from_utf8(&buff[..read]).unwrap(), expected);
-                let possible_err = conn.read(&mut buff);
-                assert!(possible_err.unwrap() == 0);
-            });
-            expect_response(&mut stream, "200 Command okay.\r\n");
-            stream
-                .write_all(&"RETR ./testfile.txt\r\n".as_bytes())
-                .expect("writing everything");
-            expect_response(
-                &mut stream,
-                "150 File status okay; about to open data connection.\r\n",
-            );
-            expect_response(
-                &mut stream,
-                "226 Closing data connection. Requested file action successful (file transfer).\r\n",
-            );
-            join.join().unwrap();
-            std::thread::sleep(Duration::from_millis(20));
+            list_and_retr(&server, "user_012");
         }
     }
 
     #[test]
     fn it_works2() {
+        let server = TestServer::start("it_works2", &["user_test_it_works_2"]);
+        server.write("user_test_it_works_2", "/other.txt", b"other");
+        server.root("user_test_it_works_2").mkdir("/dir").unwrap();
         for _ in 0..100 {
-            let mut stream = TcpStream::connect("127.0.0.1:8080").unwrap();
-            expect_response(&mut stream, "220 Service ready for new user.\r\n");
-            log_in(&mut stream, "user_test_it_works_2", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2235").expect("to create server");
-            stream
-                .write_all(&"PORT 127,0,0,1,8,187\r\n".as_bytes())
-                .expect("writing everything");
-            let join = std::thread::spawn(move || {
-                let (mut conn, _) = srv.accept().expect("expect to receive connection");
-                let mut buff = [0; 1024];
-                // print_stdout!("read 1st");
-                let read = conn.read(&mut buff).expect("to have read");
-                let v = system::ls("./root/user_test_it_works_2").unwrap();
-                assert_eq!(v, &buff[..read]);
-                // print_stdout!("read 2nd");
-                let possible_err = conn.read(&mut buff);
-                assert!(possible_err.unwrap() == 0);
-            });
-
-            expect_response(&mut stream, "200 Command okay.\r\n");
-
-            stream
-                .write_all(&"LIST\r\n".as_bytes())
-                .expect("writing everything");
-            expect_response(
-                &mut stream,
-                "150 File status okay; about to open data connection.\r\n",
-            );
-
-            expect_response(&mut stream, "226 Closing data connection. Requested file action successful (file transfer).\r\n");
-            join.join().unwrap();
-            std::thread::sleep(Duration::from_millis(20));
-            let srv = TcpListener::bind("127.0.0.1:2235").expect("to create server");
-            stream
-                .write_all(&"PORT 127,0,0,1,8,187\r\n".as_bytes())
-                .expect("writing everything");
-            let join = std::thread::spawn(move || {
-                let (mut conn, _) = srv.accept().expect("expect to receive connection");
-                let mut buff = [0; 1024];
-                let read = conn.read(&mut buff).expect("to have read");
-                let expected = "Hello world!";
-                assert_eq!(read, expected.len());
-                assert_eq!(std::str::from_utf8(&buff[..read]).unwrap(), expected);
-                let possible_err = conn.read(&mut buff);
-                assert!(possible_err.unwrap() == 0);
-            });
-            expect_response(&mut stream, "200 Command okay.\r\n");
-            stream
-                .write_all(&"RETR ./testfile.txt\r\n".as_bytes())
-                .expect("writing everything");
-            expect_response(
-                &mut stream,
-                "150 File status okay; about to open data connection.\r\n",
-            );
-            expect_response(
-                &mut stream,
-                "226 Closing data connection. Requested file action successful (file transfer).\r\n",
-            );
-            join.join().unwrap();
-            std::thread::sleep(Duration::from_millis(20));
+            list_and_retr(&server, "user_test_it_works_2");
         }
     }
 
     #[test]
     fn it_works3() {
+        let server = TestServer::start("it_works3", &["user_test_it_works_3"]);
         for _ in 0..100 {
-            let result = TcpStream::connect("127.0.0.1:8080");
-            let mut stream = result.unwrap();
-            expect_response(&mut stream, "220 Service ready for new user.\r\n");
-            log_in(&mut stream, "user_test_it_works_3", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2232").expect("to create server");
-            stream
-                .write_all(&"PORT 127,0,0,1,8,184\r\n".as_bytes())
-                .expect("writing everything");
-            let join = std::thread::spawn(move || {
-                let (mut conn, _) = srv.accept().expect("expect to receive connection");
-                let mut buff = [0; 1024];
-                let read = conn.read(&mut buff).expect("to have read");
-                let v = system::ls("./root/user_test_it_works_3").unwrap();
-                assert_eq!(v, &buff[..read]);
-                let possible_err = conn.read(&mut buff);
-                assert!(possible_err.unwrap() == 0);
-            });
+            let mut stream = server.log_in("user_test_it_works_3");
+            let join = receive(data_listener(&mut stream));
             expect_response(&mut stream, "200 Command okay.\r\n");
             stream
                 .write_all(&"LIST\r\n".as_bytes())
@@ -783,38 +1477,18 @@ mod ftp_server_testing {
                 .write_all(&"QUIT\r\n".as_bytes())
                 .expect("writing everything");
             expect_response(&mut stream, "221 Service closing control connection.\r\n");
-            join.join().unwrap();
+            assert_eq!(join.join().unwrap(), server.listing("user_test_it_works_3"));
             std::thread::sleep(Duration::from_millis(20));
         }
     }
 
     #[test]
     fn image_transfer() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_test_image_transfer", "123456");
-        let srv = TcpListener::bind("127.0.0.1:2233").expect("to create server");
-        stream
-            .write_all(&"PORT 127,0,0,1,8,185\r\n".as_bytes())
-            .expect("writing everything");
-        let join = std::thread::spawn(move || {
-            let mut f = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open("./2.jpg")
-                .unwrap();
-            let (mut conn, _) = srv.accept().expect("expect to receive connection");
-            let mut buff = [0; 1024];
-            loop {
-                let read = conn.read(&mut buff).expect("to have read");
-                if read == 0 {
-                    break;
-                }
-                f.write(&buff[0..read]).expect("to work");
-            }
-        });
-
+        let server = TestServer::start("image_transfer", &["user_test_image_transfer"]);
+        let image = fs::read("./test_files/1.jpeg").unwrap();
+        server.write("user_test_image_transfer", "/1.jpeg", &image);
+        let mut stream = server.log_in("user_test_image_transfer");
+        let join = receive(data_listener(&mut stream));
         expect_response(&mut stream, "200 Command okay.\r\n");
         stream
             .write_all(&"RETR ./1.jpeg\r\n".as_bytes())
@@ -827,34 +1501,27 @@ mod ftp_server_testing {
             &mut stream,
             "226 Closing data connection. Requested file action successful (file transfer).\r\n",
         );
-        join.join().unwrap();
+        assert!(join.join().unwrap() == image);
         std::thread::sleep(Duration::from_millis(20));
     }
 
     #[test]
     fn image_transfer_02() {
+        let server = TestServer::start("image_transfer_02", &["user_test_image_transfer_02"]);
         for _i in 0..100 {
-            let result = TcpStream::connect("127.0.0.1:8080");
-            let mut stream = result.unwrap();
-            expect_response(&mut stream, "220 Service ready for new user.\r\n");
-            log_in(&mut stream, "user_test_image_transfer_02", "123456");
-            let srv = TcpListener::bind("127.0.0.1:2253").expect("to create server");
-            stream
-                .write_all(&"PORT 127,0,0,1,8,205\r\n".as_bytes())
-                .expect("writing everything");
+            let mut stream = server.log_in("user_test_image_transfer_02");
+            let srv = data_listener(&mut stream);
             upload_hello_world(srv, &mut stream);
+            assert_eq!(
+                server.read("user_test_image_transfer_02", "/thing.txt"),
+                b"Hello World!\n".repeat(100)
+            );
             std::thread::sleep(Duration::from_millis(20));
         }
     }
 
-    fn upload_active<'a>(stream: &mut TcpStream, to: &'a str, from: &'static str, port: u16) {
-        let conn = format!("127.0.0.1:{}", port);
-        let srv = TcpListener::bind(conn).expect("to create server");
-        let (first, second) = port::get_ftp_port_pair(port);
-        let command = format!("PORT 127,0,0,1,{},{}\r\n", first, second);
-        stream
-            .write_all(&command.as_bytes())
-            .expect("writing everything");
+    fn upload_active(stream: &mut TcpStream, to: &str, from: &'static str) {
+        let srv = data_listener(stream);
         let join = std::thread::spawn(move || {
             let mut f = std::fs::OpenOptions::new().read(true).open(from).unwrap();
             let (mut conn, _) = srv.accept().expect("expect to receive connection");
@@ -885,34 +1552,10 @@ mod ftp_server_testing {
         join.join().unwrap();
     }
 
-    fn recv_active<'a>(stream: &mut TcpStream, to: &'a str, from: &'static str, port: u16) {
-        let conn = format!("127.0.0.1:{}", port);
-        let srv = TcpListener::bind(conn).expect("to create server");
-        let (first, second) = port::get_ftp_port_pair(port);
-        let command = format!("PORT 127,0,0,1,{},{}\r\n", first, second);
-        stream
-            .write_all(&command.as_bytes())
-            .expect("writing everything");
-        let join = std::thread::spawn(move || {
-            let mut f = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(from)
-                .unwrap();
-            let (mut conn, _) = srv.accept().expect("expect to receive connection");
-            let mut buff = [0; 1024];
-            loop {
-                let r = conn.read(&mut buff).expect("to have read");
-                if r == 0 {
-                    break;
-                }
-                let w = f.write(&mut buff[0..r]).expect("to have read");
-                assert!(w == r);
-            }
-            drop(f);
-        });
+    fn recv_active(stream: &mut TcpStream, from: &str) -> Vec<u8> {
+        let join = receive(data_listener(stream));
         expect_response(stream, "200 Command okay.\r\n");
-        let command = format!("RETR {}\r\n", to);
+        let command = format!("RETR {}\r\n", from);
         stream
             .write_all(&command.as_bytes())
             .expect("writing everything");
@@ -924,19 +1567,19 @@ mod ftp_server_testing {
             stream,
             "226 Closing data connection. Requested file action successful (file transfer).\r\n",
         );
-        join.join().unwrap();
+        join.join().unwrap()
     }
 
     #[test]
     fn mkdir() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_test_mkdir_01", "123456");
+        let server = TestServer::start("mkdir", &["user_test_mkdir_01"]);
+        let mut stream = server.log_in("user_test_mkdir_01");
         stream
             .write_all(&"MKD /test\r\n".as_bytes())
             .expect("writing everything");
         expect_response(&mut stream, "257 'test' directory created.\r\n");
+        let test = server.root("user_test_mkdir_01").stat("/test").unwrap();
+        assert!(test.is_dir);
         stream
             .write_all(&"RMD /test\r\n".as_bytes())
             .expect("writing everything");
@@ -944,6 +1587,7 @@ mod ftp_server_testing {
             &mut stream,
             "250 Requested file action okay, completed.\r\n",
         );
+        assert!(!server.exists("user_test_mkdir_01", "/test"));
     }
 
     fn upload_hello_world(srv: TcpListener, stream: &mut TcpStream) {
@@ -972,14 +1616,9 @@ mod ftp_server_testing {
 
     #[test]
     fn cwd_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_test_cwd_test", "123456");
-        let srv = TcpListener::bind("127.0.0.1:2302").expect("to create server");
-        stream
-            .write_all(&"PORT 127,0,0,1,8,254\r\n".as_bytes())
-            .expect("writing everything");
+        let server = TestServer::start("cwd_test", &["user_test_cwd_test"]);
+        let mut stream = server.log_in("user_test_cwd_test");
+        let srv = data_listener(&mut stream);
         upload_hello_world(srv, &mut stream);
         stream
             .write_all(&"MKD /test\r\n".as_bytes())
@@ -993,11 +1632,9 @@ mod ftp_server_testing {
             &mut stream,
             "250 Requested file action okay, completed.\r\n",
         );
-        let srv = TcpListener::bind("127.0.0.1:2302").expect("to create server");
-        stream
-            .write_all(&"PORT 127,0,0,1,8,254\r\n".as_bytes())
-            .expect("writing everything");
+        let srv = data_listener(&mut stream);
         upload_hello_world(srv, &mut stream);
+        assert!(server.exists("user_test_cwd_test", "/test/thing.txt"));
         stream
             .write_all(&"DELE ../thing.txt\r\n".as_bytes())
             .expect("writing everything");
@@ -1005,6 +1642,7 @@ mod ftp_server_testing {
             &mut stream,
             "250 Requested file action okay, completed.\r\n",
         );
+        assert!(!server.exists("user_test_cwd_test", "/thing.txt"));
         stream
             .write_all(&"CWD ../\r\n".as_bytes())
             .expect("writing everything");
@@ -1019,19 +1657,16 @@ mod ftp_server_testing {
             &mut stream,
             "250 Requested file action okay, completed.\r\n",
         );
+        assert!(!server.exists("user_test_cwd_test", "/test"));
     }
 
     #[test]
     fn create_file_delete() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_test_create_file_delete", "123456");
-        let srv = TcpListener::bind("127.0.0.1:2303").expect("to create server");
-        stream
-            .write_all(&"PORT 127,0,0,1,8,255\r\n".as_bytes())
-            .expect("writing everything");
+        let server = TestServer::start("create_file_delete", &["user_test_create_file_delete"]);
+        let mut stream = server.log_in("user_test_create_file_delete");
+        let srv = data_listener(&mut stream);
         upload_hello_world(srv, &mut stream);
+        assert!(server.exists("user_test_create_file_delete", "/thing.txt"));
         stream
             .write_all(&"DELE ./thing.txt\r\n".as_bytes())
             .expect("writing everything");
@@ -1039,6 +1674,7 @@ mod ftp_server_testing {
             &mut stream,
             "250 Requested file action okay, completed.\r\n",
         );
+        assert!(!server.exists("user_test_create_file_delete", "/thing.txt"));
     }
 
     fn dele(stream: &mut TcpStream, what: &str) {
@@ -1097,16 +1733,16 @@ mod ftp_server_testing {
     //cargo test --package ftp_server --bin ftp_server -- ftp::ftp_server_testing::pwd_test --exact --nocapture
     #[test]
     fn pwd_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_pwd_test", "123456");
+        let server = TestServer::start("pwd_test", &["user_pwd_test"]);
+        let mut stream = server.log_in("user_pwd_test");
         pwd(&mut stream, "/");
         mkd(&mut stream, "/thing");
         mkd(&mut stream, "/thing/thing2");
         mkd(&mut stream, "/thing/thing2/thing3");
+        assert!(server.exists("user_pwd_test", "/thing/thing2/thing3"));
         cwd(&mut stream, "/thing");
         mkd(&mut stream, "./thing4");
+        assert!(server.exists("user_pwd_test", "/thing/thing4"));
         rmd(&mut stream, "./thing4");
         pwd(&mut stream, "/thing");
         cwd(&mut stream, "./thing2");
@@ -1115,85 +1751,92 @@ mod ftp_server_testing {
         pwd(&mut stream, "/thing/thing2");
         cwd(&mut stream, "../../");
         rmd(&mut stream, "/thing");
+        assert!(!server.exists("user_pwd_test", "/thing"));
     }
 
     #[test]
     fn recv_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_recv_test", "123456");
-        upload_active(&mut stream, "./1.jpeg", "./test_files/1.jpeg", 1887);
-        recv_active(&mut stream, "./1.jpeg", "./test_files/2.jpeg", 1887);
+        let server = TestServer::start("recv_test", &["user_recv_test"]);
+        let mut stream = server.log_in("user_recv_test");
+        upload_active(&mut stream, "./1.jpeg", "./test_files/1.jpeg");
+        let image = fs::read("./test_files/1.jpeg").unwrap();
+        assert!(server.read("user_recv_test", "/1.jpeg") == image);
+        assert!(recv_active(&mut stream, "./1.jpeg") == image);
         dele(&mut stream, "/1.jpeg");
+        assert!(!server.exists("user_recv_test", "/1.jpeg"));
     }
 
     #[test]
     fn store_text_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_store_text_test", "123456");
-        upload_active(&mut stream, "./t.txt", "./test_files/hola.txt", 2777);
+        let server = TestServer::start("store_text_test", &["user_store_text_test"]);
+        let mut stream = server.log_in("user_store_text_test");
+        upload_active(&mut stream, "./t.txt", "./test_files/hola.txt");
+        assert_eq!(
+            server.read("user_store_text_test", "/t.txt"),
+            fs::read("./test_files/hola.txt").unwrap()
+        );
     }
 
     #[test]
     fn store_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_store_test", "123456");
+        let server = TestServer::start("store_test", &["user_store_test"]);
+        let mut stream = server.log_in("user_store_test");
         mkd(&mut stream, "/thing");
         mkd(&mut stream, "/thing/thing2");
-        upload_active(&mut stream, "./thing/1.jpeg", "./test_files/1.jpeg", 1777);
-        upload_active(
-            &mut stream,
-            "./thing/thing2/1.jpeg",
-            "./test_files/1.jpeg",
-            1777,
-        );
+        upload_active(&mut stream, "./thing/1.jpeg", "./test_files/1.jpeg");
+        upload_active(&mut stream, "./thing/thing2/1.jpeg", "./test_files/1.jpeg");
+        let image = fs::read("./test_files/1.jpeg").unwrap();
+        assert!(server.read("user_store_test", "/thing/1.jpeg") == image);
+        assert!(server.read("user_store_test", "/thing/thing2/1.jpeg") == image);
         rmd(&mut stream, "/thing");
+        assert!(!server.exists("user_store_test", "/thing"));
     }
 
     #[test]
     fn store_2_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_store_2_test", "123456");
+        let server = TestServer::start("store_2_test", &["user_store_2_test"]);
+        let mut stream = server.log_in("user_store_2_test");
         for i in 0..100 {
             let s = format!("./{}.jpeg", i);
-            upload_active(&mut stream, &s, "./test_files/1.jpeg", 1778);
+            upload_active(&mut stream, &s, "./test_files/1.jpeg");
         }
+        let files = server.root("user_store_2_test").list("/").unwrap();
+        assert_eq!(files.len(), 101);
 
         for i in 0..100 {
             let s = format!("./{}.jpeg", i);
             dele(&mut stream, &s);
         }
+        let files = server.root("user_store_2_test").list("/").unwrap();
+        assert_eq!(files.len(), 1);
     }
 
     #[test]
     fn rnto_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_rnto_test", "123456");
+        let server = TestServer::start("rnto_test", &["user_rnto_test"]);
+        let mut stream = server.log_in("user_rnto_test");
         pwd(&mut stream, "/");
         mkd(&mut stream, "/thing");
         mkd(&mut stream, "/thing/thing2");
         mkd(&mut stream, "/thing/thing2/thing3");
         rnto(&mut stream, "/thing", "/thing5");
+        assert!(!server.exists("user_rnto_test", "/thing"));
+        assert!(server.exists("user_rnto_test", "/thing5/thing2/thing3"));
         rnto(&mut stream, "/thing5/thing2", "/thing2");
+        assert!(server.exists("user_rnto_test", "/thing2/thing3"));
         rmd(&mut stream, "/thing2");
         rmd(&mut stream, "/thing5");
+        assert!(!server.exists("user_rnto_test", "/thing2"));
+        assert!(!server.exists("user_rnto_test", "/thing5"));
     }
 
     #[test]
     fn delete_its_own_directory_test() {
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_delete_its_own_directory_test", "123456");
+        let server = TestServer::start(
+            "delete_its_own_directory_test",
+            &["user_delete_its_own_directory_test"],
+        );
+        let mut stream = server.log_in("user_delete_its_own_directory_test");
         pwd(&mut stream, "/");
         mkd(&mut stream, "/thing");
         mkd(&mut stream, "/thing/thing2");
@@ -1205,20 +1848,70 @@ mod ftp_server_testing {
         pwd(&mut stream, "/thing");
         rmd(&mut stream, "../thing");
         pwd(&mut stream, "/");
+        assert!(!server.exists("user_delete_its_own_directory_test", "/thing"));
     }
 
     #[test]
-    fn passive_connection() {
-        // We could reduce these steps to functions and reuse them but its ok
-        // at the moment
-        let result = TcpStream::connect("127.0.0.1:8080");
-        let mut stream = result.unwrap();
-        expect_response(&mut stream, "220 Service ready for new user.\r\n");
-        log_in(&mut stream, "user_test_image_transfer_02", "123456");
+    fn every_session_has_its_own_working_dir() {
+        let mut config = ServerConfig::default();
+        config.anonymous.enabled = true;
+        let server = TestServer::with_config("session_cwd", &["user_cwd"], config);
+        server.root("user_cwd").mkdir("/dir").unwrap();
+        let anonymous = server.storage.root("./root/anonymous");
+        anonymous.mkdir("/pub").unwrap();
+        let sessions = [
+            ("user_cwd", "123456", "/dir"),
+            ("anonymous", "guest@example.com", "/pub"),
+        ];
+        for (user, password, dir) in sessions {
+            let mut first = server.connect();
+            assert!(try_log_in(&mut first, user, password).starts_with("230 "));
+            let mut second = server.connect();
+            assert!(try_log_in(&mut second, user, password).starts_with("230 "));
+            cwd(&mut first, dir);
+            pwd(&mut first, dir);
+            pwd(&mut second, "/");
+            // Logging out doesn't move the other sessions either
+            second.write_all(b"QUIT\r\n").unwrap();
+            expect_response(&mut second, "221 Service closing control connection.\r\n");
+            pwd(&mut first, dir);
+        }
+    }
+
+    #[test]
+    fn unknown_users_are_answered_like_wrong_passwords() {
+        let server = TestServer::start("unknown_users", &["user_known"]);
+        let mut stream = server.connect();
+        let wrong_password = try_log_in(&mut stream, "user_known", "654321");
+        assert_eq!(wrong_password, "530 Not logged in.\r\n");
+        let mut stream = server.connect();
+        let unknown_user = try_log_in(&mut stream, "user_unknown", "123456");
+        assert_eq!(unknown_user, wrong_password);
+    }
+
+    #[test]
+    fn registration_keeps_the_users_in_root() {
+        let mut config = ServerConfig::default();
+        config.registration.policy = RegistrationPolicy::Open;
+        let server = TestServer::with_config("registration", &[], config);
+        for name in ["..", ".", "a/../../..", "a\\..\\.."] {
+            let mut stream = server.connect();
+            assert_eq!(
+                try_log_in(&mut stream, name, "123456"),
+                "530 Not logged in.\r\n"
+            );
+        }
+        let users = fs::read_to_string(server.dir.join("users.json")).unwrap();
+        assert!(!users.contains(".."));
+        let mut stream = server.connect();
+        log_in(&mut stream, "newcomer", "123456");
+        pwd(&mut stream, "/");
+    }
+
+    /// Sends PASV, returns the address of the passive port
+    fn pasv(stream: &mut TcpStream) -> SocketAddr {
         stream.write_all(&"PASV\r\n".as_bytes()).unwrap();
-        let mut b = BufReader::new(&mut stream);
-        let mut str = String::new();
-        b.read_line(&mut str).expect("to work");
+        let mut str = read_reply(stream);
         let end_no_jl = str.len() - 2;
         let s = &mut str[..end_no_jl - 1];
         let split = s.split('(').collect::<Vec<&str>>();
@@ -1233,24 +1926,24 @@ mod ftp_server_testing {
             "{}.{}.{}.{}:{}",
             bytes[0], bytes[1], bytes[2], bytes[3], port
         );
-        let mut connection =
-            TcpStream::connect_timeout(ip.parse().as_ref().unwrap(), Duration::from_micros(1000))
-                .unwrap();
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn passive_connection() {
+        let server = TestServer::start("passive_connection", &["user_test_image_transfer_02"]);
+        let image = fs::read("./test_files/1.jpeg").unwrap();
+        server.write("user_test_image_transfer_02", "/1.jpeg", &image);
+        // We could reduce these steps to functions and reuse them but its ok
+        // at the moment
+        let mut stream = server.log_in("user_test_image_transfer_02");
+        let ip = pasv(&mut stream);
+        let mut connection = TcpStream::connect_timeout(&ip, Duration::from_millis(1000)).unwrap();
         expect_response(&mut stream, "200 Command okay.\r\n");
         let join = std::thread::spawn(move || {
-            let mut f = std::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open("./2.jpg")
-                .unwrap();
-            let mut buff = [0; 1024];
-            loop {
-                let read = connection.read(&mut buff).expect("to have read");
-                if read == 0 {
-                    break;
-                }
-                f.write(&buff[0..read]).expect("to work");
-            }
+            let mut data = Vec::new();
+            connection.read_to_end(&mut data).expect("to have read");
+            data
         });
         stream
             .write_all(&"RETR ./1.jpeg\r\n".as_bytes())
@@ -1263,7 +1956,81 @@ mod ftp_server_testing {
             &mut stream,
             "226 Closing data connection. Requested file action successful (file transfer).\r\n",
         );
-        join.join().unwrap();
+        assert!(join.join().unwrap() == image);
         std::thread::sleep(Duration::from_millis(20));
     }
+
+    /// Server whose timeouts are of a few seconds, 0 turns one off
+    fn with_timeouts(test: &str, user: &str, control: u64, data: u64, passive: u64) -> TestServer {
+        let config = ServerConfig {
+            control_timeout: control,
+            data_timeout: data,
+            passive_timeout: passive,
+            ..ServerConfig::default()
+        };
+        TestServer::with_config(test, &[user], config)
+    }
+
+    /// Starts the download of a file that is too big for the socket buffers
+    /// and never reads it, returns the data connection
+    fn stall_download(server: &TestServer, stream: &mut TcpStream, user: &str) -> TcpStream {
+        server.write(user, "/big.bin", &vec![b'a'; 64 * 1024 * 1024]);
+        let srv = data_listener(stream);
+        expect_response(stream, "200 Command okay.\r\n");
+        stream.write_all(b"RETR big.bin\r\n").unwrap();
+        let (data, _) = srv.accept().unwrap();
+        expect_response(
+            stream,
+            "150 File status okay; about to open data connection.\r\n",
+        );
+        data
+    }
+
+    #[test]
+    fn idle_control_connections_are_closed() {
+        let server = with_timeouts("idle_control", "user_idle_control", 1, 30, 30);
+        let mut stream = server.log_in("user_idle_control");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        expect_response(&mut stream, "421 Timeout, closing control connection.\r\n");
+        assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn unused_passive_ports_are_closed() {
+        let server = with_timeouts("unused_passive", "user_unused_passive", 30, 30, 1);
+        let mut stream = server.log_in("user_unused_passive");
+        let ip = pasv(&mut stream);
+        std::thread::sleep(Duration::from_secs(3));
+        assert!(TcpStream::connect_timeout(&ip, Duration::from_millis(1000)).is_err());
+        pwd(&mut stream, "/");
+    }
+
+    #[test]
+    fn stalled_downloads_are_aborted() {
+        let server = with_timeouts("stalled_download", "user_stalled_download", 30, 1, 30);
+        let mut stream = server.log_in("user_stalled_download");
+        let _data = stall_download(&server, &mut stream, "user_stalled_download");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        expect_response(&mut stream, "426 Connection closed; transfer aborted.\r\n");
+        pwd(&mut stream, "/");
+    }
+
+    #[test]
+    fn control_connections_wait_for_their_transfers() {
+        let server = with_timeouts("waiting_control", "user_waiting_control", 1, 0, 30);
+        let mut stream = server.log_in("user_waiting_control");
+        let mut data = stall_download(&server, &mut stream, "user_waiting_control");
+        std::thread::sleep(Duration::from_secs(3));
+        let mut received = Vec::new();
+        data.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), 64 * 1024 * 1024);
+        expect_response(
+            &mut stream,
+            "226 Closing data connection. Requested file action successful (file transfer).\r\n",
+        );
+    }
 }
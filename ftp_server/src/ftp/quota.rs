@@ -0,0 +1,544 @@
+//! Disk and file count quotas of the users and of the shared directories of the groups.
+//!
+//! `quota_bytes` and `quota_files` of a user in `users.json` limit what it can keep in its tree
+//! (its `chroot` and the mounts that it can write to), the ones of a group in `groups.json` limit
+//! its shared directory, whoever of the members writes there. The usage is counted from the
+//! files the first time that it's needed and then kept up to date by the uploads, DELE, RMD and
+//! the renames between accounts (which are refused with 552 if they don't fit in the quota of
+//! the destination), the `quota-rescan` command of the admin socket counts it again (e.g if the files were changed
+//! by something else than the server). An upload is refused with 552 if it's already over the
+//! quota and stopped with 552 as soon as it goes over it.
+
+use super::{
+    commands::{CommandHandler, Reply, Session},
+    response::ResponseCode,
+    storage::{self, Storage, StorageBackend},
+};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+};
+use user_manage::User;
+
+/// Bytes and files of a user, or of a part of its tree
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: u64,
+
+    pub files: u64,
+}
+
+impl Usage {
+    /// Counts the files under `dir`, the directories in `skip` are left out
+    pub fn scan(tree: &dyn StorageBackend, dir: &str, skip: &[String]) -> io::Result<Self> {
+        let mut usage = Usage::default();
+        for entry in tree.list(dir)? {
+            let path = storage::join(dir, &entry.name);
+            if !entry.metadata.is_dir {
+                usage.bytes += entry.metadata.size;
+                usage.files += 1;
+            } else if !skip.contains(&path) {
+                let inner = Usage::scan(tree, &path, skip)?;
+                usage.bytes += inner.bytes;
+                usage.files += inner.files;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// How much of the limits it is, e.g `100 of 1024 bytes and 2 files (no limit) used`
+    fn describe(&self, quota_bytes: Option<u64>, quota_files: Option<u64>) -> String {
+        let part = |used: u64, limit: Option<u64>, unit: &str| match limit {
+            Some(limit) => format!("{} of {} {}", used, limit, unit),
+            None => format!("{} {} (no limit)", used, unit),
+        };
+        format!(
+            "{} and {} used",
+            part(self.bytes, quota_bytes, "bytes"),
+            part(self.files, quota_files, "files")
+        )
+    }
+}
+
+/// If the user, or one of its groups, has any quota
+pub fn has_quota(user: &User) -> bool {
+    user.get_quota_bytes().is_some()
+        || user.get_quota_files().is_some()
+        || user
+            .get_groups()
+            .iter()
+            .any(|group| group.quota_bytes.is_some() || group.quota_files.is_some())
+}
+
+/// Whose usage the files count in
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Account {
+    User(String),
+    Group(String),
+}
+
+/// An account as a user sees it
+struct Owner {
+    account: Account,
+
+    quota_bytes: Option<u64>,
+
+    quota_files: Option<u64>,
+
+    /// Where its files are in the tree of the user
+    root: String,
+
+    /// Directories under `root` that don't count
+    skip: Vec<String>,
+}
+
+/// If `path` is `dir` or it's inside it
+fn contains(dir: &str, path: &str) -> bool {
+    dir == "/" || path == dir || path.starts_with(&storage::join(dir, ""))
+}
+
+/// The account of the user and the ones of the shared directories of its groups
+fn owners(user_name: &str, user: &User) -> Vec<Owner> {
+    let groups = user.get_groups().iter().filter_map(|group| {
+        group.mount.as_ref().map(|mount| Owner {
+            account: Account::Group(group.name.clone()),
+            quota_bytes: group.quota_bytes,
+            quota_files: group.quota_files,
+            root: storage::resolve("/", &mount.path),
+            skip: Vec::new(),
+        })
+    });
+    let mut owners: Vec<Owner> = groups.collect();
+    // The files of the read only mounts and of the groups aren't the ones of the user
+    let skip = user
+        .get_mounts()
+        .filter(|mount| mount.read_only)
+        .map(|mount| storage::resolve("/", &mount.path))
+        .chain(owners.iter().map(|owner| owner.root.clone()))
+        .collect();
+    owners.insert(
+        0,
+        Owner {
+            account: Account::User(user_name.to_string()),
+            quota_bytes: user.get_quota_bytes(),
+            quota_files: user.get_quota_files(),
+            root: "/".to_string(),
+            skip,
+        },
+    );
+    owners
+}
+
+/// The account that a path of the tree of the user counts in
+fn owner(user_name: &str, user: &User, path: &str) -> Owner {
+    let mut owners = owners(user_name, user);
+    let index = owners
+        .iter()
+        .rposition(|owner| contains(&owner.root, path))
+        .unwrap_or(0);
+    owners.swap_remove(index)
+}
+
+fn exceeded(what: &str) -> Reply {
+    Reply::new(
+        ResponseCode::exceeded_storage_allocation(),
+        &format!(
+            "Requested file action aborted. Exceeded storage allocation ({}).",
+            what
+        ),
+    )
+}
+
+/// Usage of the users and groups, shared between the worker threads.
+/// Only the accounts whose usage has been counted are kept up to date
+#[derive(Default, Debug, Clone)]
+pub struct Quotas {
+    usage: Arc<Mutex<HashMap<Account, Usage>>>,
+}
+
+impl Quotas {
+    /// Usage of the account, it's counted from the files the first time
+    fn usage(&self, owner: &Owner, user: &User, storage: &dyn Storage) -> io::Result<Usage> {
+        if let Some(usage) = self.usage.lock().unwrap().get(&owner.account) {
+            return Ok(*usage);
+        }
+        self.scan(owner, user, storage)
+    }
+
+    fn scan(&self, owner: &Owner, user: &User, storage: &dyn Storage) -> io::Result<Usage> {
+        let tree = storage.user_root(user)?;
+        let usage = Usage::scan(tree.as_ref(), &owner.root, &owner.skip)?;
+        self.usage
+            .lock()
+            .unwrap()
+            .insert(owner.account.clone(), usage);
+        Ok(usage)
+    }
+
+    /// Describes the usage of the user and of its groups,
+    /// e.g `10 of 100 bytes and 1 files (no limit) used, group team: ...`
+    pub fn report(
+        &self,
+        user_name: &str,
+        user: &User,
+        storage: &dyn Storage,
+    ) -> io::Result<String> {
+        self.describe(user_name, user, storage, false)
+    }
+
+    /// Counts again the files of the user and of its groups, then describes them like `report`
+    pub fn rescan(
+        &self,
+        user_name: &str,
+        user: &User,
+        storage: &dyn Storage,
+    ) -> io::Result<String> {
+        self.describe(user_name, user, storage, true)
+    }
+
+    fn describe(
+        &self,
+        user_name: &str,
+        user: &User,
+        storage: &dyn Storage,
+        rescan: bool,
+    ) -> io::Result<String> {
+        let mut parts = Vec::new();
+        for owner in owners(user_name, user) {
+            let usage = if rescan {
+                self.scan(&owner, user, storage)?
+            } else {
+                self.usage(&owner, user, storage)?
+            };
+            let description = usage.describe(owner.quota_bytes, owner.quota_files);
+            parts.push(match &owner.account {
+                Account::User(_) => description,
+                Account::Group(name) => format!("group {}: {}", name, description),
+            });
+        }
+        Ok(parts.join(", "))
+    }
+
+    /// Drops the usage of the user, it's counted again the next time that it's needed
+    pub fn forget(&self, user_name: &str) {
+        self.usage
+            .lock()
+            .unwrap()
+            .remove(&Account::User(user_name.to_string()));
+    }
+
+    /// If the usage that `path` counts in is being kept up to date
+    pub fn tracks(&self, user_name: &str, user: &User, path: &str) -> bool {
+        let owner = owner(user_name, user, path);
+        self.usage.lock().unwrap().contains_key(&owner.account)
+    }
+
+    /// Takes `freed` out of the usage that `path` counts in, e.g when the user deletes it
+    pub fn release(&self, user_name: &str, user: &User, path: &str, freed: Usage) {
+        let owner = owner(user_name, user, path);
+        if let Some(usage) = self.usage.lock().unwrap().get_mut(&owner.account) {
+            usage.bytes = usage.bytes.saturating_sub(freed.bytes);
+            usage.files = usage.files.saturating_sub(freed.files);
+        }
+    }
+
+    /// Checks that what is at `from` fits in the quota of the account that `to` counts in, when
+    /// it isn't the one of `from`. Returns the usage that the rename moves between them, if any
+    pub fn start_rename(
+        &self,
+        user_name: &str,
+        user: &User,
+        storage: &dyn Storage,
+        tree: &dyn StorageBackend,
+        from: &str,
+        to: &str,
+    ) -> Result<Option<Usage>, Reply> {
+        let source = owner(user_name, user, from);
+        let destination = owner(user_name, user, to);
+        if source.account == destination.account {
+            return Ok(None);
+        }
+        let local_error = |_| {
+            Reply::new(
+                ResponseCode::local_error(),
+                "Requested action aborted: local error in processing.",
+            )
+        };
+        let metadata = tree.stat(from).map_err(local_error)?;
+        let moved = if metadata.is_dir {
+            Usage::scan(tree, from, &source.skip).map_err(local_error)?
+        } else {
+            Usage {
+                bytes: metadata.size,
+                files: 1,
+            }
+        };
+        if destination.quota_bytes.is_some() || destination.quota_files.is_some() {
+            let usage = self
+                .usage(&destination, user, storage)
+                .map_err(local_error)?;
+            if let Some(limit) = destination.quota_files {
+                if usage.files + moved.files > limit {
+                    return Err(exceeded("files"));
+                }
+            }
+            if let Some(limit) = destination.quota_bytes {
+                if usage.bytes + moved.bytes > limit {
+                    return Err(exceeded("bytes"));
+                }
+            }
+        }
+        Ok(Some(moved))
+    }
+
+    /// Moves `moved` from the usage that `from` counts in to the one of `to`, once the rename
+    /// that `start_rename` checked is done
+    pub fn renamed(&self, user_name: &str, user: &User, from: &str, to: &str, moved: Usage) {
+        self.release(user_name, user, from, moved);
+        let destination = owner(user_name, user, to);
+        if let Some(usage) = self.usage.lock().unwrap().get_mut(&destination.account) {
+            usage.bytes += moved.bytes;
+            usage.files += moved.files;
+        }
+    }
+
+    /// Checks that the user can upload the file at `path` and counts it, `existing` is the size
+    /// of the file if it's already there (STOR replaces it, APPE adds to it).
+    /// The bytes of the upload must go through the returned charge
+    pub fn start_upload(
+        &self,
+        user_name: &str,
+        user: &User,
+        storage: &dyn Storage,
+        path: &str,
+        existing: Option<u64>,
+        append: bool,
+    ) -> Result<Charge, Reply> {
+        let owner = owner(user_name, user, path);
+        let charge = Charge {
+            quotas: self.clone(),
+            account: owner.account.clone(),
+            limit: owner.quota_bytes,
+            new_file: existing.is_none(),
+            freed: if append { 0 } else { existing.unwrap_or(0) },
+        };
+        if owner.quota_bytes.is_some() || owner.quota_files.is_some() {
+            let usage = self.usage(&owner, user, storage).map_err(|_| {
+                Reply::new(
+                    ResponseCode::local_error(),
+                    "Requested action aborted: local error in processing.",
+                )
+            })?;
+            if let Some(limit) = owner.quota_files {
+                if charge.new_file && usage.files >= limit {
+                    return Err(exceeded("files"));
+                }
+            }
+            if let Some(limit) = charge.limit {
+                if usage.bytes.saturating_sub(charge.freed) >= limit {
+                    return Err(exceeded("bytes"));
+                }
+            }
+        }
+        if let Some(usage) = self.usage.lock().unwrap().get_mut(&owner.account) {
+            usage.files += charge.new_file as u64;
+            usage.bytes = usage.bytes.saturating_sub(charge.freed);
+        }
+        Ok(charge)
+    }
+}
+
+/// Counts the bytes of an upload in the usage of its user, or group
+#[derive(Debug)]
+pub struct Charge {
+    quotas: Quotas,
+
+    account: Account,
+
+    /// `quota_bytes` of the account when the upload started
+    limit: Option<u64>,
+
+    /// If the upload creates the file
+    new_file: bool,
+
+    /// Bytes of the file that the upload replaces
+    freed: u64,
+}
+
+impl Charge {
+    /// Counts `bytes` more of the upload, returns false (and doesn't count them)
+    /// if they don't fit in the quota
+    pub fn add(&self, bytes: u64) -> bool {
+        let mut usage = self.quotas.usage.lock().unwrap();
+        let usage = match usage.get_mut(&self.account) {
+            Some(usage) => usage,
+            None => return true,
+        };
+        if self.limit.is_some_and(|limit| usage.bytes + bytes > limit) {
+            return false;
+        }
+        usage.bytes += bytes;
+        true
+    }
+
+    /// Undoes the start of an upload that couldn't open the file
+    pub fn cancel(self) {
+        if let Some(usage) = self.quotas.usage.lock().unwrap().get_mut(&self.account) {
+            usage.files = usage.files.saturating_sub(self.new_file as u64);
+            usage.bytes += self.freed;
+        }
+    }
+}
+
+/// `SITE QUOTA`, tells the user how much of its quotas it's using
+pub struct SiteQuota;
+
+impl CommandHandler for SiteQuota {
+    fn handle(&self, session: &Session, _argument: Option<&str>) -> Reply {
+        let (user_name, user) = match (session.user_name(), session.user()) {
+            (Some(user_name), Some(user)) => (user_name, user),
+            _ => return Reply::new(ResponseCode::unauthorized(), "Not logged in."),
+        };
+        let shared = &session.shared;
+        match shared
+            .quotas
+            .report(user_name, &user, shared.storage.as_ref())
+        {
+            Ok(report) => Reply::new(
+                ResponseCode::command_okay(),
+                &format!("Quota of {}: {}.", user_name, report),
+            ),
+            Err(_) => Reply::new(
+                ResponseCode::local_error(),
+                "Requested action aborted: local error in processing.",
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Quotas, Usage};
+    use crate::ftp::storage::{MemoryStorage, Mounts, Storage, StorageBackend};
+    use std::{io::Write, sync::Arc};
+    use user_manage::{Membership, Mount, User};
+
+    fn write(tree: &dyn StorageBackend, path: &str, size: usize) {
+        let mut file = tree.open_write(path, false).unwrap();
+        file.write_all(&vec![0; size]).unwrap();
+        file.finish().unwrap();
+    }
+
+    #[test]
+    fn uploads_count_against_the_quota() {
+        let storage = MemoryStorage::new(0, 0);
+        let user: User = serde_json::from_str(
+            r#"{ "passwd": "", "chroot": "/n", "uid": 1, "quota_bytes": 100, "quota_files": 3 }"#,
+        )
+        .unwrap();
+        let tree = storage.user_root(&user).unwrap();
+        tree.mkdir("/dir").unwrap();
+        write(tree.as_ref(), "/a", 40);
+        write(tree.as_ref(), "/dir/b", 20);
+        assert_eq!(
+            Usage::scan(tree.as_ref(), "/", &[]).unwrap(),
+            Usage {
+                bytes: 60,
+                files: 2
+            }
+        );
+
+        let quotas = Quotas::default();
+        let upload =
+            |existing, append| quotas.start_upload("n", &user, &storage, "/c", existing, append);
+        let charge = upload(None, false).unwrap();
+        assert!(charge.add(30));
+        assert!(!charge.add(11));
+        assert_eq!(
+            quotas.report("n", &user, &storage).unwrap(),
+            "90 of 100 bytes and 3 of 3 files used"
+        );
+        // A fourth file is too many, replacing one isn't
+        assert!(upload(None, false).is_err());
+        let charge = upload(Some(40), false).unwrap();
+        assert!(charge.add(50));
+        // Appending needs free bytes
+        assert!(upload(Some(50), true).is_err());
+        quotas.release(
+            "n",
+            &user,
+            "/c",
+            Usage {
+                bytes: 50,
+                files: 1,
+            },
+        );
+        assert_eq!(
+            quotas.report("n", &user, &storage).unwrap(),
+            "50 of 100 bytes and 2 of 3 files used"
+        );
+    }
+
+    #[test]
+    fn renames_move_the_usage_between_accounts() {
+        let mut storage = Mounts::new(Arc::new(MemoryStorage::new(0, 0)));
+        storage.add_storage("shared", Arc::new(MemoryStorage::new(0, 0)));
+        let mut user: User = serde_json::from_str(
+            r#"{ "passwd": "", "chroot": "/ana", "uid": 1, "quota_bytes": 100 }"#,
+        )
+        .unwrap();
+        user.set_groups(vec![Membership {
+            name: "team".to_string(),
+            mount: Some(Mount {
+                path: "/team".to_string(),
+                dir: "/team".to_string(),
+                storage: Some("shared".to_string()),
+                read_only: false,
+            }),
+            permissions: Vec::new(),
+            quota_bytes: Some(50),
+            quota_files: None,
+        }]);
+        let tree = storage.user_root(&user).unwrap();
+        write(tree.as_ref(), "/big", 60);
+        write(tree.as_ref(), "/small", 30);
+        let quotas = Quotas::default();
+        assert_eq!(
+            quotas.report("ana", &user, &storage).unwrap(),
+            "90 of 100 bytes and 2 files (no limit) used, \
+             group team: 0 of 50 bytes and 0 files (no limit) used"
+        );
+        let rename =
+            |from, to| quotas.start_rename("ana", &user, &storage, tree.as_ref(), from, to);
+
+        // Inside the same account nothing moves, the group can't take 60 bytes
+        assert!(rename("/big", "/big2").unwrap().is_none());
+        assert!(rename("/big", "/team/big").is_err());
+
+        let moved = rename("/small", "/team/small").unwrap().unwrap();
+        assert_eq!(
+            moved,
+            Usage {
+                bytes: 30,
+                files: 1
+            }
+        );
+        tree.rename("/small", "/team/small").unwrap();
+        quotas.renamed("ana", &user, "/small", "/team/small", moved);
+        assert_eq!(
+            quotas.report("ana", &user, &storage).unwrap(),
+            "60 of 100 bytes and 1 files (no limit) used, \
+             group team: 30 of 50 bytes and 1 files (no limit) used"
+        );
+
+        // Back to the user, which has room for it
+        let moved = rename("/team/small", "/small").unwrap().unwrap();
+        tree.rename("/team/small", "/small").unwrap();
+        quotas.renamed("ana", &user, "/team/small", "/small", moved);
+        assert_eq!(
+            quotas.rescan("ana", &user, &storage).unwrap(),
+            quotas.report("ana", &user, &storage).unwrap()
+        );
+    }
+}
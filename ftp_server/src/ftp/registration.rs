@@ -0,0 +1,88 @@
+//! Self-registration of new users.
+//!
+//! A login with a user name that isn't in `users.json` creates the user with the password that
+//! was sent, if the `registration` policy of the configuration lets the client do it:
+//!
+//! * `disabled` (the default): never, the client gets the same 530 as with a wrong password.
+//! * `open`: always.
+//! * `allowlist`: only from the `allowed_addresses`, the rest are answered like `disabled`.
+//! * `invite`: PASS is answered with 332 and the user is created when the client sends one of
+//!   the `invite_codes` with `ACCT <code>`.
+//!
+//! The name of the user is the last component of its `chroot`, so names that could leave `root`
+//! (`..`, `a/b`, ...) are always answered like `disabled`.
+
+use super::config::{RegistrationConfig, RegistrationPolicy};
+use std::net::IpAddr;
+use user_manage::is_valid_user_name;
+
+/// What to do with a login of an unknown user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+    Create,
+    NeedsInvite,
+    Denied,
+}
+
+/// What the policy lets a client that connects from `address` do with `user_name`
+pub fn check(
+    config: &RegistrationConfig,
+    user_name: &str,
+    address: Option<IpAddr>,
+) -> Registration {
+    if !is_valid_user_name(user_name) {
+        return Registration::Denied;
+    }
+    match config.policy {
+        RegistrationPolicy::Disabled => Registration::Denied,
+        RegistrationPolicy::Open => Registration::Create,
+        RegistrationPolicy::Allowlist => match address {
+            Some(address) if config.allowed_addresses.contains(&address) => Registration::Create,
+            _ => Registration::Denied,
+        },
+        RegistrationPolicy::Invite => Registration::NeedsInvite,
+    }
+}
+
+/// If `code` is one of the invite codes, every code is compared in constant time
+pub fn is_invite_code(config: &RegistrationConfig, code: &str) -> bool {
+    config.invite_codes.iter().fold(false, |found, invite| {
+        constant_time_eq(invite.as_bytes(), code.as_bytes()) | found
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check, is_invite_code, Registration};
+    use crate::ftp::config::{RegistrationConfig, RegistrationPolicy};
+
+    #[test]
+    fn registration_policies() {
+        let mut config = RegistrationConfig::default();
+        let address = "10.0.0.1".parse().ok();
+        assert_eq!(check(&config, "new", address), Registration::Denied);
+        config.policy = RegistrationPolicy::Allowlist;
+        assert_eq!(check(&config, "new", address), Registration::Denied);
+        config.allowed_addresses.push("10.0.0.1".parse().unwrap());
+        assert_eq!(check(&config, "new", address), Registration::Create);
+        assert_eq!(check(&config, "new", None), Registration::Denied);
+        assert_eq!(check(&config, "..", address), Registration::Denied);
+        config.policy = RegistrationPolicy::Invite;
+        config.invite_codes.push("welcome-42".to_string());
+        assert_eq!(check(&config, "new", address), Registration::NeedsInvite);
+        assert_eq!(check(&config, "a/../..", address), Registration::Denied);
+        config.policy = RegistrationPolicy::Open;
+        assert_eq!(check(&config, "new", address), Registration::Create);
+        assert_eq!(check(&config, ".", address), Registration::Denied);
+        assert!(is_invite_code(&config, "welcome-42"));
+        assert!(!is_invite_code(&config, "welcome-43"));
+        assert!(!is_invite_code(&config, ""));
+    }
+}
@@ -87,6 +87,14 @@ impl ResponseCode {
         )
     }
 
+    pub fn need_account() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::PositiveIntermediate,
+            CodeSecond::AuthenticationAndAccounting,
+            2,
+        )
+    }
+
     pub fn passive_ok() -> ResponseCode {
         ResponseCode::new_from_enums(CodeFirst::Positive, CodeSecond::Connections, 7)
     }
@@ -111,6 +119,30 @@ impl ResponseCode {
         )
     }
 
+    pub fn local_error() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::TransientNegativeCompletion,
+            CodeSecond::FileSystem,
+            1,
+        )
+    }
+
+    pub fn insufficient_storage() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::TransientNegativeCompletion,
+            CodeSecond::FileSystem,
+            2,
+        )
+    }
+
+    pub fn exceeded_storage_allocation() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::PermanentNegativeCompletion,
+            CodeSecond::FileSystem,
+            2,
+        )
+    }
+
     pub fn file_busy() -> ResponseCode {
         ResponseCode::new_from_enums(
             CodeFirst::TransientNegativeCompletion,
@@ -142,4 +174,40 @@ impl ResponseCode {
             5,
         )
     }
+
+    pub fn service_not_available() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::TransientNegativeCompletion,
+            CodeSecond::Connections,
+            1,
+        )
+    }
+
+    pub fn transfer_aborted() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::TransientNegativeCompletion,
+            CodeSecond::Connections,
+            6,
+        )
+    }
+
+    pub fn help_message() -> ResponseCode {
+        ResponseCode::new_from_enums(CodeFirst::Positive, CodeSecond::Information, 4)
+    }
+
+    pub fn command_not_implemented() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::PermanentNegativeCompletion,
+            CodeSecond::Syntax,
+            2,
+        )
+    }
+
+    pub fn parameter_not_implemented() -> ResponseCode {
+        ResponseCode::new_from_enums(
+            CodeFirst::PermanentNegativeCompletion,
+            CodeSecond::Syntax,
+            4,
+        )
+    }
 }
@@ -0,0 +1,288 @@
+//! Hardening of the process with the `sandbox` key of the configuration.
+//!
+//! Once the listeners are bound (so they can be privileged ports, and the admin socket can be
+//! outside of `var`) and before the threads of the server are started, the process switches to
+//! an unprivileged user, Landlock limits the files that it can reach to the storage of the users,
+//! `etc`, `var` and the configured paths, and a seccomp filter makes the system calls that the
+//! server doesn't need fail with `EPERM`.
+//! The threads that are created later inherit all of it. Every restriction that is asked for
+//! must be applied, otherwise the server doesn't start.
+
+use landlock::{
+    Access, AccessFs, PathBeneath, PathFd, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetStatus, ABI,
+};
+use log::{info, warn};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    error::Error,
+    ffi::CString,
+    fs,
+    path::Path,
+};
+
+/// Newest Landlock ABI that we know, older kernels enforce what they can of it
+/// (V2 is needed to rename files between directories)
+const LANDLOCK_ABI: ABI = ABI::V3;
+
+const DEV_NULL: &str = "/dev/null";
+
+/// System calls of the standard library, mio and the transfers
+const SYSCALLS: &[libc::c_long] = &[
+    // Files
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_openat,
+    libc::SYS_newfstatat,
+    libc::SYS_fstat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_getdents64,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_utimensat,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_ftruncate,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_flock,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_sendfile,
+    libc::SYS_copy_file_range,
+    libc::SYS_getcwd,
+    // Memory
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    // Threads and signals
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_prctl,
+    libc::SYS_getrandom,
+    libc::SYS_getrlimit,
+    libc::SYS_prlimit64,
+    libc::SYS_uname,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    // Time
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    // Network
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_shutdown,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+];
+
+/// System calls that running the commands of the hooks needs, only allowed when there are some
+const HOOK_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_wait4,
+    libc::SYS_waitid,
+    libc::SYS_kill,
+    libc::SYS_setsid,
+    libc::SYS_pidfd_open,
+    libc::SYS_pidfd_send_signal,
+    libc::SYS_setpgid,
+    libc::SYS_getppid,
+    libc::SYS_getpgrp,
+    libc::SYS_umask,
+    libc::SYS_chdir,
+];
+
+/// Older versions of the system calls above that only x86_64 has
+#[cfg(target_arch = "x86_64")]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_chmod,
+    libc::SYS_mkdir,
+    libc::SYS_rmdir,
+    libc::SYS_unlink,
+    libc::SYS_getdents,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_wait,
+    libc::SYS_fork,
+    libc::SYS_vfork,
+    libc::SYS_arch_prctl,
+    libc::SYS_time,
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+/// Switches the whole process to `user` and its primary group, for good
+pub fn drop_privileges(user: &str) -> Result<(), Box<dyn Error>> {
+    let name = CString::new(user)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("the user {} doesn't exist", user).into());
+    }
+    let (uid, gid) = unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) };
+    if unsafe { libc::getuid() == uid && libc::geteuid() == uid } {
+        info!("[SANDBOX] Already running as {}", user);
+        return Ok(());
+    }
+    // glibc changes the ids of every thread of the process
+    unsafe {
+        if libc::setgroups(1, &gid) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+        {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    if unsafe { libc::getuid() != uid || libc::geteuid() != uid || libc::getegid() != gid } {
+        return Err("the ids of the process didn't change".into());
+    }
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err("the privileges can be regained".into());
+    }
+    info!("[SANDBOX] Running as {} (uid {}, gid {})", user, uid, gid);
+    Ok(())
+}
+
+/// Restricts the files that the process can reach to `write_paths` and `read_paths`.
+/// The write paths must exist, the read paths that don't are skipped
+pub fn landlock(write_paths: &[String], read_paths: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(LANDLOCK_ABI))?
+        .create()?;
+    let read_paths: Vec<&String> = read_paths
+        .iter()
+        .filter(|path| {
+            let exists = Path::new(path).exists();
+            if !exists {
+                warn!("[SANDBOX] {} doesn't exist, it can't be read", path);
+            }
+            exists
+        })
+        .collect();
+    // The commands of the hooks get it as their standard streams
+    let dev_null = DEV_NULL.to_string();
+    let rules = write_paths
+        .iter()
+        .chain(std::iter::once(&dev_null))
+        .map(|path| (path, AccessFs::from_all(LANDLOCK_ABI)))
+        .chain(read_paths.iter().map(|path| (*path, AccessFs::from_read(LANDLOCK_ABI))));
+    for (path, access) in rules {
+        let is_dir = fs::metadata(path)
+            .map_err(|err| format!("can't allow {}: {}", path, err))?
+            .is_dir();
+        let access = if is_dir {
+            access
+        } else {
+            access & AccessFs::from_file(LANDLOCK_ABI)
+        };
+        ruleset = ruleset.add_rule(PathBeneath::new(PathFd::new(path)?, access))?;
+    }
+    let status = ruleset.restrict_self()?;
+    if status.ruleset == RulesetStatus::NotEnforced {
+        return Err("the kernel doesn't support Landlock".into());
+    }
+    info!(
+        "[SANDBOX] Landlock restricts the files to {:?} and {:?} ({:?})",
+        write_paths, read_paths, status.ruleset
+    );
+    Ok(())
+}
+
+/// Makes every system call that the server doesn't need fail with `EPERM`, in every thread.
+/// The ones of the commands of the hooks are only allowed with `hook_commands`
+pub fn seccomp(hook_commands: bool) -> Result<(), Box<dyn Error>> {
+    let hook_syscalls = if hook_commands { HOOK_SYSCALLS } else { &[] };
+    let rules: BTreeMap<i64, Vec<_>> = SYSCALLS
+        .iter()
+        .chain(LEGACY_SYSCALLS)
+        .chain(hook_syscalls)
+        .map(|syscall| (*syscall, Vec::new()))
+        .collect();
+    let allowed = rules.len();
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+        std::env::consts::ARCH.try_into()?,
+    )?;
+    let program: BpfProgram = filter.try_into()?;
+    seccompiler::apply_filter_all_threads(&program)?;
+    info!("[SANDBOX] Seccomp allows {} system calls", allowed);
+    Ok(())
+}
+
+/// Creates the directory if it's missing, so it can be given to Landlock
+pub fn prepare_dir(dir: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(dir).exists() {
+        fs::create_dir_all(dir).map_err(|err| format!("can't create {}: {}", dir, err))?;
+    }
+    Ok(())
+}
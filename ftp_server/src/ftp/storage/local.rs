@@ -0,0 +1,431 @@
+//! Files of the local disk.
+//!
+//! Paths are never joined to the root and opened as a whole: the directories are opened one by
+//! one from the root with `openat` and `O_NOFOLLOW`, so a symbolic link that is swapped in while
+//! a path is resolved can't take it out of the root. What is done with the links that are found
+//! depends on the [`SymlinkPolicy`], by default they are refused.
+
+use super::{mount::copy_tree, DirEntry, FileReader, FileWriter, Metadata, Storage, StorageBackend};
+use crate::ftp::config::SymlinkPolicy;
+use std::{
+    collections::VecDeque,
+    ffi::{CStr, CString},
+    fs::{self, File},
+    io::{self, ErrorKind},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use user_manage::User;
+
+/// Links that can be followed while a path is resolved, like `MAXSYMLINKS` of Linux
+const MAX_LINKS: usize = 40;
+
+/// Keeps every user in its `chroot` directory of the local disk, it's created when the user logs
+/// in (the directories of the mounts must exist)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalDisk {
+    symlinks: SymlinkPolicy,
+}
+
+impl LocalDisk {
+    pub fn new(symlinks: SymlinkPolicy) -> Self {
+        Self { symlinks }
+    }
+}
+
+impl Storage for LocalDisk {
+    fn root(&self, dir: &str) -> io::Result<Box<dyn StorageBackend>> {
+        Ok(Box::new(LocalStorage::new(dir, self.symlinks)?))
+    }
+
+    fn create_user_root(&self, user: &User) -> io::Result<()> {
+        fs::create_dir_all(user.get_chroot())
+    }
+}
+
+/// Directory of the local disk that the virtual paths are relative to
+#[derive(Debug)]
+pub struct LocalStorage {
+    /// Opened with `O_PATH`, every path is resolved from it
+    root: File,
+
+    /// Canonical path of the root, the absolute links are only followed if they are inside it
+    root_path: PathBuf,
+
+    symlinks: SymlinkPolicy,
+}
+
+/// Where a path is, the directory that it resolves to or an entry of a directory,
+/// which is there when the entry doesn't exist yet or isn't followed
+enum Location {
+    Dir(File),
+    Entry(File, CString),
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn cstring(name: &[u8]) -> io::Result<CString> {
+    CString::new(name).map_err(|_| io::Error::new(ErrorKind::InvalidInput, "invalid file name"))
+}
+
+/// Opens `name` in `dir`, if it's a symbolic link it fails or, with `O_PATH`, opens the link
+fn openat(dir: &File, name: &CStr, flags: libc::c_int) -> io::Result<File> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, 0o666) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn readlinkat(dir: &File, name: &CStr) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; libc::PATH_MAX as usize];
+    let len = unsafe {
+        libc::readlinkat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            buffer.as_mut_ptr() as *mut libc::c_char,
+            buffer.len(),
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buffer.truncate(len as usize);
+    Ok(buffer)
+}
+
+/// Names of the entries of a directory opened with `O_DIRECTORY`
+fn read_dir(dir: &File) -> io::Result<Vec<CString>> {
+    let fd = unsafe { libc::dup(dir.as_raw_fd()) };
+    check(fd)?;
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(err);
+    }
+    let mut names = Vec::new();
+    let result = loop {
+        unsafe { *libc::__errno_location() = 0 };
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            let err = io::Error::last_os_error();
+            break if err.raw_os_error() == Some(0) { Ok(()) } else { Err(err) };
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name.to_bytes() != b"." && name.to_bytes() != b".." {
+            names.push(name.to_owned());
+        }
+    };
+    unsafe { libc::closedir(stream) };
+    result.map(|_| names)
+}
+
+/// Removes a directory of `dir` with everything inside, without following links
+fn remove_tree(dir: &File, name: &CStr) -> io::Result<()> {
+    let inner = openat(dir, name, libc::O_RDONLY | libc::O_DIRECTORY)?;
+    for entry in read_dir(&inner)? {
+        if openat(&inner, &entry, libc::O_PATH)?.metadata()?.is_dir() {
+            remove_tree(&inner, &entry)?;
+        } else {
+            check(unsafe { libc::unlinkat(inner.as_raw_fd(), entry.as_ptr(), 0) })?;
+        }
+    }
+    check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) })
+}
+
+fn symlink_denied() -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, "symbolic links aren't allowed")
+}
+
+fn outside_root() -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, "the path is outside of the root")
+}
+
+impl LocalStorage {
+    pub fn new<P: AsRef<Path>>(root: P, symlinks: SymlinkPolicy) -> io::Result<Self> {
+        let root_path = root.as_ref().canonicalize()?;
+        let path = cstring(root_path.as_os_str().as_bytes())?;
+        let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+        let fd = unsafe { libc::open(path.as_ptr(), flags) };
+        check(fd)?;
+        Ok(Self {
+            root: unsafe { File::from_raw_fd(fd) },
+            root_path,
+            symlinks,
+        })
+    }
+
+    /// Opens the directories of a virtual path one by one. The links that are found are followed
+    /// if the policy allows it, and the last component too if `follow` is set
+    fn locate(&self, path: &str, follow: bool) -> io::Result<Location> {
+        let mut pending: VecDeque<Vec<u8>> = path
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(|part| part.as_bytes().to_vec())
+            .collect();
+        let mut dirs = vec![self.root.try_clone()?];
+        let mut links = 0;
+        while let Some(part) = pending.pop_front() {
+            match part.as_slice() {
+                b"." => continue,
+                b".." => {
+                    if dirs.len() == 1 {
+                        return Err(outside_root());
+                    }
+                    dirs.pop();
+                    continue;
+                }
+                _ => {}
+            }
+            let name = cstring(&part)?;
+            let dir = dirs.last().expect("the root is never removed");
+            let last = pending.is_empty();
+            if last && !follow {
+                let dir = dirs.pop().expect("the root is never removed");
+                return Ok(Location::Entry(dir, name));
+            }
+            let file = match openat(dir, &name, libc::O_PATH) {
+                Ok(file) => file,
+                Err(err) if last && err.kind() == ErrorKind::NotFound => {
+                    let dir = dirs.pop().expect("the root is never removed");
+                    return Ok(Location::Entry(dir, name));
+                }
+                Err(err) => return Err(err),
+            };
+            let metadata = file.metadata()?;
+            if metadata.file_type().is_symlink() {
+                if self.symlinks == SymlinkPolicy::Deny {
+                    return Err(symlink_denied());
+                }
+                links += 1;
+                if links > MAX_LINKS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = readlinkat(dir, &name)?;
+                let target = if target.starts_with(b"/") {
+                    // Absolute links only work if they point inside the root
+                    let target = Path::new(std::ffi::OsStr::from_bytes(&target));
+                    let inside = target.strip_prefix(&self.root_path).map_err(|_| outside_root())?;
+                    dirs.truncate(1);
+                    inside.as_os_str().as_bytes().to_vec()
+                } else {
+                    target
+                };
+                for part in target.split(|byte| *byte == b'/').rev() {
+                    if !part.is_empty() {
+                        pending.push_front(part.to_vec());
+                    }
+                }
+                continue;
+            }
+            if last {
+                let dir = dirs.pop().expect("the root is never removed");
+                return Ok(Location::Entry(dir, name));
+            }
+            if !metadata.is_dir() {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+            }
+            dirs.push(file);
+        }
+        Ok(Location::Dir(dirs.pop().expect("the root is never removed")))
+    }
+
+    /// Opens what a path resolved to, `flags` are the ones of `open`
+    fn open(&self, location: &Location, flags: libc::c_int) -> io::Result<File> {
+        match location {
+            Location::Dir(dir) => openat(dir, &cstring(b".")?, flags),
+            Location::Entry(dir, name) => openat(dir, name, flags),
+        }
+    }
+
+    /// Directory and name of an entry that is going to be created, removed or renamed
+    fn entry(&self, path: &str) -> io::Result<(File, CString)> {
+        match self.locate(path, false)? {
+            Location::Entry(dir, name) => Ok((dir, name)),
+            Location::Dir(_) => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "the root can't be changed",
+            )),
+        }
+    }
+}
+
+fn metadata(metadata: fs::Metadata) -> Metadata {
+    Metadata {
+        is_dir: metadata.is_dir(),
+        size: if metadata.is_dir() { 0 } else { metadata.len() },
+        modified: metadata.modified().ok(),
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn FileReader>> {
+        let location = self.locate(path, true)?;
+        // Non blocking so a FIFO can't hang the worker, it doesn't change regular files
+        let file = self.open(&location, libc::O_RDONLY | libc::O_NONBLOCK)?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "it's not a file"));
+        }
+        Ok(Box::new(file))
+    }
+
+    fn open_write(&self, path: &str, append: bool) -> io::Result<Box<dyn FileWriter>> {
+        let (dir, name) = match self.locate(path, append)? {
+            Location::Entry(dir, name) => (dir, name),
+            Location::Dir(_) => {
+                return Err(io::Error::new(ErrorKind::InvalidInput, "it's a directory"))
+            }
+        };
+        let flags = if append {
+            libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND
+        } else {
+            // A new file, so it can't be a link to another one
+            unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) };
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL
+        };
+        let file = openat(&dir, &name, flags)?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "it's not a file"));
+        }
+        Ok(Box::new(file))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let location = self.locate(path, true)?;
+        let dir = self.open(&location, libc::O_RDONLY | libc::O_DIRECTORY)?;
+        let mut entries = Vec::new();
+        for name in read_dir(&dir)? {
+            let entry_name = String::from_utf8_lossy(name.to_bytes()).to_string();
+            let link = openat(&dir, &name, libc::O_PATH)?.metadata()?;
+            let entry_metadata = if link.file_type().is_symlink()
+                && self.symlinks == SymlinkPolicy::Follow
+            {
+                // The links that go nowhere (or outside the root) aren't listed
+                match self.stat(&super::join(path, &entry_name)) {
+                    Ok(entry_metadata) => entry_metadata,
+                    Err(_) => continue,
+                }
+            } else {
+                metadata(link)
+            };
+            entries.push(DirEntry {
+                name: entry_name,
+                metadata: entry_metadata,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let location = self.locate(path, true)?;
+        self.open(&location, libc::O_PATH)?.metadata().map(metadata)
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        let (dir, name) = self.entry(path)?;
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o777) })
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let (dir, name) = self.entry(path)?;
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        let (dir, name) = self.entry(path)?;
+        remove_tree(&dir, &name)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from_dir, from_name) = self.entry(from)?;
+        let (to_dir, to_name) = self.entry(to)?;
+        if openat(&to_dir, &to_name, libc::O_PATH).is_ok() {
+            return Err(io::Error::from(ErrorKind::AlreadyExists));
+        }
+        let renamed = check(unsafe {
+            libc::renameat(
+                from_dir.as_raw_fd(),
+                from_name.as_ptr(),
+                to_dir.as_raw_fd(),
+                to_name.as_ptr(),
+            )
+        });
+        match renamed {
+            // Another file system is mounted inside the root
+            Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+                copy_tree(self, from, self, to)?;
+                if self.stat(from)?.is_dir {
+                    self.remove_dir(from)
+                } else {
+                    self.remove_file(from)
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn set_mtime(&self, path: &str, modified: SystemTime) -> io::Result<()> {
+        let location = self.locate(path, true)?;
+        self.open(&location, libc::O_RDONLY | libc::O_NONBLOCK)?
+            .set_modified(modified)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LocalStorage;
+    use crate::ftp::{config::SymlinkPolicy, storage::StorageBackend};
+    use std::{fs, io::Write, os::unix::fs::symlink};
+
+    #[test]
+    fn local_storage_stays_in_root() {
+        let root = std::env::temp_dir().join(format!("ftp_local_storage_{}", std::process::id()));
+        fs::create_dir_all(root.join("docs")).unwrap();
+        symlink("/etc", root.join("etc")).unwrap();
+        symlink("docs", root.join("link")).unwrap();
+        symlink(root.join("docs"), root.join("absolute")).unwrap();
+        let storage = LocalStorage::new(&root, SymlinkPolicy::Deny).unwrap();
+
+        let mut file = storage.open_write("/docs/a.txt", false).unwrap();
+        file.write_all(b"hello").unwrap();
+        file.finish().unwrap();
+        assert_eq!(storage.stat("/docs/a.txt").unwrap().size, 5);
+        let names: Vec<String> = storage
+            .list("/docs")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, vec!["a.txt"]);
+        assert!(storage.stat("/link/a.txt").is_err());
+        storage.rename("/docs/a.txt", "/b.txt").unwrap();
+        assert!(storage.stat("/docs/a.txt").is_err());
+        assert!(storage.open_read("/etc/passwd").is_err());
+        assert!(storage.open_write("/etc/evil", false).is_err());
+        assert!(storage.remove_dir("/").is_err());
+
+        let storage = LocalStorage::new(&root, SymlinkPolicy::Follow).unwrap();
+        storage.rename("/b.txt", "/link/a.txt").unwrap();
+        assert_eq!(storage.stat("/link/a.txt").unwrap().size, 5);
+        assert_eq!(storage.stat("/absolute/a.txt").unwrap().size, 5);
+        assert!(storage.open_read("/etc/passwd").is_err());
+        assert!(storage.open_write("/etc/evil", false).is_err());
+        // Removing a link doesn't touch what it points to
+        storage.remove_file("/link").unwrap();
+        assert!(storage.stat("/docs/a.txt").is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+use super::{join, parent, DirEntry, FileReader, FileWriter, Metadata, Storage, StorageBackend};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, ErrorKind, Write},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+enum Node {
+    File {
+        data: Arc<Vec<u8>>,
+        modified: SystemTime,
+    },
+    Dir {
+        entries: BTreeMap<String, Node>,
+        modified: SystemTime,
+    },
+}
+
+impl Node {
+    fn dir() -> Self {
+        Node::Dir {
+            entries: BTreeMap::new(),
+            modified: SystemTime::now(),
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::File { data, modified } => Metadata {
+                is_dir: false,
+                size: data.len() as u64,
+                modified: Some(*modified),
+            },
+            Node::Dir { modified, .. } => Metadata {
+                is_dir: true,
+                size: 0,
+                modified: Some(*modified),
+            },
+        }
+    }
+
+    /// Bytes of the files inside
+    fn size(&self) -> u64 {
+        match self {
+            Node::File { data, .. } => data.len() as u64,
+            Node::Dir { entries, .. } => entries.values().map(Node::size).sum(),
+        }
+    }
+
+    fn get(&self, path: &str) -> io::Result<&Node> {
+        let mut node = self;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = match node {
+                Node::Dir { entries, .. } => entries.get(name).ok_or_else(not_found)?,
+                Node::File { .. } => return Err(not_found()),
+            };
+        }
+        Ok(node)
+    }
+
+    fn get_mut(&mut self, path: &str) -> io::Result<&mut Node> {
+        let mut node = self;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            node = match node {
+                Node::Dir { entries, .. } => entries.get_mut(name).ok_or_else(not_found)?,
+                Node::File { .. } => return Err(not_found()),
+            };
+        }
+        Ok(node)
+    }
+
+    /// Entries of the directory that contains `path` and the name of `path` in it
+    fn parent_entries<'a>(
+        &mut self,
+        path: &'a str,
+    ) -> io::Result<(&mut BTreeMap<String, Node>, &'a str)> {
+        let name = super::file_name(path)
+            .ok_or_else(|| io::Error::new(ErrorKind::PermissionDenied, "it's the root"))?;
+        match self.get_mut(parent(path))? {
+            Node::Dir { entries, modified } => {
+                *modified = SystemTime::now();
+                Ok((entries, name))
+            }
+            Node::File { .. } => Err(not_found()),
+        }
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::from(ErrorKind::NotFound)
+}
+
+fn no_space() -> io::Error {
+    io::Error::new(ErrorKind::StorageFull, "the storage is full")
+}
+
+struct Tree {
+    /// Root directory of every chroot
+    roots: HashMap<String, Node>,
+
+    /// Bytes of all the files
+    used: u64,
+
+    /// Bytes that a file can have, 0 means unlimited
+    max_file_size: u64,
+
+    /// Bytes that all the files can have together, 0 means unlimited
+    max_size: u64,
+}
+
+/// Keeps the files in memory, they are lost when the server stops.
+/// Users with the same `chroot` see the same files, and every `MemoryStorage` is independent
+/// of the others so a test can have its own
+#[derive(Clone)]
+pub struct MemoryStorage {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl MemoryStorage {
+    /// `max_file_size` and `max_size` are in bytes, 0 means unlimited
+    pub fn new(max_file_size: u64, max_size: u64) -> Self {
+        Self {
+            tree: Arc::new(Mutex::new(Tree {
+                roots: HashMap::new(),
+                used: 0,
+                max_file_size,
+                max_size,
+            })),
+        }
+    }
+
+    /// Files of the `chroot`, e.g to fill them before a test
+    pub fn root(&self, chroot: &str) -> MemoryRoot {
+        self.tree
+            .lock()
+            .unwrap()
+            .roots
+            .entry(chroot.to_string())
+            .or_insert_with(Node::dir);
+        MemoryRoot {
+            tree: self.tree.clone(),
+            chroot: chroot.to_string(),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn root(&self, dir: &str) -> io::Result<Box<dyn StorageBackend>> {
+        Ok(Box::new(MemoryStorage::root(self, dir)))
+    }
+}
+
+/// Files of a `chroot` of a `MemoryStorage`
+pub struct MemoryRoot {
+    tree: Arc<Mutex<Tree>>,
+
+    chroot: String,
+}
+
+impl MemoryRoot {
+    fn with_root<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut Node, &mut u64) -> io::Result<T>,
+    {
+        let mut tree = self.tree.lock().unwrap();
+        let tree = &mut *tree;
+        let root = tree.roots.get_mut(&self.chroot).ok_or_else(not_found)?;
+        f(root, &mut tree.used)
+    }
+}
+
+/// A file that is being written, its content is stored when it's flushed or dropped
+struct MemoryFile {
+    tree: Arc<Mutex<Tree>>,
+
+    chroot: String,
+
+    path: String,
+
+    data: Vec<u8>,
+}
+
+impl MemoryFile {
+    fn store(&mut self) -> io::Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+        let root = tree.roots.get_mut(&self.chroot).ok_or_else(not_found)?;
+        let (entries, name) = root.parent_entries(&self.path)?;
+        entries.insert(
+            name.to_string(),
+            Node::File {
+                data: Arc::new(self.data.clone()),
+                modified: SystemTime::now(),
+            },
+        );
+        Ok(())
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut tree = self.tree.lock().unwrap();
+        let size = self.data.len() as u64 + buf.len() as u64;
+        if tree.max_file_size > 0 && size > tree.max_file_size {
+            return Err(io::Error::other("the file is too big"));
+        }
+        if tree.max_size > 0 && tree.used + buf.len() as u64 > tree.max_size {
+            return Err(no_space());
+        }
+        tree.used += buf.len() as u64;
+        drop(tree);
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store()
+    }
+}
+
+impl FileWriter for MemoryFile {}
+
+impl Drop for MemoryFile {
+    /// Like in a disk, what was received of an aborted upload stays there
+    fn drop(&mut self) {
+        let _ = self.store();
+    }
+}
+
+/// Content of a file when it was opened, later changes don't affect it
+struct MemoryReader(Arc<Vec<u8>>);
+
+impl FileReader for MemoryReader {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let start = (offset as usize).min(self.0.len());
+        let read = buf.len().min(self.0.len() - start);
+        buf[..read].copy_from_slice(&self.0[start..start + read]);
+        Ok(read)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.0.len() as u64)
+    }
+}
+
+impl StorageBackend for MemoryRoot {
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn FileReader>> {
+        self.with_root(|root, _| match root.get(path)? {
+            Node::File { data, .. } => Ok(Box::new(MemoryReader(data.clone())) as Box<dyn FileReader>),
+            Node::Dir { .. } => Err(io::Error::new(ErrorKind::InvalidInput, "it's a directory")),
+        })
+    }
+
+    fn open_write(&self, path: &str, append: bool) -> io::Result<Box<dyn FileWriter>> {
+        let data = self.with_root(|root, used| {
+            let (entries, name) = root.parent_entries(path)?;
+            let data = match entries.get(name) {
+                Some(Node::Dir { .. }) => {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, "it's a directory"))
+                }
+                Some(Node::File { data, .. }) if append => data.to_vec(),
+                Some(Node::File { data, .. }) => {
+                    *used = used.saturating_sub(data.len() as u64);
+                    Vec::new()
+                }
+                None => Vec::new(),
+            };
+            entries.insert(
+                name.to_string(),
+                Node::File {
+                    data: Arc::new(data.clone()),
+                    modified: SystemTime::now(),
+                },
+            );
+            Ok(data)
+        })?;
+        Ok(Box::new(MemoryFile {
+            tree: self.tree.clone(),
+            chroot: self.chroot.clone(),
+            path: path.to_string(),
+            data,
+        }))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        self.with_root(|root, _| match root.get(path)? {
+            Node::Dir { entries, .. } => Ok(entries
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    metadata: node.metadata(),
+                })
+                .collect()),
+            Node::File { .. } => Err(io::Error::new(ErrorKind::InvalidInput, "it's a file")),
+        })
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        self.with_root(|root, _| root.get(path).map(Node::metadata))
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.with_root(|root, _| {
+            let (entries, name) = root.parent_entries(path)?;
+            if entries.contains_key(name) {
+                return Err(io::Error::from(ErrorKind::AlreadyExists));
+            }
+            entries.insert(name.to_string(), Node::dir());
+            Ok(())
+        })
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.with_root(|root, used| {
+            let (entries, name) = root.parent_entries(path)?;
+            match entries.get(name) {
+                Some(Node::File { .. }) => {
+                    let removed = entries.remove(name).map(|node| node.size()).unwrap_or(0);
+                    *used = used.saturating_sub(removed);
+                    Ok(())
+                }
+                Some(Node::Dir { .. }) => {
+                    Err(io::Error::new(ErrorKind::InvalidInput, "it's a directory"))
+                }
+                None => Err(not_found()),
+            }
+        })
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        self.with_root(|root, used| {
+            let (entries, name) = root.parent_entries(path)?;
+            match entries.get(name) {
+                Some(Node::Dir { .. }) => {
+                    let removed = entries.remove(name).map(|node| node.size()).unwrap_or(0);
+                    *used = used.saturating_sub(removed);
+                    Ok(())
+                }
+                Some(Node::File { .. }) => {
+                    Err(io::Error::new(ErrorKind::InvalidInput, "it's a file"))
+                }
+                None => Err(not_found()),
+            }
+        })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        if to == from || to.starts_with(&join(from, "")) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "a directory can't be moved inside itself",
+            ));
+        }
+        self.with_root(|root, _| {
+            root.get(parent(to))?;
+            if root.get(to).is_ok() {
+                return Err(io::Error::from(ErrorKind::AlreadyExists));
+            }
+            let (entries, name) = root.parent_entries(from)?;
+            let node = entries.remove(name).ok_or_else(not_found)?;
+            let (entries, name) = root.parent_entries(to)?;
+            entries.insert(name.to_string(), node);
+            Ok(())
+        })
+    }
+
+    fn set_mtime(&self, path: &str, mtime: SystemTime) -> io::Result<()> {
+        self.with_root(|root, _| {
+            match root.get_mut(path)? {
+                Node::File { modified, .. } | Node::Dir { modified, .. } => *modified = mtime,
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MemoryStorage;
+    use crate::ftp::storage::{FileReader, StorageBackend};
+    use std::io::Write;
+
+    fn read(file: Box<dyn FileReader>) -> Vec<u8> {
+        let mut buf = vec![0; file.size().unwrap() as usize];
+        file.read_at(&mut buf, 0).unwrap();
+        buf
+    }
+
+    #[test]
+    fn memory_storage() {
+        let storage = MemoryStorage::new(8, 12);
+        let root = storage.root("./root/user");
+        root.mkdir("/docs").unwrap();
+        let mut file = root.open_write("/docs/a.txt", false).unwrap();
+        file.write_all(b"hello").unwrap();
+        file.finish().unwrap();
+        drop(file);
+        assert_eq!(read(root.open_read("/docs/a.txt").unwrap()), b"hello");
+
+        // Limits of the file and of the whole storage
+        let mut file = root.open_write("/docs/a.txt", true).unwrap();
+        assert!(file.write_all(b" world").is_err());
+        let mut file = root.open_write("/b.txt", false).unwrap();
+        assert!(file.write_all(b"12345678").is_err());
+        drop(file);
+
+        root.rename("/docs", "/papers").unwrap();
+        assert!(root.rename("/papers", "/papers/inside").is_err());
+        assert_eq!(root.list("/papers").unwrap()[0].name, "a.txt");
+        root.remove_dir("/papers").unwrap();
+        assert!(root.stat("/papers/a.txt").is_err());
+
+        // Other storages and chroots don't see these files
+        assert!(storage.root("./root/other").stat("/b.txt").is_err());
+        assert!(MemoryStorage::new(0, 0).root("./root/user").stat("/b.txt").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! Where the files of the users live.
+//!
+//! The handlers never touch the file system, they resolve the paths sent by the client
+//! against the working directory of the session with [`resolve`] and give the resulting
+//! virtual path (`/dir/file.txt`, relative to the root of the user) to the [`StorageBackend`]
+//! of the user. The [`Storage`] of the server builds the backend of every user,
+//! by default it's [`LocalDisk`], which keeps every user inside its `chroot` directory.
+//! The `storage` key of the configuration chooses it, e.g `{ "backend": "memory" }`
+//! for a server that never writes the files of the users to the disk, or `"s3"` for
+//! a bucket of an S3 compatible service. The `mounts` of a user add directories of the disk
+//! or of the `storages` of the configuration to its tree (see [`Mounts`]).
+
+mod local;
+mod memory;
+mod mount;
+mod s3;
+
+pub use local::{LocalDisk, LocalStorage};
+pub use memory::{MemoryRoot, MemoryStorage};
+pub use mount::Mounts;
+pub use s3::{S3Config, S3Storage};
+
+use super::config::StorageConfig;
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::{Component, Path},
+    sync::Arc,
+    time::SystemTime,
+};
+use user_manage::User;
+
+/// What we know about a file or a directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+
+    /// Size in bytes, 0 for directories
+    pub size: u64,
+
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+
+    pub metadata: Metadata,
+}
+
+/// File that is being sent to the client
+pub trait FileReader: Send {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    fn size(&self) -> io::Result<u64>;
+
+    /// The file of the local disk, if it's one, so it can be sent with `sendfile`
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl FileReader for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.metadata().map(|metadata| metadata.len())
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// File that is being received from the client
+pub trait FileWriter: Write + Send {
+    /// Called when the client has sent the whole file, the upload has only succeeded if this does
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl FileWriter for File {}
+
+/// Files and directories of a user, every path is a virtual one returned by [`resolve`]
+pub trait StorageBackend: Send + Sync {
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn FileReader>>;
+
+    /// Creates the file or truncates it, unless `append` is set
+    fn open_write(&self, path: &str, append: bool) -> io::Result<Box<dyn FileWriter>>;
+
+    fn list(&self, path: &str) -> io::Result<Vec<DirEntry>>;
+
+    fn stat(&self, path: &str) -> io::Result<Metadata>;
+
+    fn mkdir(&self, path: &str) -> io::Result<()>;
+
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+
+    /// Removes the directory with everything inside
+    fn remove_dir(&self, path: &str) -> io::Result<()>;
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    fn set_mtime(&self, path: &str, modified: SystemTime) -> io::Result<()>;
+}
+
+/// Builds the storage of the users when they log in or send a command
+pub trait Storage: Send + Sync {
+    /// Files under `dir` (a directory like the `chroot` of the users, or a `dir` of a mount)
+    fn root(&self, dir: &str) -> io::Result<Box<dyn StorageBackend>>;
+
+    fn user_root(&self, user: &User) -> io::Result<Box<dyn StorageBackend>> {
+        self.root(user.get_chroot())
+    }
+
+    /// Creates the `chroot` of the user if the storage needs it, it's called when the user
+    /// logs in instead of every time that its files are used
+    fn create_user_root(&self, _user: &User) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds the storage of the configuration
+pub fn from_config(config: &StorageConfig) -> io::Result<Arc<dyn Storage>> {
+    Ok(match config {
+        StorageConfig::Local { symlinks } => Arc::new(LocalDisk::new(*symlinks)),
+        StorageConfig::Memory {
+            max_file_size,
+            max_size,
+        } => Arc::new(MemoryStorage::new(*max_file_size, *max_size)),
+        StorageConfig::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            prefix,
+            timeout,
+        } => Arc::new(S3Storage::new(S3Config {
+            endpoint: endpoint.clone(),
+            bucket: bucket.clone(),
+            region: region.clone(),
+            access_key: access_key.clone(),
+            secret_key: secret_key.clone(),
+            prefix: prefix.clone(),
+            timeout: *timeout,
+        })?),
+    })
+}
+
+/// Resolves `path` against the working directory `cwd`, `.` and `..` are removed without
+/// looking at the storage and the result never goes above `/`
+pub fn resolve<P: AsRef<Path>>(cwd: &str, path: P) -> String {
+    let path = path.as_ref();
+    let mut parts: Vec<String> = Vec::new();
+    let start = if path.has_root() { None } else { Some(Path::new(cwd)) };
+    for component in start.iter().flat_map(|cwd| cwd.components()).chain(path.components()) {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().to_string()),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Name of the last component of a virtual path, `None` for `/`
+pub fn file_name(path: &str) -> Option<&str> {
+    path.rsplit('/').next().filter(|name| !name.is_empty())
+}
+
+/// Directory that contains a virtual path
+pub fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(slash) => &path[..slash],
+    }
+}
+
+/// Joins a virtual directory with the name of an entry
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{file_name, parent, resolve};
+
+    #[test]
+    fn resolves_virtual_paths() {
+        assert_eq!(resolve("/", "./"), "/");
+        assert_eq!(resolve("/", "docs/a.txt"), "/docs/a.txt");
+        assert_eq!(resolve("/docs", "../../../etc/passwd"), "/etc/passwd");
+        assert_eq!(resolve("/docs", "/other/./b"), "/other/b");
+        assert_eq!(resolve("./docs/../img", "x.png"), "/img/x.png");
+        assert_eq!(file_name("/docs/a.txt"), Some("a.txt"));
+        assert_eq!(file_name("/"), None);
+        assert_eq!(parent("/docs/a.txt"), "/docs");
+        assert_eq!(parent("/a.txt"), "/");
+    }
+}
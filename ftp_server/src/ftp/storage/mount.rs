@@ -0,0 +1,377 @@
+//! Trees of the users made of several storages.
+//!
+//! The `mounts` of a user put a directory of the disk, or of one of the `storages` of the
+//! configuration, at a path of its tree, e.g every user can see a shared `/docs` next to its
+//! own files. The deepest mount that contains a path handles it, listings show the mount
+//! points and renames between mounts copy the files and remove the old ones.
+
+use super::{
+    file_name, from_config, join, parent, DirEntry, FileReader, FileWriter, LocalDisk, Metadata,
+    Storage, StorageBackend,
+};
+use crate::ftp::config::StorageConfig;
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::SystemTime,
+};
+use user_manage::User;
+
+/// Bytes copied at a time when a file is moved between mounts
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Storage of the server with the storages that the mounts can use
+#[derive(Clone)]
+pub struct Mounts {
+    /// Where the `chroot` of the users is
+    storage: Arc<dyn Storage>,
+
+    /// The `storages` of the configuration by name
+    storages: HashMap<String, Arc<dyn Storage>>,
+
+    /// Where the mounts without a `storage` are
+    disk: LocalDisk,
+}
+
+impl Mounts {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            storage,
+            storages: HashMap::new(),
+            disk: LocalDisk::default(),
+        }
+    }
+
+    pub fn from_config(
+        storage: &StorageConfig,
+        storages: &HashMap<String, StorageConfig>,
+    ) -> io::Result<Self> {
+        let mut mounts = Self::new(from_config(storage)?);
+        if let StorageConfig::Local { symlinks } = storage {
+            mounts.disk = LocalDisk::new(*symlinks);
+        }
+        for (name, config) in storages {
+            mounts.add_storage(name, from_config(config)?);
+        }
+        Ok(mounts)
+    }
+
+    /// Replaces the storage of the `chroot` of the users
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.storage = storage;
+    }
+
+    /// Lets the mounts use `storage` with `"storage": "<name>"`
+    pub fn add_storage(&mut self, name: &str, storage: Arc<dyn Storage>) {
+        self.storages.insert(name.to_string(), storage);
+    }
+}
+
+impl Storage for Mounts {
+    fn root(&self, dir: &str) -> io::Result<Box<dyn StorageBackend>> {
+        self.storage.root(dir)
+    }
+
+    /// Only the `chroot` is created, the tree is opened so a mount whose directory doesn't
+    /// exist fails here
+    fn create_user_root(&self, user: &User) -> io::Result<()> {
+        self.storage.create_user_root(user)?;
+        self.user_root(user).map(|_| ())
+    }
+
+    fn user_root(&self, user: &User) -> io::Result<Box<dyn StorageBackend>> {
+        let root = self.storage.user_root(user)?;
+        if user.get_mounts().next().is_none() {
+            return Ok(root);
+        }
+        let mut points = vec![MountPoint {
+            path: "/".to_string(),
+            backend: root,
+            read_only: false,
+        }];
+        for mount in user.get_mounts() {
+            let backend = match &mount.storage {
+                None => self.disk.root(&mount.dir)?,
+                Some(name) => self
+                    .storages
+                    .get(name)
+                    .ok_or_else(|| {
+                        io::Error::new(ErrorKind::NotFound, format!("unknown storage {}", name))
+                    })?
+                    .root(&mount.dir)?,
+            };
+            let path = super::resolve("/", &mount.path);
+            // A mount of `/` replaces the `chroot`
+            points.retain(|point| point.path != path);
+            points.push(MountPoint {
+                path,
+                backend,
+                read_only: mount.read_only,
+            });
+        }
+        // The deepest mount of a path is the first one that contains it
+        points.sort_by_key(|point| std::cmp::Reverse(point.path.len()));
+        Ok(Box::new(MountedTree { points }))
+    }
+}
+
+struct MountPoint {
+    path: String,
+
+    backend: Box<dyn StorageBackend>,
+
+    read_only: bool,
+}
+
+impl MountPoint {
+    /// Path inside the mount of a path of the tree, if it's in the mount
+    fn inner(&self, path: &str) -> Option<String> {
+        if self.path == "/" {
+            return Some(path.to_string());
+        }
+        match path.strip_prefix(&self.path) {
+            Some("") => Some("/".to_string()),
+            Some(rest) if rest.starts_with('/') => Some(rest.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Tree of a user with mounts, every path is sent to the mount that contains it
+struct MountedTree {
+    /// From the deepest mount to `/`
+    points: Vec<MountPoint>,
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, "the directory is read only")
+}
+
+fn is_under(path: &str, dir: &str) -> bool {
+    path != dir && path.starts_with(&join(dir, ""))
+}
+
+impl MountedTree {
+    fn route(&self, path: &str) -> (&MountPoint, String) {
+        self.points
+            .iter()
+            .find_map(|point| point.inner(path).map(|inner| (point, inner)))
+            .expect("`/` contains every path")
+    }
+
+    /// Mount of a path that is going to be changed, it can't be a mount point or contain one
+    fn route_write(&self, path: &str) -> io::Result<(&MountPoint, String)> {
+        let (point, inner) = self.route(path);
+        if point.read_only {
+            return Err(read_only());
+        }
+        let is_mount = |point: &MountPoint| point.path == path || is_under(&point.path, path);
+        if self.points.iter().any(is_mount) {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "the directory is or contains a mount point",
+            ));
+        }
+        Ok((point, inner))
+    }
+
+    /// If there are mounts under the directory, so it exists even if its storage doesn't have it
+    fn has_mounts_under(&self, dir: &str) -> bool {
+        self.points.iter().any(|point| is_under(&point.path, dir))
+    }
+}
+
+/// Copies a file or a directory with everything inside from a storage to another one
+pub(super) fn copy_tree(
+    from: &dyn StorageBackend,
+    from_path: &str,
+    to: &dyn StorageBackend,
+    to_path: &str,
+) -> io::Result<()> {
+    let metadata = from.stat(from_path)?;
+    if metadata.is_dir {
+        to.mkdir(to_path)?;
+        for entry in from.list(from_path)? {
+            copy_tree(
+                from,
+                &join(from_path, &entry.name),
+                to,
+                &join(to_path, &entry.name),
+            )?;
+        }
+        return Ok(());
+    }
+    let reader = from.open_read(from_path)?;
+    let mut writer = to.open_write(to_path, false)?;
+    copy_file(reader.as_ref(), writer.as_mut())?;
+    if let Some(modified) = metadata.modified {
+        // Not every storage can keep it
+        let _ = to.set_mtime(to_path, modified);
+    }
+    Ok(())
+}
+
+fn copy_file(reader: &dyn FileReader, writer: &mut dyn FileWriter) -> io::Result<()> {
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut offset = 0;
+    loop {
+        let read = reader.read_at(&mut buffer, offset)?;
+        if read == 0 {
+            return writer.finish();
+        }
+        writer.write_all(&buffer[..read])?;
+        offset += read as u64;
+    }
+}
+
+impl StorageBackend for MountedTree {
+    fn open_read(&self, path: &str) -> io::Result<Box<dyn FileReader>> {
+        let (point, inner) = self.route(path);
+        point.backend.open_read(&inner)
+    }
+
+    fn open_write(&self, path: &str, append: bool) -> io::Result<Box<dyn FileWriter>> {
+        let (point, inner) = self.route_write(path)?;
+        point.backend.open_write(&inner, append)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let (point, inner) = self.route(path);
+        let mut entries = match point.backend.list(&inner) {
+            Err(err) if err.kind() == ErrorKind::NotFound && self.has_mounts_under(path) => {
+                Vec::new()
+            }
+            result => result?,
+        };
+        // The mount points (or the directories that lead to them) hide what is in their place
+        for mount in self.points.iter().filter(|mount| is_under(&mount.path, path)) {
+            let rest = &mount.path[join(path, "").len()..];
+            let name = rest.split('/').next().unwrap_or(rest);
+            let metadata = self.stat(&join(path, name))?;
+            entries.retain(|entry| entry.name != name);
+            entries.push(DirEntry {
+                name: name.to_string(),
+                metadata,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let (point, inner) = self.route(path);
+        match point.backend.stat(&inner) {
+            Err(err) if err.kind() == ErrorKind::NotFound && self.has_mounts_under(path) => {
+                Ok(Metadata {
+                    is_dir: true,
+                    size: 0,
+                    modified: None,
+                })
+            }
+            result => result,
+        }
+    }
+
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        let (point, inner) = self.route_write(path)?;
+        point.backend.mkdir(&inner)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let (point, inner) = self.route_write(path)?;
+        point.backend.remove_file(&inner)
+    }
+
+    fn remove_dir(&self, path: &str) -> io::Result<()> {
+        let (point, inner) = self.route_write(path)?;
+        point.backend.remove_dir(&inner)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from_point, from_inner) = self.route_write(from)?;
+        let (to_point, to_inner) = self.route_write(to)?;
+        if std::ptr::eq(from_point, to_point) {
+            return from_point.backend.rename(&from_inner, &to_inner);
+        }
+        if file_name(&to_inner).is_none() || to_point.backend.stat(&to_inner).is_ok() {
+            return Err(io::Error::from(ErrorKind::AlreadyExists));
+        }
+        if !to_point.backend.stat(parent(&to_inner))?.is_dir {
+            return Err(io::Error::from(ErrorKind::NotFound));
+        }
+        let from_backend = from_point.backend.as_ref();
+        if let Err(err) = copy_tree(from_backend, &from_inner, to_point.backend.as_ref(), &to_inner)
+        {
+            // Don't leave half of it in the destination
+            let _ = match to_point.backend.stat(&to_inner) {
+                Ok(metadata) if metadata.is_dir => to_point.backend.remove_dir(&to_inner),
+                _ => to_point.backend.remove_file(&to_inner),
+            };
+            return Err(err);
+        }
+        if from_backend.stat(&from_inner)?.is_dir {
+            from_backend.remove_dir(&from_inner)
+        } else {
+            from_backend.remove_file(&from_inner)
+        }
+    }
+
+    fn set_mtime(&self, path: &str, modified: SystemTime) -> io::Result<()> {
+        let (point, inner) = self.route(path);
+        if point.read_only {
+            return Err(read_only());
+        }
+        point.backend.set_mtime(&inner, modified)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MountPoint, MountedTree};
+    use crate::ftp::storage::{MemoryStorage, StorageBackend};
+    use std::io::{ErrorKind, Write};
+
+    fn point(storage: &MemoryStorage, path: &str, read_only: bool) -> MountPoint {
+        MountPoint {
+            path: path.to_string(),
+            backend: Box::new(storage.root(path)),
+            read_only,
+        }
+    }
+
+    #[test]
+    fn mounts_compose_the_tree() {
+        let storage = MemoryStorage::new(0, 0);
+        let docs = storage.root("/shared/docs");
+        docs.open_write("/manual.txt", false)
+            .unwrap()
+            .write_all(b"read me")
+            .unwrap();
+        let tree = MountedTree {
+            points: vec![
+                point(&storage, "/shared/docs", true),
+                point(&storage, "/incoming", false),
+                point(&storage, "/", false),
+            ],
+        };
+
+        let mut names: Vec<String> = tree.list("/").unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        assert_eq!(names, vec!["incoming", "shared"]);
+        assert!(tree.stat("/shared").unwrap().is_dir);
+        assert_eq!(tree.stat("/shared/docs/manual.txt").unwrap().size, 7);
+        assert_eq!(
+            tree.remove_file("/shared/docs/manual.txt").unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert!(tree.remove_dir("/incoming").is_err());
+
+        let mut file = tree.open_write("/a.txt", false).unwrap();
+        file.write_all(b"hello").unwrap();
+        file.finish().unwrap();
+        tree.rename("/a.txt", "/incoming/b.txt").unwrap();
+        assert!(tree.stat("/a.txt").is_err());
+        assert_eq!(tree.stat("/incoming/b.txt").unwrap().size, 5);
+        assert!(tree.rename("/incoming/b.txt", "/shared/docs/b.txt").is_err());
+    }
+}
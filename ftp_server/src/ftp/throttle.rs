@@ -0,0 +1,194 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Smallest chunk that we wait for when a bucket is empty, so slow transfers
+/// don't wake up the poll for every few bytes
+const MIN_CHUNK: u64 = 4096;
+
+/// Token bucket where every token is a byte, it's refilled at `rate` bytes per second
+/// and it can store at most a second of transfer
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// Changes the rate without losing the tokens that are already stored
+    fn set_rate(&mut self, rate: u64) {
+        self.refill(Instant::now());
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    /// Bytes that can be transferred right now
+    fn available(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.tokens as u64
+    }
+
+    /// Time until there are enough tokens to transfer a chunk
+    fn wait_time(&self) -> Duration {
+        let chunk = MIN_CHUNK.min(self.rate) as f64;
+        let missing = (chunk - self.tokens).max(0.0);
+        Duration::from_secs_f64(missing / self.rate as f64)
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+/// Buckets that apply to a single transfer (e.g global, user and session),
+/// the transfer can only move the bytes that every bucket allows
+#[derive(Clone, Default, Debug)]
+pub struct RateLimiter {
+    buckets: Vec<SharedBucket>,
+}
+
+impl RateLimiter {
+    /// Returns how many bytes (at most `wanted`) can be transferred now,
+    /// or how long the transfer should wait if some bucket is empty
+    pub fn allowance(&self, wanted: usize) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let mut allowed = wanted;
+        let mut wait = Duration::from_secs(0);
+        for bucket in self.buckets.iter() {
+            let mut bucket = bucket.lock().unwrap();
+            let available = bucket.available(now);
+            if available == 0 {
+                wait = wait.max(bucket.wait_time());
+            }
+            allowed = allowed.min(available as usize);
+        }
+        if allowed == 0 && wanted != 0 {
+            return Err(wait.max(Duration::from_millis(1)));
+        }
+        Ok(allowed)
+    }
+
+    /// Takes the transferred bytes from every bucket
+    pub fn consume(&self, bytes: usize) {
+        for bucket in self.buckets.iter() {
+            bucket.lock().unwrap().consume(bytes);
+        }
+    }
+}
+
+/// Direction of the transfer from the point of view of the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// Key of a bucket, the user name is `None` for the global buckets
+type BucketKey = (Option<String>, Direction);
+
+/// Owner of the buckets that outlive a transfer (global and per user)
+#[derive(Clone, Default)]
+pub struct Throttle {
+    buckets: Arc<Mutex<HashMap<BucketKey, SharedBucket>>>,
+}
+
+impl Throttle {
+    /// Gets the bucket of the key updating its rate, `None` when the rate is 0 (unlimited)
+    fn bucket(
+        &self,
+        user_name: Option<&str>,
+        direction: Direction,
+        rate: u64,
+    ) -> Option<SharedBucket> {
+        let key = (user_name.map(|user_name| user_name.to_string()), direction);
+        let mut buckets = self.buckets.lock().unwrap();
+        if rate == 0 {
+            buckets.remove(&key);
+            return None;
+        }
+        let bucket = buckets
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(rate))));
+        let mut locked = bucket.lock().unwrap();
+        if locked.rate != rate {
+            locked.set_rate(rate);
+        }
+        drop(locked);
+        Some(bucket.clone())
+    }
+
+    /// Builds the limiter of a new transfer, rates are in bytes per second and 0 means unlimited
+    pub fn limiter(
+        &self,
+        user_name: &str,
+        direction: Direction,
+        global_rate: u64,
+        user_rate: u64,
+        session_rate: u64,
+    ) -> RateLimiter {
+        let mut buckets = Vec::new();
+        buckets.extend(self.bucket(None, direction, global_rate));
+        buckets.extend(self.bucket(Some(user_name), direction, user_rate));
+        if session_rate != 0 {
+            buckets.push(Arc::new(Mutex::new(TokenBucket::new(session_rate))));
+        }
+        RateLimiter { buckets }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Direction, RateLimiter, Throttle};
+
+    #[test]
+    fn unlimited_transfer() {
+        let limiter = RateLimiter::default();
+        assert_eq!(limiter.allowance(10024), Ok(10024));
+    }
+
+    #[test]
+    fn limits_transfer() {
+        let throttle = Throttle::default();
+        let limiter = throttle.limiter("user", Direction::Download, 0, 1000, 0);
+        assert_eq!(limiter.allowance(10024), Ok(1000));
+        limiter.consume(1000);
+        let wait = limiter
+            .allowance(10024)
+            .expect_err("bucket should be empty");
+        assert!(wait.as_millis() > 500);
+    }
+
+    #[test]
+    fn shares_user_buckets() {
+        let throttle = Throttle::default();
+        let first = throttle.limiter("user", Direction::Upload, 0, 1000, 0);
+        let second = throttle.limiter("user", Direction::Upload, 0, 1000, 5000);
+        first.consume(800);
+        assert!(second.allowance(10024).unwrap() <= 201);
+        let other_direction = throttle.limiter("user", Direction::Download, 0, 1000, 0);
+        assert_eq!(other_direction.allowance(10024), Ok(1000));
+    }
+}
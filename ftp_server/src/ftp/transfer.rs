@@ -0,0 +1,66 @@
+use super::events::{self, Event};
+use super::hooks::{self, HookEvent};
+use super::metrics;
+use super::throttle::Direction;
+use super::xferlog;
+use std::time::Instant;
+
+/// Bookkeeping of a RETR or STOR so it can be reported when it ends
+#[derive(Debug)]
+pub struct Transfer {
+    pub direction: Direction,
+
+    /// Path of the file as the user sees it, relative to its chroot
+    pub path: String,
+
+    /// Bytes moved through the data connection
+    pub bytes: u64,
+
+    started: Instant,
+
+    finished: bool,
+}
+
+impl Transfer {
+    pub fn start(direction: Direction, path: String) -> Self {
+        events::emit(&Event::TransferStart {
+            direction,
+            path: &path,
+        });
+        Self {
+            direction,
+            path,
+            bytes: 0,
+            started: Instant::now(),
+            finished: false,
+        }
+    }
+
+    /// Reports the end of the transfer, only the first call does something
+    /// so every close path can call it without checking the others.
+    /// Returns false if it had already finished
+    pub fn finish(&mut self, success: bool) -> bool {
+        if self.finished {
+            return false;
+        }
+        self.finished = true;
+        let duration = self.started.elapsed();
+        events::emit(&Event::TransferEnd {
+            direction: self.direction,
+            path: &self.path,
+            bytes: self.bytes,
+            duration_ms: duration.as_millis() as u64,
+            success,
+        });
+        xferlog::write(&self.path, self.direction, self.bytes, duration, success);
+        metrics::transfer(self.direction, duration);
+        if success {
+            let event = match self.direction {
+                Direction::Upload => HookEvent::Upload,
+                Direction::Download => HookEvent::Download,
+            };
+            hooks::trigger(event, Some(&self.path), None, Some(self.bytes));
+        }
+        true
+    }
+}
@@ -0,0 +1,191 @@
+//! Transfer log in the format of wu-ftpd and vsftpd `xferlog`, one line per RETR or STOR:
+//!
+//! `current-time transfer-time remote-host file-size filename transfer-type special-action-flag
+//! direction access-mode username service-name authentication-method authenticated-user-id
+//! completion-status`
+//!
+//! The access mode is `r` for the users and `a` for the anonymous sessions, which are logged
+//! with what they sent as the password (usually an email) as the username.
+
+use super::{logger, throttle::Direction};
+use chrono::{DateTime, Local};
+use log::warn;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+static XFERLOG: Mutex<Option<XferLog>> = Mutex::new(None);
+
+struct XferLog {
+    path: String,
+    file: File,
+
+    /// Device and inode of the open file, if the path stops pointing to them
+    /// the file has been rotated and we open it again
+    id: (u64, u64),
+}
+
+impl XferLog {
+    fn open(path: &str) -> io::Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path: path.to_string(),
+            file,
+            id: (metadata.dev(), metadata.ino()),
+        })
+    }
+
+    fn reopen_if_rotated(&mut self) -> io::Result<()> {
+        let rotated = match fs::metadata(&self.path) {
+            Ok(metadata) => (metadata.dev(), metadata.ino()) != self.id,
+            Err(_) => true,
+        };
+        if rotated {
+            *self = Self::open(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fields of a finished transfer
+struct Entry<'a> {
+    duration: Duration,
+    remote_host: &'a str,
+    bytes: u64,
+    /// Path relative to the chroot of the user
+    path: &'a str,
+    direction: Direction,
+    user: &'a str,
+    /// The identification of an anonymous session, it's logged instead of the user
+    guest: Option<&'a str>,
+    complete: bool,
+}
+
+/// The format splits the fields by spaces, so they can't have any
+fn field(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .collect()
+}
+
+impl Entry<'_> {
+    fn format(&self, now: DateTime<Local>) -> String {
+        // Anonymous sessions have the `a` access mode and are logged with their identification
+        let (access_mode, user) = match self.guest {
+            Some(guest) => ('a', field(guest)),
+            None => ('r', field(self.user)),
+        };
+        format!(
+            "{} {} {} {} {} b _ {} {} {} ftp 0 * {}\n",
+            now.format("%a %b %e %H:%M:%S %Y"),
+            // Like wu-ftpd, transfers that take less than a second count as one
+            self.duration.as_secs().max(1),
+            self.remote_host,
+            self.bytes,
+            field(self.path),
+            match self.direction {
+                Direction::Download => 'o',
+                Direction::Upload => 'i',
+            },
+            access_mode,
+            user,
+            if self.complete { 'c' } else { 'i' },
+        )
+    }
+}
+
+/// Opens the transfer log, an empty path disables it
+pub fn configure(path: &str) -> io::Result<()> {
+    let xferlog = if path.is_empty() {
+        None
+    } else {
+        Some(XferLog::open(path)?)
+    };
+    *XFERLOG.lock().unwrap() = xferlog;
+    Ok(())
+}
+
+/// Writes the line of a finished transfer of the session that the current thread is serving
+pub fn write(path: &str, direction: Direction, bytes: u64, duration: Duration, complete: bool) {
+    let mut xferlog = XFERLOG.lock().unwrap();
+    let xferlog = match xferlog.as_mut() {
+        Some(xferlog) => xferlog,
+        None => return,
+    };
+    let session = logger::current_session();
+    let remote_host = session
+        .as_ref()
+        .and_then(|session| session.peer)
+        .map(|peer| peer.ip().to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let user = session
+        .as_ref()
+        .and_then(|session| session.user.clone())
+        .unwrap_or_else(|| "-".to_string());
+    let guest = session.and_then(|session| session.guest);
+    let entry = Entry {
+        duration,
+        remote_host: &remote_host,
+        bytes,
+        path,
+        direction,
+        user: &user,
+        guest: guest.as_deref(),
+        complete,
+    };
+    let result = xferlog
+        .reopen_if_rotated()
+        .and_then(|_| xferlog.file.write_all(entry.format(Local::now()).as_bytes()));
+    if let Err(err) = result {
+        warn!("[XFERLOG] Error writing {}: {}", xferlog.path, err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Entry;
+    use crate::ftp::throttle::Direction;
+    use chrono::{Local, TimeZone};
+    use std::time::Duration;
+
+    #[test]
+    fn xferlog_line() {
+        let entry = Entry {
+            duration: Duration::from_millis(200),
+            remote_host: "127.0.0.1",
+            bytes: 1024,
+            path: "/some dir/file.txt",
+            direction: Direction::Upload,
+            user: "user",
+            guest: None,
+            complete: false,
+        };
+        let now = Local.ymd(2021, 3, 7).and_hms(9, 5, 2);
+        assert_eq!(
+            entry.format(now),
+            "Sun Mar  7 09:05:02 2021 1 127.0.0.1 1024 /some_dir/file.txt b _ i r user ftp 0 * i\n"
+        );
+
+        let entry = Entry {
+            user: "anonymous",
+            guest: Some("guest@example.com"),
+            direction: Direction::Download,
+            complete: true,
+            ..entry
+        };
+        assert_eq!(
+            entry.format(now),
+            "Sun Mar  7 09:05:02 2021 1 127.0.0.1 1024 /some_dir/file.txt b _ o a guest@example.com ftp 0 * c\n"
+        );
+    }
+}
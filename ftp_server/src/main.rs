@@ -2,7 +2,6 @@ pub mod ftp;
 pub mod port;
 pub mod system;
 pub mod tcp;
-use std::fs::OpenOptions;
 
 use clap::{App, Arg};
 fn main() {
@@ -25,34 +24,39 @@ fn main() {
                 .short("c")
                 .long("capacity")
                 .value_name("CAPACITY")
-                .default_value("500"),
-        )               
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .help("Configuration file, it's read again when the server receives SIGHUP. The capacity flag overrides its maximum connections")
+                .long("config")
+                .value_name("CONFIG")
+                .default_value(ftp::config::CONFIG_PATH),
+        )
         .arg(
             Arg::with_name("debug")
-                .help("If it should write to stdout the logs")
+                .help("If it should write the logs to stdout, overrides the output of the config file")
                 .short("d")
                 .long("debug")
                 .value_name("DEBUG")
-                .default_value("true"),
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("log_file")
-                .help("If it should write to the specified file the logs, don't pass anything to not use a log file.")
+                .help("Writes the logs to the specified file too, it's created if it doesn't exist")
                 .short("l")
                 .long("log_file")
                 .value_name("LOG_FILE")
-                .default_value("--none--"),
+                .takes_value(true),
         )
         .get_matches();
-    let debug: bool = matches.value_of("debug").unwrap().parse().unwrap(); 
-    let log_file: &str = matches.value_of("log_file").unwrap(); 
-    if log_file != "--none--" {
-        ftp::config::use_stdout(OpenOptions::new().write(true).open(log_file).expect("Error with log file"));
-    }
-    ftp::config::set_debug(debug);
+    let debug: Option<bool> = matches.value_of("debug").map(|d| d.parse().unwrap());
+    let log_file = matches.value_of("log_file").map(|l| l.to_string());
+    ftp::logger::init(debug, log_file);
     let port = matches.value_of("port").unwrap();
-    let capacity: usize = matches.value_of("capacity").unwrap().parse().unwrap();
+    let capacity: Option<usize> = matches.value_of("capacity").map(|c| c.parse().unwrap());
+    let config = matches.value_of("config").unwrap();
     let ip = format!("0.0.0.0:{}", port);
-    let mut ftp_server = ftp::FTPServer::with_connection_capacity(capacity);
+    let mut ftp_server = ftp::FTPServer::with_config(config, capacity);
     tcp::create_server(ip.as_str(), &mut ftp_server).expect("server returned an error");
 }
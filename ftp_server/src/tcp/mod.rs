@@ -5,14 +5,18 @@ use mio::{
     net::{TcpListener, TcpStream},
     Waker,
 };
+use signal_hook::consts::SIGHUP;
+use signal_hook_mio::v0_7::Signals;
 use std::error::Error;
 use std::io::ErrorKind;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // use crate::stats::program_information;
 
 const SERVER: Token = Token(0);
 const THREAD: Token = Token(2_147_483_647);
+const SIGNAL: Token = Token(2_147_483_646);
 
 // pub fn convert_to_server(id: u64) -> u64 {
 //     id | (1 << 63)
@@ -74,6 +78,21 @@ pub trait TCPImplementation {
 
     /// Function that will be called when the server needs a new id for the next connection
     fn next_id(&mut self) -> usize;
+
+    /// Called when the process receives a SIGHUP, the implementation should read again
+    /// its configuration without dropping the current connections
+    fn reload(&mut self);
+
+    /// How long the poll can wait before calling `tick` again, `None` means that it can block forever
+    fn next_timeout(&self) -> Option<Duration>;
+
+    /// Called once before the loop starts, with the waker that other threads
+    /// can use to wake up the poll after adding actions
+    fn started(&mut self, waker: Arc<Waker>);
+
+    /// Called after every poll iteration, used for timers (e.g expiring idle connections).
+    /// The implementation decides if it's time to do something
+    fn tick(&mut self, poll: &Poll, waker: &Arc<Waker>);
 }
 
 fn handle_request_type(
@@ -84,7 +103,7 @@ fn handle_request_type(
 ) -> Result<(), std::io::Error> {
     let mut r = request.lock().unwrap();
     match &mut r.request_type {
-        RequestType::Closed(stream)
+        RequestType::Closed(stream, _)
         | RequestType::CommandTransfer(stream, _, _, _)
         | RequestType::FileTransferActive(stream, _, _)
         | RequestType::FileTransferPassive(stream, _, _) => {
@@ -101,6 +120,18 @@ fn handle_request_type(
 pub fn create_server<T: AsRef<str>>(
     addr: T,
     tcp_implementation: &mut dyn TCPImplementation,
+) -> Result<(), Box<dyn Error>> {
+    // Setup the server socket.
+    let addr = addr.as_ref().parse()?;
+    // Main server listener, even though you can create more bindings
+    let server = TcpListener::bind(addr)?;
+    serve(server, tcp_implementation)
+}
+
+/// Like `create_server` with a listener that is already bound, e.g to port 0 in the tests
+pub fn serve(
+    mut server: TcpListener,
+    tcp_implementation: &mut dyn TCPImplementation,
 ) -> Result<(), Box<dyn Error>> {
     // Create a poll instance.
     let mut poll = Poll::new()?;
@@ -108,15 +139,16 @@ pub fn create_server<T: AsRef<str>>(
     let mut events = Events::with_capacity(128);
     // Unique id for a connection
     let mut id = tcp_implementation.next_id();
-    // Setup the server socket.
-    let addr = addr.as_ref().parse()?;
-    // Main server listener, even though you can create more bindings
-    let mut server = TcpListener::bind(addr)?;
     // Start listening for incoming connections.
     poll.registry()
         .register(&mut server, SERVER, Interest::READABLE)?;
     // We need this so we can wake up the poll from another thread when we add new events
     let waker = Arc::new(Waker::new(poll.registry(), THREAD)?);
+    // Signals that we handle inside the loop
+    let mut signals = Signals::new([SIGHUP])?;
+    poll.registry()
+        .register(&mut signals, SIGNAL, Interest::READABLE)?;
+    tcp_implementation.started(waker.clone());
     loop {
         {
             let actions = tcp_implementation.action_list();
@@ -129,7 +161,13 @@ pub fn create_server<T: AsRef<str>>(
         }
 
         // Poll Mio for events, blocking until we get an event.
-        poll.poll(&mut events, None)?;
+        if let Err(err) = poll.poll(&mut events, tcp_implementation.next_timeout()) {
+            // A signal arrived while we were waiting
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
 
         // Process each event.
         for event in events.iter() {
@@ -170,6 +208,13 @@ pub fn create_server<T: AsRef<str>>(
                 THREAD => {
                     continue;
                 }
+                SIGNAL => {
+                    for signal in signals.pending() {
+                        if signal == SIGHUP {
+                            tcp_implementation.reload();
+                        }
+                    }
+                }
                 Token(_) => {
                     if event.is_writable() {
                         if let Err(err) =
@@ -219,6 +264,9 @@ pub fn create_server<T: AsRef<str>>(
                 }
             }
         }
+
+        // Timers
+        tcp_implementation.tick(&poll, &waker);
     }
 }
 
@@ -1,17 +1,27 @@
 use serde::{Deserialize, Serialize};
 
+pub mod password;
+
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     error::Error,
     fs::OpenOptions,
     fs::{self, File},
     io::Write,
-    path::{Path, PathBuf},
+    net::IpAddr,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
 };
 
 pub const USER_PATH: &'static str = "./etc/users.json";
 pub const LOG_PATH: &'static str = "./var/ftpserver.log";
 
+/// Name of the groups file, it's next to the users one
+pub const GROUPS_FILE: &str = "groups.json";
+
+/// Name of the anonymous user when the server has one (see `SystemUsers::set_anonymous`)
+pub const ANONYMOUS: &str = "anonymous";
+
 /// Structure that stores the user data of a connection
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
@@ -19,8 +29,153 @@ pub struct User {
     chroot: String,
     uid: u16,
 
+    /// Maximum logged in sessions, overrides the server default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_sessions: Option<usize>,
+
+    /// Upload bytes per second shared by all the sessions, overrides the server default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_rate: Option<u64>,
+
+    /// Download bytes per second shared by all the sessions, overrides the server default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_rate: Option<u64>,
+
+    /// Directories of other places that are part of the tree of the user
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mounts: Vec<Mount>,
+
+    /// Maximum bytes of the files of the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota_bytes: Option<u64>,
+
+    /// Maximum number of files of the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quota_files: Option<u64>,
+
+    /// What the user can do in every part of its tree, everything if there are none
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    permissions: Vec<PathPermissions>,
+
+    /// Groups of the user, they come from the groups file
     #[serde(skip)]
-    actual_dir: String,
+    groups: Vec<Membership>,
+}
+
+/// Directory that is seen by the user at `path` of its tree instead of the `chroot` one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Path in the tree of the user, e.g `/docs`
+    pub path: String,
+
+    /// Directory of the disk, or of `storage` if it's set (a prefix for a bucket)
+    pub dir: String,
+
+    /// Name of one of the `storages` of the server configuration, the disk if it's not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<String>,
+
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// What a user can do with the files and directories of its tree
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// LIST
+    List,
+    /// RETR
+    Read,
+    /// STOR
+    Write,
+    /// APPE
+    Append,
+    /// DELE
+    Delete,
+    /// RNFR and RNTO
+    Rename,
+    /// MKD
+    Mkdir,
+    /// RMD
+    Rmdir,
+}
+
+/// Permissions of a user under a path of its tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PathPermissions {
+    /// Path in the tree of the user, e.g `/incoming`, it applies to everything inside
+    pub path: String,
+
+    pub allow: Vec<Permission>,
+}
+
+/// Users that share a directory, permissions and quotas
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Group {
+    /// Names of the users of the group
+    #[serde(default)]
+    pub members: Vec<String>,
+
+    /// Shared directory of the disk, or of `storage` if it's set, the members see it at `path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir: Option<String>,
+
+    /// Name of one of the `storages` of the server configuration, the disk if it's not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<String>,
+
+    /// Where the members see the shared directory, `/<name of the group>` if it's not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Added to the permissions of every member
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<PathPermissions>,
+
+    /// Maximum bytes of the shared directory, for all the members together
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_bytes: Option<u64>,
+
+    /// Maximum number of files of the shared directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_files: Option<u64>,
+}
+
+/// A group of a user, as the user sees it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Membership {
+    pub name: String,
+
+    /// The shared directory of the group, if it has one
+    pub mount: Option<Mount>,
+
+    pub permissions: Vec<PathPermissions>,
+
+    pub quota_bytes: Option<u64>,
+
+    pub quota_files: Option<u64>,
+}
+
+impl Group {
+    fn membership(&self, name: &str) -> Membership {
+        let mount = self.dir.as_ref().map(|dir| Mount {
+            path: self.path.clone().unwrap_or_else(|| format!("/{}", name)),
+            dir: dir.clone(),
+            storage: self.storage.clone(),
+            read_only: self.read_only,
+        });
+        Membership {
+            name: name.to_string(),
+            mount,
+            permissions: self.permissions.clone(),
+            quota_bytes: self.quota_bytes,
+            quota_files: self.quota_files,
+        }
+    }
 }
 
 impl User {
@@ -28,136 +183,149 @@ impl User {
         let _ = fs::create_dir(&self.chroot);
     }
 
+    /// The chroot directory is created by the storage of the server when the user needs it,
+    /// the password is stored hashed
     pub fn new(username: &str, passwd: &str, uid: u16) -> Self {
-        let _ = fs::create_dir(format!("./root/{}", username));
+        Self::with_stored_passwd(username, password::hash(passwd), uid)
+    }
+
+    fn with_stored_passwd(username: &str, passwd: String, uid: u16) -> Self {
         let chroot = "./root/".to_string() + username;
         Self {
-            passwd: passwd.to_string(),
+            passwd,
             chroot: chroot.clone(),
             uid,
-            actual_dir: "./".to_string(),
+            max_sessions: None,
+            upload_rate: None,
+            download_rate: None,
+            mounts: Vec::new(),
+            quota_bytes: None,
+            quota_files: None,
+            permissions: Vec::new(),
+            groups: Vec::new(),
         }
     }
 
-    pub fn are_equal_paths(&mut self, path: &str) -> bool {
-        return self.total_path() == Path::new(path).canonicalize().unwrap();
-    }
-
-    /// Same behaviour as change_dir but returning the expected path
-    pub fn new_dir<'a>(
-        chroot: &'a str,
-        actual_dir: &str,
-        new_dir: &'a Path,
-    ) -> Result<String, &'static str> {
-        // Get root
-        let root = Path::new(chroot);
-        // Get total path root
-        let expected_root = root.canonicalize().unwrap();
-        // Path buffer to build directory
-        let mut path_buf: PathBuf = PathBuf::new();
-        path_buf.push(actual_dir);
-        path_buf.push(new_dir);
-        // Final path making sure it's not absolute
-        let final_path = format!("./{}", path_buf.to_str().unwrap());
-        // Join with root
-        let path = root.join(final_path.clone());
-        // Get total path
-        let total_path = path.canonicalize().map_err(|_| "Directory not found")?;
-        // Check if it's valid (doesn't exit the chroot)
-        let valid_dir = total_path.starts_with(&expected_root);
-        if valid_dir {
-            let p = total_path.to_str().unwrap();
-            Ok(p.to_string())
-        } else {
-            Err("Invalid directory")
+    /// Record of the anonymous user, it can log in with any password
+    pub fn anonymous(
+        chroot: &str,
+        permissions: Vec<PathPermissions>,
+        max_sessions: Option<usize>,
+        upload_rate: Option<u64>,
+        download_rate: Option<u64>,
+    ) -> Self {
+        Self {
+            chroot: chroot.to_string(),
+            max_sessions,
+            upload_rate,
+            download_rate,
+            permissions,
+            ..Self::with_stored_passwd(ANONYMOUS, String::new(), 0)
         }
     }
 
-    pub fn change_dir<P: AsRef<Path>>(&mut self, new_dir: P) -> Result<(), &'static str> {
-        // Get root
-        let root = Path::new(&self.chroot);
-        // Get total path root
-        let expected_root = root.canonicalize().unwrap();
-        // Path buffer to build directory
-        let mut path_buf: PathBuf = PathBuf::new();
-        path_buf.push(&self.actual_dir);
-        path_buf.push(new_dir);
-        // Final path making sure it's not absolute
-        let final_path = format!("./{}", path_buf.to_str().unwrap());
-        // Join with root
-        let path = root.join(final_path.clone());
-        // Get total path
-        let total_path = path.canonicalize().map_err(|_| "Directory not found")?;
-        // Check if it's valid (doesn't exit the chroot)
-        let valid_dir = total_path.starts_with(&expected_root);
-        if valid_dir {
-            // If it's actually valid set it to actual directory
-            self.actual_dir = final_path;
-            Ok(())
-        } else {
-            Err("Invalid directory")
-        }
+    pub fn has_passwd(&self, passwd: &str) -> bool {
+        password::verify(&self.passwd, passwd)
     }
 
-    pub fn has_passwd(&self, passwd: &str) -> bool {
-        self.passwd == passwd
+    pub fn get_chroot(&self) -> &String {
+        &self.chroot
     }
 
-    pub fn get_actual_dir(&self) -> &String {
-        &self.actual_dir
+    pub fn get_uid(&self) -> u16 {
+        self.uid
     }
 
-    pub fn get_chroot(&self) -> &String {
-        &self.chroot
+    pub fn get_max_sessions(&self) -> Option<usize> {
+        self.max_sessions
     }
 
-    // Gets the total path of the user (in the system)
-    pub fn total_path(&self) -> PathBuf {
-        Path::new(&self.chroot)
-            .join(&self.actual_dir)
-            .canonicalize()
-            .unwrap()
+    pub fn get_upload_rate(&self) -> Option<u64> {
+        self.upload_rate
     }
 
-    pub fn total_path_and_decano(&self) -> String {
-        let t = self.total_path().to_str().unwrap().replacen(
-            Path::new(&self.chroot)
-                .canonicalize()
-                .unwrap()
-                .to_str()
-                .unwrap(),
-            "",
-            1,
-        );
-        if t == "" {
-            return String::from("/");
-        }
-        t
-    }
-
-    // Gets the total path of the user (in the system)
-    pub fn total_path_non_canon(&self) -> String {
-        Path::new(&self.chroot)
-            .join(&self.actual_dir)
-            .to_str()
-            .unwrap()
-            .to_string()
-    }
-
-    pub fn change_dir_to_recursive_if_doesnt_exist(&mut self) {
-        while Path::new(&self.total_path_non_canon())
-            .canonicalize()
-            .is_err()
-        {
-            let mut p_buf = PathBuf::from(&self.actual_dir);
-            p_buf.pop();
-            self.actual_dir = p_buf.to_str().unwrap().to_string();
+    pub fn get_download_rate(&self) -> Option<u64> {
+        self.download_rate
+    }
+
+    /// Mounts of the user and the shared directories of its groups
+    pub fn get_mounts(&self) -> impl Iterator<Item = &Mount> {
+        self.mounts.iter().chain(
+            self.groups
+                .iter()
+                .filter_map(|membership| membership.mount.as_ref()),
+        )
+    }
+
+    pub fn get_groups(&self) -> &[Membership] {
+        &self.groups
+    }
+
+    /// Replaces the groups of the user, `SystemUsers` sets them from the groups file
+    pub fn set_groups(&mut self, groups: Vec<Membership>) {
+        self.groups = groups;
+    }
+
+    pub fn get_quota_bytes(&self) -> Option<u64> {
+        self.quota_bytes
+    }
+
+    pub fn get_quota_files(&self) -> Option<u64> {
+        self.quota_files
+    }
+
+    pub fn get_permissions(&self) -> &[PathPermissions] {
+        &self.permissions
+    }
+
+    /// If the user can do `permission` with `path` (a path of its tree, like `/dir/file.txt`).
+    /// The permissions of the deepest path that contains it apply (the ones of the user and of
+    /// its groups for the same path add up), nothing is allowed outside of them.
+    /// The permissions of a group with a shared directory only apply inside of it.
+    /// Users without `permissions` that apply to `path`, theirs or of their groups, can do
+    /// everything
+    pub fn is_allowed(&self, permission: Permission, path: &str) -> bool {
+        let path = virtual_dir(Path::new(path));
+        let groups = self.groups.iter().filter(|group| match &group.mount {
+            Some(mount) => path.starts_with(virtual_dir(Path::new(&mount.path))),
+            None => true,
+        });
+        let rules: Vec<(PathBuf, &PathPermissions)> = self
+            .permissions
+            .iter()
+            .chain(groups.flat_map(|group| group.permissions.iter()))
+            .map(|rule| (virtual_dir(Path::new(&rule.path)), rule))
+            .collect();
+        if rules.is_empty() {
+            return true;
         }
+        let deepest = rules
+            .iter()
+            .filter(|(rule_path, _)| path.starts_with(rule_path))
+            .map(|(rule_path, _)| rule_path.components().count())
+            .max();
+        rules.iter().any(|(rule_path, rule)| {
+            Some(rule_path.components().count()) == deepest
+                && path.starts_with(rule_path)
+                && rule.allow.contains(&permission)
+        })
     }
+}
 
-    pub fn get_uid(&self) -> u16 {
-        self.uid
+/// Path of `path` relative to the chroot, `.` and `..` are removed without looking at the disk
+/// and it never goes above the chroot
+fn virtual_dir(path: &Path) -> PathBuf {
+    let mut dir = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => dir.push(part),
+            Component::ParentDir => {
+                dir.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
     }
+    dir
 }
 
 /// Structure that stores all users
@@ -167,56 +335,255 @@ impl User {
 pub struct SystemUsers {
     config_path: String,
     users_data: HashMap<String, User>,
+    groups_path: PathBuf,
+    groups: BTreeMap<String, Group>,
+    /// The anonymous user, it isn't saved in the users file
+    anonymous: Option<User>,
     log_file: File,
 }
 
+/// Authentication events of the audit log (see `SystemUsers::audit`), they never have the
+/// passwords or other credentials
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthEvent<'a> {
+    /// A client sent the password of a user
+    Attempt,
+    /// The session of the user started
+    Success,
+    /// The login was refused, for the reason given
+    Failure(&'a str),
+}
+
+/// Reads the groups file, there are no groups if it doesn't exist
+fn load_groups(path: &Path) -> Result<BTreeMap<String, Group>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Gives every user the groups it's a member of
+fn apply_groups(users_data: &mut HashMap<String, User>, groups: &BTreeMap<String, Group>) {
+    for user in users_data.values_mut() {
+        user.groups.clear();
+    }
+    for (name, group) in groups {
+        for member in &group.members {
+            if let Some(user) = users_data.get_mut(member) {
+                user.groups.push(group.membership(name));
+            }
+        }
+    }
+}
+
+/// If `user_name` can be the name of a new user, it's the last component of its `chroot` so
+/// it can't be empty, `.`, `..` or have separators or control characters
+pub fn is_valid_user_name(user_name: &str) -> bool {
+    !user_name.is_empty()
+        && user_name != "."
+        && user_name != ".."
+        && !user_name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control())
+}
+
+/// Replaces the plaintext passwords of a users file by their hashes, the rest of the file
+/// is kept. Returns how many were hashed
+pub fn rehash_file(filename: &str) -> Result<usize, Box<dyn Error>> {
+    let content = fs::read_to_string(filename)?;
+    let mut users: BTreeMap<String, serde_json::Value> = serde_json::from_str(&content)?;
+    let mut hashed = 0;
+    for user in users.values_mut() {
+        let passwd = match user.get_mut("passwd") {
+            Some(serde_json::Value::String(passwd)) => passwd,
+            _ => continue,
+        };
+        if !password::is_hashed(passwd) {
+            *passwd = password::hash(passwd);
+            hashed += 1;
+        }
+    }
+    if hashed > 0 {
+        fs::write(filename, serde_json::to_string_pretty(&users)?)?;
+    }
+    Ok(hashed)
+}
+
 impl SystemUsers {
     pub fn load_data(filename: &str) -> Result<Self, Box<dyn Error>> {
+        Self::load(filename, LOG_PATH)
+    }
+
+    /// Reads the users file `filename`, the events are appended to the log at `log_path`
+    pub fn load(filename: &str, log_path: &str) -> Result<Self, Box<dyn Error>> {
         let content = fs::read_to_string(filename)?;
         let mut users_data: HashMap<String, User> = serde_json::from_str(&content)?;
 
-        users_data.iter_mut().for_each(|(_, user)| {
-            user.actual_dir = "./".to_string();
-            user.create_dir();
-        });
+        let groups_path = Path::new(filename).with_file_name(GROUPS_FILE);
+        let groups = load_groups(&groups_path)?;
+        apply_groups(&mut users_data, &groups);
 
-        let log_file = OpenOptions::new().write(true).append(true).open(LOG_PATH)?;
+        let log_file = OpenOptions::new().append(true).open(log_path)?;
+        // Now and not at the first login of an unknown user, which would take longer
+        password::prepare();
 
         Ok(Self {
             config_path: filename.to_string(),
             users_data,
+            groups_path,
+            groups,
+            anonymous: None,
             log_file,
         })
     }
 
-    pub fn user_exists(&self, user_name: &str) -> bool {
+    /// Reads again the users and groups files, if they can't be parsed the current data is kept.
+    /// The users in `logged_users` keep their record even if they were removed from the file,
+    /// so their sessions keep working
+    pub fn reload(&mut self, logged_users: &[String]) -> Result<(), Box<dyn Error>> {
         let time = chrono::offset::Local::now();
+        let content = fs::read_to_string(&self.config_path)?;
+        let mut users_data: HashMap<String, User> = serde_json::from_str(&content)?;
+        let groups = load_groups(&self.groups_path)?;
+
+        for user_name in logged_users {
+            if users_data.contains_key(user_name) {
+                continue;
+            }
+            if let Some(user) = self.users_data.get(user_name) {
+                users_data.insert(user_name.clone(), user.clone());
+            }
+        }
+
+        apply_groups(&mut users_data, &groups);
+
         writeln!(
             &self.log_file,
-            "[{:?}] Looking for USER {}",
-            time, user_name
+            "[{:?}] Reloaded {} users from {} and {} groups",
+            time,
+            users_data.len(),
+            self.config_path,
+            groups.len()
         )
         .unwrap();
-        self.users_data.iter().any(|(u, _)| u == user_name)
+        self.users_data = users_data;
+        self.groups = groups;
+        Ok(())
+    }
+
+    /// Sets the record of the anonymous user, `None` turns anonymous logins off.
+    /// It takes the place of a user of the file with the same name
+    pub fn set_anonymous(&mut self, user: Option<User>) {
+        self.anonymous = user;
+    }
+
+    /// If `user_name` is the anonymous user and anonymous logins are on
+    pub fn is_anonymous(&self, user_name: &str) -> bool {
+        self.anonymous.is_some() && user_name == ANONYMOUS
+    }
+
+    pub fn user_exists(&self, user_name: &str) -> bool {
+        self.is_anonymous(user_name) || self.users_data.iter().any(|(u, _)| u == user_name)
     }
 
     pub fn has_passwd(&self, user_name: &str, passwd: &str) -> bool {
+        // The anonymous user sends its email as the password
+        if self.is_anonymous(user_name) {
+            return true;
+        }
+        match self.users_data.get(user_name) {
+            Some(user) => user.has_passwd(passwd),
+            None => password::verify_unknown(passwd),
+        }
+    }
+
+    /// Checks the password of the user like `has_passwd` but without keeping `users` locked
+    /// while it's hashed, the error is the reason for the audit log. A plaintext password of
+    /// the file is replaced by its hash when it's right
+    pub fn authenticate(
+        users: &Mutex<Self>,
+        user_name: &str,
+        passwd: &str,
+    ) -> Result<(), &'static str> {
+        let stored = {
+            let users = users.lock().unwrap();
+            // The anonymous user sends its email as the password
+            if users.is_anonymous(user_name) {
+                return Ok(());
+            }
+            users.users_data.get(user_name).map(|user| user.passwd.clone())
+        };
+        let stored = match stored {
+            Some(stored) => stored,
+            None => {
+                password::verify_unknown(passwd);
+                return Err("unknown user");
+            }
+        };
+        if !password::verify(&stored, passwd) {
+            return Err("wrong password");
+        }
+        if !password::is_hashed(&stored) {
+            let hash = password::hash(passwd);
+            users.lock().unwrap().set_passwd_hash(user_name, hash);
+        }
+        Ok(())
+    }
+
+    /// Writes an authentication event of a client that connects from `address` to the log,
+    /// e.g `[...] AUTH failure user="bob" address=10.0.0.7 reason="wrong password"`
+    pub fn audit(&self, event: AuthEvent, user_name: &str, address: Option<IpAddr>) {
         let time = chrono::offset::Local::now();
-        writeln!(
-            &self.log_file,
-            "[{:?}] Looking for USER {}, PASS {}",
-            time, user_name, passwd
-        )
+        let address = address.map_or_else(|| "-".to_string(), |address| address.to_string());
+        // The user name is quoted and escaped, it comes from the client
+        match event {
+            AuthEvent::Attempt => writeln!(
+                &self.log_file,
+                "[{:?}] AUTH attempt user={:?} address={}",
+                time, user_name, address
+            ),
+            AuthEvent::Success => writeln!(
+                &self.log_file,
+                "[{:?}] AUTH success user={:?} address={}",
+                time, user_name, address
+            ),
+            AuthEvent::Failure(reason) => writeln!(
+                &self.log_file,
+                "[{:?}] AUTH failure user={:?} address={} reason={:?}",
+                time, user_name, address, reason
+            ),
+        }
         .unwrap();
-        if let Some(user) = self.users_data.get(user_name) {
-            &user.passwd == passwd
-        } else {
-            false
+    }
+
+    /// Replaces the plaintext password of the user by `hash`, unless it was already replaced
+    fn set_passwd_hash(&mut self, user_name: &str, hash: String) {
+        let user = match self.users_data.get_mut(user_name) {
+            Some(user) if !password::is_hashed(&user.passwd) => user,
+            _ => return,
+        };
+        user.passwd = hash;
+        let time = chrono::offset::Local::now();
+        match self.serialize_users() {
+            Ok(()) => writeln!(
+                &self.log_file,
+                "[{:?}] Password of USER {} stored hashed",
+                time, user_name
+            ),
+            Err(err) => writeln!(
+                &self.log_file,
+                "[{:?}] Error storing the hashed password of USER {}: {}",
+                time, user_name, err
+            ),
         }
+        .unwrap();
     }
 
     pub fn get_user<'a>(&'a self, user_name: &str) -> Option<&'a User> {
-        if self.user_exists(user_name) {
+        if self.is_anonymous(user_name) {
+            self.anonymous.as_ref()
+        } else if self.user_exists(user_name) {
             Some(&self.users_data[user_name])
         } else {
             None
@@ -224,29 +591,99 @@ impl SystemUsers {
     }
 
     pub fn get_user_mut<'a>(&'a mut self, user_name: &str) -> Option<&'a mut User> {
-        if self.user_exists(user_name) {
+        if self.is_anonymous(user_name) {
+            self.anonymous.as_mut()
+        } else if self.user_exists(user_name) {
             Some(self.users_data.get_mut(user_name).unwrap())
         } else {
             None
         }
     }
 
-    pub fn get_user_clone(&self, user_name: &str) -> Option<User> {
-        if self.user_exists(user_name) {
-            Some(self.users_data[user_name].clone())
-        } else {
-            None
+    /// Every user with its name, the anonymous one too
+    pub fn users(&self) -> impl Iterator<Item = (&str, &User)> {
+        let anonymous = self.anonymous.iter().map(|user| (ANONYMOUS, user));
+        self.users_data
+            .iter()
+            .filter(move |(user_name, _)| !self.is_anonymous(user_name))
+            .map(|(user_name, user)| (user_name.as_str(), user))
+            .chain(anonymous)
+    }
+
+    /// Every group with its name
+    pub fn groups(&self) -> impl Iterator<Item = (&String, &Group)> {
+        self.groups.iter()
+    }
+
+    pub fn get_group(&self, group_name: &str) -> Option<&Group> {
+        self.groups.get(group_name)
+    }
+
+    /// Adds the user to the group and saves the groups file
+    pub fn add_member(&mut self, group_name: &str, user_name: &str) -> Result<(), &'static str> {
+        if !self.users_data.contains_key(user_name) {
+            return Err("User do not exists");
+        }
+        let group = self.groups.get_mut(group_name).ok_or("Group do not exists")?;
+        if group.members.iter().any(|member| member == user_name) {
+            return Err("User is already a member");
         }
+        group.members.push(user_name.to_string());
+        self.groups_changed()
     }
 
-    pub fn create_user(&mut self, user_name: &str, passwd: &str) -> Result<(), &'static str> {
+    /// Takes the user out of the group and saves the groups file
+    pub fn remove_member(&mut self, group_name: &str, user_name: &str) -> Result<(), &'static str> {
+        let group = self.groups.get_mut(group_name).ok_or("Group do not exists")?;
+        let members = group.members.len();
+        group.members.retain(|member| member != user_name);
+        if group.members.len() == members {
+            return Err("User is not a member");
+        }
+        self.groups_changed()
+    }
+
+    fn groups_changed(&mut self) -> Result<(), &'static str> {
+        apply_groups(&mut self.users_data, &self.groups);
+        let content =
+            serde_json::to_string_pretty(&self.groups).map_err(|_| "Groups can't be saved")?;
+        fs::write(&self.groups_path, content).map_err(|_| "Groups can't be saved")?;
         let time = chrono::offset::Local::now();
         writeln!(
             &self.log_file,
-            "[{:?}] Looking for USER {}, PASS *****",
-            time, user_name
+            "[{:?}] Groups saved to {}",
+            time,
+            self.groups_path.display()
         )
         .unwrap();
+        Ok(())
+    }
+
+    pub fn get_user_clone(&self, user_name: &str) -> Option<User> {
+        self.get_user(user_name).cloned()
+    }
+
+    pub fn create_user(&mut self, user_name: &str, passwd: &str) -> Result<(), &'static str> {
+        self.create_user_hashed(user_name, password::hash(passwd))
+    }
+
+    /// Creates the user with the hash of its password, from `password::hash`. Hashing takes a
+    /// while, so it's better done before locking the users
+    pub fn create_user_hashed(
+        &mut self,
+        user_name: &str,
+        passwd_hash: String,
+    ) -> Result<(), &'static str> {
+        let time = chrono::offset::Local::now();
+        if !is_valid_user_name(user_name) {
+            writeln!(
+                &self.log_file,
+                "[{:?}] Invalid user name {:?}",
+                time, user_name
+            )
+            .unwrap();
+            return Err("Invalid user name");
+        }
         if let Some(_) = self.users_data.get(user_name) {
             writeln!(
                 &self.log_file,
@@ -265,9 +702,9 @@ impl SystemUsers {
             }
         }
 
-        let user = User::new(user_name, passwd, uid);
-        let _ = fs::create_dir(&user.get_actual_dir());
+        let user = User::with_stored_passwd(user_name, passwd_hash, uid);
         self.users_data.insert(user_name.to_string(), user);
+        apply_groups(&mut self.users_data, &self.groups);
         self.serialize_users().unwrap();
 
         writeln!(
@@ -282,12 +719,6 @@ impl SystemUsers {
 
     pub fn delete_user(&mut self, user_name: &str, passwd: &str) -> Result<User, &'static str> {
         let time = chrono::offset::Local::now();
-        writeln!(
-            &self.log_file,
-            "[{:?}] Looking for USER {}, PASS *****",
-            time, user_name
-        )
-        .unwrap();
 
         if let Some(user_content) = self.users_data.get(user_name) {
             if !user_content.has_passwd(passwd) {
@@ -325,7 +756,7 @@ impl SystemUsers {
 
     fn serialize_users(&self) -> Result<(), Box<dyn Error>> {
         let user_data = serde_json::to_string_pretty(&self.users_data)?;
-        fs::write(USER_PATH, &user_data)?;
+        fs::write(&self.config_path, &user_data)?;
         Ok(())
     }
 }
@@ -333,7 +764,29 @@ impl SystemUsers {
 #[cfg(test)]
 mod system_users_test {
 
-    use super::{SystemUsers, USER_PATH};
+    use super::{
+        apply_groups, is_valid_user_name, password, AuthEvent, Group, Mount, PathPermissions,
+        Permission, SystemUsers, User, ANONYMOUS, USER_PATH,
+    };
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    /// Copy of the users file with a log of its own in a temporary directory, so the test
+    /// doesn't change the ones of the repository or race with the other tests
+    fn temp_users(test: &str) -> (SystemUsers, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("user_manage-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let users_path = dir.join("users.json");
+        fs::copy(USER_PATH, &users_path).unwrap();
+        let log_path = dir.join("ftpserver.log");
+        fs::write(&log_path, "").unwrap();
+        let users =
+            SystemUsers::load(users_path.to_str().unwrap(), log_path.to_str().unwrap()).unwrap();
+        (users, dir)
+    }
+
     // #[test]
     // fn check_exist () {
     // let user_list = SystemUsers::load_data(USER_PATH).unwrap();
@@ -378,41 +831,71 @@ mod system_users_test {
     // }
 
     #[test]
-    fn check_paths() {
-        let new_user_name = "qwerty2";
-        let new_user_passwd = new_user_name;
-        let mut sys_users = SystemUsers::load_data(USER_PATH).unwrap();
-        let create = sys_users.create_user(new_user_name, new_user_passwd);
-        assert!(create.is_ok());
-        let user = sys_users.get_user_mut(new_user_name).expect("to work");
-        user.change_dir("./thing")
-            .expect_err("Expect this an error");
-        assert!(user.are_equal_paths("./home/qwerty2"));
-        user.change_dir("..")
-            .expect_err("Expected this to be an error");
-        assert!(user.are_equal_paths("./home/qwerty2"));
-        user.change_dir("/thing3/thing4")
-            .expect("expect this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2/thing3/thing4"));
-        user.change_dir("../").expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2/thing3"));
-        user.change_dir("..").expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2"));
-        user.change_dir("./thing3").expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2/thing3"));
-        user.change_dir("./thing4").expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2/thing3/thing4"));
-        user.change_dir("/thing2").expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2/thing2"));
-        user.change_dir("/thing3/thing4")
-            .expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2/thing3/thing4"));
-        user.change_dir("/").expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2"));
-        assert!(user.are_equal_paths("./home/qwerty2//././././././//./"));
-        user.change_dir("./thing3").expect("Expected this to be ok");
-        assert!(user.are_equal_paths("./home/qwerty2//././././././//./thing3/thing4/.."));
-        assert!(!user.are_equal_paths("./home/qwerty2//././././././//./thing3/thing4/./."));
+    fn permissions_by_path() {
+        let partner: User = serde_json::from_str(
+            r#"{ "passwd": "", "chroot": "./root/p", "uid": 1, "permissions": [
+                { "path": "/", "allow": ["list"] },
+                { "path": "/incoming", "allow": ["list", "write", "append", "mkdir"] }
+            ] }"#,
+        )
+        .unwrap();
+        assert!(partner.is_allowed(Permission::List, "/"));
+        assert!(!partner.is_allowed(Permission::Read, "/report.pdf"));
+        assert!(!partner.is_allowed(Permission::Write, "/file"));
+        assert!(partner.is_allowed(Permission::Write, "/incoming/file"));
+        assert!(partner.is_allowed(Permission::Mkdir, "/incoming/a/b"));
+        assert!(!partner.is_allowed(Permission::Write, "/incomingx"));
+        assert!(!partner.is_allowed(Permission::Delete, "/incoming/file"));
+
+        let admin = User::new("admin", "admin", 0);
+        assert!(admin.is_allowed(Permission::Rmdir, "/anything"));
+    }
+
+    #[test]
+    fn groups_of_users() {
+        let mut users: HashMap<String, User> = HashMap::new();
+        users.insert("ana".to_string(), User::new("ana", "ana", 1));
+        users.insert("bob".to_string(), User::new("bob", "bob", 2));
+        let groups: BTreeMap<String, Group> = serde_json::from_str(
+            r#"{
+                "team": { "members": ["ana", "nobody"], "dir": "./shared/team" },
+                "auditors": { "members": ["bob"], "permissions": [
+                    { "path": "/", "allow": ["list", "read"] }
+                ] }
+            }"#,
+        )
+        .unwrap();
+        apply_groups(&mut users, &groups);
+
+        let ana = &users["ana"];
+        let mounts: Vec<&Mount> = ana.get_mounts().collect();
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].path, "/team");
+        assert!(ana.is_allowed(Permission::Write, "/team/file"));
+
+        let bob = &users["bob"];
+        assert_eq!(bob.get_mounts().count(), 0);
+        assert!(bob.is_allowed(Permission::Read, "/file"));
+        assert!(!bob.is_allowed(Permission::Delete, "/file"));
+    }
+
+    #[test]
+    fn group_permissions_stay_in_shared_dir() {
+        let mut users: HashMap<String, User> = HashMap::new();
+        users.insert("ana".to_string(), User::new("ana", "ana", 1));
+        let groups: BTreeMap<String, Group> = serde_json::from_str(
+            r#"{ "team": { "members": ["ana"], "dir": "./shared/team", "permissions": [
+                { "path": "/team", "allow": ["list", "read"] }
+            ] } }"#,
+        )
+        .unwrap();
+        apply_groups(&mut users, &groups);
+
+        let ana = &users["ana"];
+        assert!(ana.is_allowed(Permission::Write, "/file"));
+        assert!(ana.is_allowed(Permission::Delete, "/docs/file"));
+        assert!(ana.is_allowed(Permission::Read, "/team/file"));
+        assert!(!ana.is_allowed(Permission::Write, "/team/file"));
     }
 
     // cargo t create_delete_user -- --nocapture
@@ -420,7 +903,7 @@ mod system_users_test {
     fn create_delete_user() {
         let new_user_name = "qwerty";
         let new_user_passwd = new_user_name;
-        let mut sys_users = SystemUsers::load_data(USER_PATH).unwrap();
+        let (mut sys_users, dir) = temp_users("create_delete");
 
         let created = sys_users.create_user(new_user_name, new_user_passwd);
         assert!(created.is_ok());
@@ -436,5 +919,82 @@ mod system_users_test {
 
         let fail_delete = sys_users.delete_user(new_user_name, new_user_passwd);
         assert!(fail_delete.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_names_outside_root() {
+        let (mut sys_users, dir) = temp_users("invalid_names");
+        let names = [
+            "",
+            ".",
+            "..",
+            "a/../../..",
+            "a\\b",
+            "nul\0",
+            "tab\t",
+            "new\nline",
+        ];
+        for name in names {
+            assert!(!is_valid_user_name(name), "{:?}", name);
+            let hash = password::hash("secret");
+            assert!(sys_users.create_user_hashed(name, hash).is_err());
+            assert!(!sys_users.user_exists(name));
+        }
+        assert!(is_valid_user_name("user.name-01"));
+        assert!(is_valid_user_name("..hidden"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn audit_without_credentials() {
+        let user_name = "audited";
+        let passwd = "audited-secret-passwd";
+        let (users, dir) = temp_users("audit");
+        let users = Mutex::new(users);
+        users.lock().unwrap().create_user(user_name, passwd).unwrap();
+        let address = "10.0.0.7".parse().ok();
+        assert_eq!(
+            SystemUsers::authenticate(&users, user_name, "wrong"),
+            Err("wrong password")
+        );
+        assert_eq!(
+            SystemUsers::authenticate(&users, "nobody-audited", passwd),
+            Err("unknown user")
+        );
+        assert_eq!(SystemUsers::authenticate(&users, user_name, passwd), Ok(()));
+        let mut sys_users = users.into_inner().unwrap();
+        sys_users.audit(AuthEvent::Attempt, user_name, address);
+        sys_users.audit(AuthEvent::Failure("wrong password"), user_name, None);
+        sys_users.delete_user(user_name, passwd).unwrap();
+
+        let log = fs::read_to_string(dir.join("ftpserver.log")).unwrap();
+        assert!(log.contains("AUTH attempt user=\"audited\" address=10.0.0.7"));
+        assert!(log.contains("AUTH failure user=\"audited\" address=- reason=\"wrong password\""));
+        assert!(!log.contains(passwd));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn anonymous_user() {
+        let (mut sys_users, dir) = temp_users("anonymous");
+        let permissions = vec![PathPermissions {
+            path: "/".to_string(),
+            allow: vec![Permission::List, Permission::Read],
+        }];
+        let user = User::anonymous("./root/pub", permissions, Some(5), None, Some(1000));
+        sys_users.set_anonymous(Some(user));
+        assert!(sys_users.has_passwd(ANONYMOUS, "someone@example.com"));
+        let user = sys_users.get_user(ANONYMOUS).unwrap();
+        assert_eq!(user.get_chroot(), "./root/pub");
+        assert_eq!(user.get_max_sessions(), Some(5));
+        assert!(!user.is_allowed(Permission::Write, "/file"));
+        assert_eq!(
+            sys_users.users().filter(|(name, _)| *name == ANONYMOUS).count(),
+            1
+        );
+        sys_users.set_anonymous(None);
+        assert!(!sys_users.is_anonymous(ANONYMOUS));
+        fs::remove_dir_all(dir).unwrap();
     }
 }
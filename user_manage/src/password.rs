@@ -0,0 +1,82 @@
+//! Passwords of the users as they are stored in `users.json`.
+//!
+//! New passwords are stored as argon2id hashes in PHC format (`$argon2id$v=19$...`), with the
+//! default parameters of the `argon2` crate and a random salt. Entries written before that keep
+//! their plaintext password until the user logs in, then it's replaced by its hash.
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::sync::OnceLock;
+
+/// Hashes `passwd` with argon2id and a new salt
+pub fn hash(passwd: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(passwd.as_bytes(), &salt)
+        .expect("argon2 can't hash the password")
+        .to_string()
+}
+
+/// If the stored password is a hash, the rest are plaintext ones from before hashing
+pub fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+/// If `passwd` is the stored one, plaintext passwords are compared in constant time too
+pub fn verify(stored: &str, passwd: &str) -> bool {
+    if !is_hashed(stored) {
+        // As long as a hashed one, so the time doesn't tell which users still have plaintext
+        verify_unknown(passwd);
+        return constant_time_eq(stored.as_bytes(), passwd.as_bytes());
+    }
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(passwd.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Takes as long as checking the password of a user that exists, so the users that don't
+/// can't be told apart by the time that the answer takes. It's always false
+pub fn verify_unknown(passwd: &str) -> bool {
+    let _ = verify(dummy(), passwd);
+    false
+}
+
+/// Builds the hash that `verify_unknown` checks against, it's done once
+pub(crate) fn prepare() {
+    dummy();
+}
+
+fn dummy() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash(""))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, is_hashed, verify, verify_unknown};
+
+    #[test]
+    fn hashed_and_plaintext_passwords() {
+        let stored = hash("secret");
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(is_hashed(&stored));
+        assert_ne!(stored, hash("secret"));
+        assert!(verify(&stored, "secret"));
+        assert!(!verify(&stored, "Secret"));
+        assert!(verify("legacy", "legacy"));
+        assert!(!verify("legacy", "legacy2"));
+        assert!(!verify_unknown(""));
+    }
+}